@@ -1,9 +1,83 @@
-use crate::tools::search_tool::SearchTool;
+use crate::tools::search_tool::{
+    DEFAULT_DIRECTORY_ARG, DEFAULT_DIRECTORY_ENV, DEFAULT_INDEX_CACHE_SIZE,
+    DEFAULT_MAX_CONCURRENT_SEARCHES, INDEX_CACHE_SIZE_ENV, MAX_CONCURRENT_SEARCHES_ENV, SearchTool,
+};
 use rmcp::{ServiceExt, transport::stdio};
+use std::time::Duration;
 use tracing_subscriber::{self, EnvFilter};
 
 mod tools;
 
+// How long running searches and indexing runs may take to wind down at shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+// Read a count from the environment, falling back to the default when it is unset or invalid
+fn env_count(name: &str, default: usize, min: usize) -> usize {
+    match std::env::var(name) {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(count) if count >= min => count,
+            _ => {
+                tracing::warn!("Ignoring invalid {}='{}', using {}", name, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// Directory searched when a request names none, from --default-directory <path> or
+// --default-directory=<path>, else from the environment
+fn default_directory() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    let from_args = loop {
+        let Some(arg) = args.next() else {
+            break None;
+        };
+        if arg == DEFAULT_DIRECTORY_ARG {
+            break args.next();
+        }
+        if let Some(value) = arg
+            .strip_prefix(DEFAULT_DIRECTORY_ARG)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            break Some(value.to_string());
+        }
+    };
+    let directory = from_args
+        .or_else(|| std::env::var(DEFAULT_DIRECTORY_ENV).ok())
+        .filter(|directory| !directory.trim().is_empty())?;
+    // Kept anyway, as the directory may be mounted later; searches report it if it is missing
+    if !std::path::Path::new(&directory).is_dir() {
+        tracing::warn!("Default directory '{}' is not a directory", directory);
+    }
+    Some(directory)
+}
+
+// Resolve on Ctrl-C, or on SIGTERM where there are Unix signals
+async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(e) => {
+                    tracing::warn!("Cannot listen for SIGTERM: {}", e);
+                    let _ = interrupt.await;
+                    return;
+                }
+            };
+        tokio::select! {
+            _ = interrupt => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = interrupt.await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt()
@@ -14,10 +88,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing::info!("Starting MCP Server...");
 
-    let service = SearchTool::new().serve(stdio()).await.inspect_err(|e| {
+    // Searches beyond the limit wait for a running one to finish
+    let max_concurrent_searches = env_count(
+        MAX_CONCURRENT_SEARCHES_ENV,
+        DEFAULT_MAX_CONCURRENT_SEARCHES,
+        1,
+    );
+    // 0 disables the cache of recently built indexes
+    let index_cache_size = env_count(INDEX_CACHE_SIZE_ENV, DEFAULT_INDEX_CACHE_SIZE, 0);
+
+    let default_directory = default_directory();
+    if let Some(directory) = &default_directory {
+        tracing::info!("Default directory: {}", directory);
+    }
+
+    let tool = SearchTool::new(max_concurrent_searches, index_cache_size)
+        .with_default_directory(default_directory);
+    let service = tool.clone().serve(stdio()).await.inspect_err(|e| {
         tracing::error!("serving error: {:?}", e);
     })?;
 
-    service.waiting().await?;
+    // Whether the client disconnected or the process was interrupted, let the work in progress
+    // commit before exiting so that no cached index is left half-written
+    tokio::select! {
+        result = service.waiting() => {
+            result?;
+        }
+        _ = shutdown_signal() => {
+            tracing::info!("Shutdown requested, waiting for running work to finish");
+        }
+    }
+    tool.shutdown(SHUTDOWN_GRACE).await;
     Ok(())
 }
@@ -0,0 +1,288 @@
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Separates the path of an archive from the path of an entry inside it, as in
+// "docs.zip!/guide/intro.md"
+pub const ENTRY_SEPARATOR: &str = "!/";
+
+// Archive formats whose entries can be indexed. A gzip file that isn't a tarball holds a single
+// compressed file, its only entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    Gzip,
+}
+
+fn archive_format(name: &str) -> Option<ArchiveFormat> {
+    let name = name.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveFormat::Zip)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveFormat::TarGz)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveFormat::Tar)
+    } else if name.ends_with(".gz") {
+        Some(ArchiveFormat::Gzip)
+    } else {
+        None
+    }
+}
+
+// Whether the file is an archive of several files whose entries can be indexed
+pub fn is_archive(path: &Path) -> bool {
+    matches!(
+        archive_format(&path.to_string_lossy()),
+        Some(ArchiveFormat::Zip | ArchiveFormat::Tar | ArchiveFormat::TarGz)
+    )
+}
+
+// Whether the file is a single gzip-compressed file such as a rotated log
+pub fn is_gzip(path: &Path) -> bool {
+    archive_format(&path.to_string_lossy()) == Some(ArchiveFormat::Gzip)
+}
+
+// Whether the file may have been indexed as entries under virtual paths, as an archive or a
+// gzip file
+pub fn has_entries(path: &Path) -> bool {
+    archive_format(&path.to_string_lossy()).is_some()
+}
+
+// Name of the only entry of a gzip file: its own name without the .gz extension
+pub fn gzip_entry_name(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match name.len().checked_sub(3) {
+        Some(end) if name.is_char_boundary(end) => name[..end].to_string(),
+        _ => name,
+    }
+}
+
+// Decompress a gzip file, giving up as soon as the content grows beyond `max_bytes`, in which
+// case None is returned
+pub fn read_gzip(path: &Path, max_bytes: u64) -> Result<Option<Vec<u8>>, String> {
+    let file = File::open(path).map_err(|e| format!("Archive open error: {}", e))?;
+    let mut bytes = Vec::new();
+    GzDecoder::new(file)
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Gzip error: {}", e))?;
+    if bytes.len() as u64 > max_bytes {
+        return Ok(None);
+    }
+    Ok(Some(bytes))
+}
+
+// The archive containing an indexed entry, or the path itself when it is not an archive entry
+pub fn container_path(path: &str) -> &str {
+    match path.split_once(ENTRY_SEPARATOR) {
+        Some((archive, _)) if archive_format(archive).is_some() => archive,
+        _ => path,
+    }
+}
+
+// Path under which an entry of an archive is indexed
+pub fn entry_path(archive: &Path, entry: &str) -> String {
+    format!("{}{}{}", archive.to_string_lossy(), ENTRY_SEPARATOR, entry)
+}
+
+// A file inside an archive with its uncompressed content
+pub struct ArchiveEntry {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+// Read the file entries of an archive. Directories, entries larger than `max_entry_bytes`
+// whatever size they declare, and entries that can't be read are left out.
+pub fn read_entries(path: &Path, max_entry_bytes: u64) -> Result<Vec<ArchiveEntry>, String> {
+    let file = File::open(path).map_err(|e| format!("Archive open error: {}", e))?;
+    match archive_format(&path.to_string_lossy()) {
+        Some(ArchiveFormat::Zip) => read_zip_entries(file, max_entry_bytes),
+        Some(ArchiveFormat::Tar) => read_tar_entries(file, max_entry_bytes),
+        Some(ArchiveFormat::TarGz) => read_tar_entries(GzDecoder::new(file), max_entry_bytes),
+        Some(ArchiveFormat::Gzip) => Ok(read_gzip(path, max_entry_bytes)?
+            .map(|bytes| ArchiveEntry {
+                name: gzip_entry_name(path),
+                bytes,
+            })
+            .into_iter()
+            .collect()),
+        None => Err(format!("Not a supported archive: {}", path.display())),
+    }
+}
+
+// Read a single file entry of an archive, or None when the archive has no such entry. Only that
+// entry is decompressed, and entries larger than `max_bytes` are rejected.
+pub fn read_entry(archive: &Path, name: &str, max_bytes: u64) -> Result<Option<Vec<u8>>, String> {
+    let file = File::open(archive).map_err(|e| format!("Archive open error: {}", e))?;
+    match archive_format(&archive.to_string_lossy()) {
+        Some(ArchiveFormat::Zip) => read_zip_entry(file, name, max_bytes),
+        Some(ArchiveFormat::Tar) => read_tar_entry(file, name, max_bytes),
+        Some(ArchiveFormat::TarGz) => read_tar_entry(GzDecoder::new(file), name, max_bytes),
+        Some(ArchiveFormat::Gzip) if name == gzip_entry_name(archive) => {
+            match read_gzip(archive, max_bytes)? {
+                Some(bytes) => Ok(Some(bytes)),
+                None => Err(too_large(name, max_bytes)),
+            }
+        }
+        Some(ArchiveFormat::Gzip) => Ok(None),
+        None => Err(format!("Not a supported archive: {}", archive.display())),
+    }
+}
+
+fn too_large(name: &str, max_bytes: u64) -> String {
+    format!(
+        "The archive entry '{}' is larger than {} bytes",
+        name, max_bytes
+    )
+}
+
+// Read an entry, never trusting its declared size beyond `max_bytes`
+fn read_capped(entry: impl Read, name: &str, size: u64, max_bytes: u64) -> Result<Vec<u8>, String> {
+    if size > max_bytes {
+        return Err(too_large(name, max_bytes));
+    }
+    let mut bytes = Vec::with_capacity(size as usize);
+    entry
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Archive read error: {}", e))?;
+    if bytes.len() as u64 > max_bytes {
+        return Err(too_large(name, max_bytes));
+    }
+    Ok(bytes)
+}
+
+fn read_zip_entry(file: File, name: &str, max_bytes: u64) -> Result<Option<Vec<u8>>, String> {
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Zip error: {}", e))?;
+    let entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(format!("Zip error: {}", e)),
+    };
+    if entry.is_dir() {
+        return Ok(None);
+    }
+    let size = entry.size();
+    read_capped(entry, name, size, max_bytes).map(Some)
+}
+
+fn read_tar_entry(
+    reader: impl Read,
+    name: &str,
+    max_bytes: u64,
+) -> Result<Option<Vec<u8>>, String> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(|e| format!("Tar error: {}", e))? {
+        let entry = entry.map_err(|e| format!("Tar error: {}", e))?;
+        if !entry.header().entry_type().is_file()
+            || entry
+                .path()
+                .map_err(|e| format!("Tar error: {}", e))?
+                .to_string_lossy()
+                != name
+        {
+            continue;
+        }
+        let size = entry.size();
+        return read_capped(entry, name, size, max_bytes).map(Some);
+    }
+    Ok(None)
+}
+
+fn read_zip_entries(file: File, max_entry_bytes: u64) -> Result<Vec<ArchiveEntry>, String> {
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Zip error: {}", e))?;
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = archive
+            .by_index(i)
+            .map_err(|e| format!("Zip error: {}", e))?;
+        if entry.is_dir() {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let size = entry.size();
+        let Ok(bytes) = read_capped(&mut entry, &name, size, max_entry_bytes) else {
+            continue;
+        };
+        entries.push(ArchiveEntry { name, bytes });
+    }
+    Ok(entries)
+}
+
+fn read_tar_entries(reader: impl Read, max_entry_bytes: u64) -> Result<Vec<ArchiveEntry>, String> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries().map_err(|e| format!("Tar error: {}", e))? {
+        let mut entry = entry.map_err(|e| format!("Tar error: {}", e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map_err(|e| format!("Tar error: {}", e))?
+            .to_string_lossy()
+            .to_string();
+        let size = entry.size();
+        let Ok(bytes) = read_capped(&mut entry, &name, size, max_entry_bytes) else {
+            continue;
+        };
+        entries.push(ArchiveEntry { name, bytes });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn splits_entry_paths_from_their_archives() {
+        let path = entry_path(Path::new("/data/docs.zip"), "guide/intro.md");
+        assert_eq!(path, "/data/docs.zip!/guide/intro.md");
+        assert_eq!(container_path(&path), "/data/docs.zip");
+        assert_eq!(container_path("/notes/a!/b.txt"), "/notes/a!/b.txt");
+        assert_eq!(gzip_entry_name(Path::new("logs/app.log.gz")), "app.log");
+    }
+
+    #[test]
+    fn reads_single_entries_up_to_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("docs.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        zip.start_file("intro.md", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"hello").unwrap();
+        zip.finish().unwrap();
+
+        assert_eq!(
+            read_entry(&archive, "intro.md", 5).unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(read_entry(&archive, "missing.md", 5).unwrap(), None);
+        assert!(read_entry(&archive, "intro.md", 4).is_err());
+    }
+
+    #[test]
+    fn leaves_out_entries_over_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("docs.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for (name, content) in [("short.md", "hi"), ("long.md", "hello")] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let entries = read_entries(&archive, 4).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["short.md"]);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+use tantivy::collector::TopDocs;
+use tantivy::fieldnorm::FieldNormReader;
+use tantivy::postings::{Postings, SegmentPostings};
+use tantivy::query::Query;
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::{DocAddress, DocId, DocSet, Score, Searcher, SegmentReader, Term};
+
+// Tantivy's own scorer uses these constants and can't be configured
+pub const DEFAULT_K1: f32 = 1.2;
+pub const DEFAULT_B: f32 = 0.75;
+// Upper bound for k1; beyond it term frequency saturates so slowly that ranking degenerates
+const MAX_K1: f32 = 10.0;
+
+// Parameters of the BM25 scoring formula
+#[derive(Debug, Clone, Copy)]
+pub struct Bm25Params {
+    // How quickly repeated occurrences of a term stop adding to the score
+    pub k1: f32,
+    // How strongly the score is normalized by the document length, from 0 (not at all) to 1
+    pub b: f32,
+}
+
+impl Bm25Params {
+    // Resolve the parameters, or None when both are left at Tantivy's defaults
+    pub fn resolve(k1: Option<f32>, b: Option<f32>) -> Result<Option<Self>, String> {
+        if k1.is_none() && b.is_none() {
+            return Ok(None);
+        }
+        let k1 = k1.unwrap_or(DEFAULT_K1);
+        let b = b.unwrap_or(DEFAULT_B);
+        if !(0.0..=MAX_K1).contains(&k1) {
+            return Err(format!(
+                "bm25_k1 must be between 0 and {}, got {}.",
+                MAX_K1, k1
+            ));
+        }
+        if !(0.0..=1.0).contains(&b) {
+            return Err(format!("bm25_b must be between 0 and 1, got {}.", b));
+        }
+        Ok(Some(Self { k1, b }))
+    }
+}
+
+// Terms of the query on the given fields, each with the boost of its field
+pub fn weighted_terms(query: &dyn Query, fields: &[(Field, f32)]) -> Vec<(Term, f32)> {
+    let mut seen = HashSet::new();
+    let mut terms = Vec::new();
+    query.query_terms(&mut |term, _| {
+        if let Some((_, boost)) = fields.iter().find(|(field, _)| *field == term.field())
+            && seen.insert(term.clone())
+        {
+            terms.push((term.clone(), *boost));
+        }
+    });
+    terms
+}
+
+// Contribution of one query term to the score of the documents of a segment
+struct TermScorer {
+    postings: Option<SegmentPostings>,
+    fieldnorms: Option<FieldNormReader>,
+    // Inverse document frequency of the term times the boost of its field
+    weight: f32,
+    average_fieldnorm: f32,
+}
+
+impl TermScorer {
+    // Documents must be scored in increasing order, as collectors do within a segment
+    fn score(&mut self, doc: DocId, params: Bm25Params) -> f32 {
+        let Some(postings) = &mut self.postings else {
+            return 0.0;
+        };
+        if postings.doc() < doc {
+            postings.seek(doc);
+        }
+        if postings.doc() != doc {
+            return 0.0;
+        }
+        let term_freq = postings.term_freq() as f32;
+        let length = self
+            .fieldnorms
+            .as_ref()
+            .map_or(self.average_fieldnorm, |fieldnorms| {
+                fieldnorms.fieldnorm(doc) as f32
+            });
+        let norm = 1.0 - params.b + params.b * length / self.average_fieldnorm.max(1.0);
+        self.weight * term_freq * (params.k1 + 1.0) / (term_freq + params.k1 * norm)
+    }
+}
+
+// Average number of tokens of the field per document
+fn average_fieldnorm(searcher: &Searcher, field: Field) -> tantivy::Result<f32> {
+    let mut total_tokens = 0;
+    for segment_reader in searcher.segment_readers() {
+        total_tokens += segment_reader.inverted_index(field)?.total_num_tokens();
+    }
+    Ok(total_tokens as f32 / searcher.num_docs().max(1) as f32)
+}
+
+// Run the query and rank its matches by BM25 over the given terms with custom parameters.
+// Terms of phrases are scored individually, and variants matched by fuzzy or prefix queries are
+// not part of the terms, so such queries should keep Tantivy's scores.
+pub fn top_hits(
+    searcher: &Searcher,
+    query: &dyn Query,
+    terms: &[(Term, f32)],
+    collector: TopDocs,
+    params: Bm25Params,
+) -> tantivy::Result<Vec<(Score, DocAddress)>> {
+    let num_docs = searcher.num_docs() as f32;
+    let mut weighted = Vec::with_capacity(terms.len());
+    for (term, boost) in terms {
+        let doc_freq = searcher.doc_freq(term)? as f32;
+        let idf = (1.0 + (num_docs - doc_freq + 0.5) / (doc_freq + 0.5)).ln();
+        weighted.push((
+            term.clone(),
+            idf * boost,
+            average_fieldnorm(searcher, term.field())?,
+        ));
+    }
+
+    searcher.search(
+        query,
+        &collector.tweak_score(move |segment_reader: &SegmentReader| {
+            // Terms that can't be read from the segment contribute nothing
+            let mut scorers: Vec<TermScorer> = weighted
+                .iter()
+                .map(|(term, weight, average_fieldnorm)| TermScorer {
+                    postings: segment_reader.inverted_index(term.field()).ok().and_then(
+                        |inverted_index| {
+                            inverted_index
+                                .read_postings(term, IndexRecordOption::WithFreqs)
+                                .ok()
+                                .flatten()
+                        },
+                    ),
+                    fieldnorms: segment_reader.get_fieldnorms_reader(term.field()).ok(),
+                    weight: *weight,
+                    average_fieldnorm: *average_fieldnorm,
+                })
+                .collect();
+            move |doc: DocId, _score: Score| {
+                scorers
+                    .iter_mut()
+                    .map(|scorer| scorer.score(doc, params))
+                    .sum::<f32>()
+            }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::query::QueryParser;
+    use tantivy::schema::{Schema, TEXT};
+    use tantivy::{Index, doc};
+
+    // An index with one document per text, all in a single segment so that doc ids follow the
+    // order of the texts
+    fn index(texts: &[&str]) -> (Index, Field) {
+        let mut builder = Schema::builder();
+        let text = builder.add_text_field("text", TEXT);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 15_000_000).unwrap();
+        for content in texts {
+            writer.add_document(doc!(text => *content)).unwrap();
+        }
+        writer.commit().unwrap();
+        (index, text)
+    }
+
+    // Scores of the matches of the query, best first, with Tantivy's scorer or custom parameters
+    fn ranking(
+        index: &Index,
+        field: Field,
+        query: &str,
+        params: Option<Bm25Params>,
+    ) -> Vec<(DocId, Score)> {
+        let searcher = index.reader().unwrap().searcher();
+        let query = QueryParser::for_index(index, vec![field])
+            .parse_query(query)
+            .unwrap();
+        let collector = TopDocs::with_limit(10);
+        let hits = match params {
+            Some(params) => {
+                let terms = weighted_terms(&*query, &[(field, 1.0)]);
+                top_hits(&searcher, &*query, &terms, collector, params).unwrap()
+            }
+            None => searcher.search(&query, &collector).unwrap(),
+        };
+        hits.into_iter()
+            .map(|(score, address)| (address.doc_id, score))
+            .collect()
+    }
+
+    fn order(ranking: &[(DocId, Score)]) -> Vec<DocId> {
+        ranking.iter().map(|(doc, _)| *doc).collect()
+    }
+
+    #[test]
+    fn default_parameters_score_like_tantivy() {
+        let (index, field) = index(&[
+            "walrus",
+            "walrus walrus and some other words",
+            "a walrus among many many other words of a long text",
+            "no match here",
+        ]);
+        let defaults = Bm25Params::resolve(Some(DEFAULT_K1), Some(DEFAULT_B))
+            .unwrap()
+            .unwrap();
+        let tantivy = ranking(&index, field, "walrus", None);
+        let custom = ranking(&index, field, "walrus", Some(defaults));
+        assert_eq!(order(&custom), order(&tantivy));
+        for ((_, expected), (_, actual)) in tantivy.iter().zip(&custom) {
+            assert!(
+                (expected - actual).abs() < 1e-4,
+                "{} != {}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn b_sets_how_much_long_documents_are_penalized() {
+        // The long document repeats the term, the short one mentions it once
+        let (index, field) = index(&[
+            "walrus here",
+            "walrus walrus and then a great many words that make this a rather long document \
+             about nothing at all really nothing",
+        ]);
+        let without_length = Bm25Params::resolve(None, Some(0.0)).unwrap();
+        assert_eq!(
+            order(&ranking(&index, field, "walrus", without_length)),
+            [1, 0]
+        );
+        let by_length = Bm25Params::resolve(None, Some(1.0)).unwrap();
+        assert_eq!(order(&ranking(&index, field, "walrus", by_length)), [0, 1]);
+    }
+
+    #[test]
+    fn k1_sets_how_much_repeated_terms_count() {
+        // Both terms are equally rare; one document has each once, the other repeats one of them
+        let (index, field) = index(&[
+            "walrus fox",
+            "walrus walrus walrus walrus walrus walrus",
+            "fox alone",
+        ]);
+        let saturating = Bm25Params::resolve(Some(1.2), Some(0.0)).unwrap();
+        assert_eq!(
+            order(&ranking(&index, field, "walrus fox", saturating))[..2],
+            [0, 1]
+        );
+        let linear = Bm25Params::resolve(Some(10.0), Some(0.0)).unwrap();
+        assert_eq!(
+            order(&ranking(&index, field, "walrus fox", linear))[..2],
+            [1, 0]
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{DecoderResult, Encoding, UTF_8};
+
+// Look up an encoding by one of its WHATWG labels, e.g. "shift_jis", "latin1" or "utf-16le"
+pub fn encoding_for_label(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("Unknown encoding: {}", label))
+}
+
+// Guess the encoding of bytes that are not valid UTF-8, and whether the guess is confident
+fn detect(bytes: &[u8], last: bool) -> (&'static Encoding, bool) {
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, last);
+    detector.guess_assess(None, false)
+}
+
+// Whether the beginning of a file starts with a byte order mark
+pub fn has_bom(sample: &[u8]) -> bool {
+    Encoding::for_bom(sample).is_some()
+}
+
+// Whether a sample that is not valid UTF-8 decodes cleanly in a confidently detected encoding.
+// The sample may end in the middle of a character, so decoding does not treat it as the end.
+pub fn is_legacy_text(sample: &[u8]) -> bool {
+    let (encoding, confident) = detect(sample, false);
+    if !confident || encoding == UTF_8 {
+        return false;
+    }
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut output = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length_without_replacement(sample.len())
+            .unwrap_or(sample.len() * 3),
+    );
+    let (result, _) = decoder.decode_to_string_without_replacement(sample, &mut output, false);
+    matches!(result, DecoderResult::InputEmpty)
+}
+
+// Decode file contents to UTF-8. A byte order mark takes precedence, valid UTF-8 is kept as is,
+// and any other bytes are transcoded from the detected encoding, or from the fallback encoding
+// when detection is not confident. Invalid sequences are replaced with U+FFFD.
+pub fn decode(bytes: Vec<u8>, fallback: Option<&'static Encoding>) -> String {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(&bytes) {
+        let (content, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+        return content.into_owned();
+    }
+
+    let bytes = match String::from_utf8(bytes) {
+        Ok(content) => return content,
+        Err(e) => e.into_bytes(),
+    };
+
+    let encoding = match detect(&bytes, true) {
+        (encoding, true) => encoding,
+        (encoding, false) => fallback.unwrap_or(encoding),
+    };
+    let (content, _) = encoding.decode_without_bom_handling(&bytes);
+    content.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
+
+    #[test]
+    fn keeps_utf8_and_follows_byte_order_marks() {
+        assert_eq!(decode("café".as_bytes().to_vec(), None), "café");
+        assert_eq!(decode(b"\xEF\xBB\xBFcaf\xC3\xA9".to_vec(), None), "café");
+        let mut utf16 = vec![0xFF, 0xFE];
+        utf16.extend("café".encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(decode(utf16, Some(WINDOWS_1252)), "café");
+        assert!(has_bom(&[0xFF, 0xFE, b'c', 0]));
+    }
+
+    #[test]
+    fn transcodes_detected_legacy_encodings() {
+        let text = "これは日本語で書かれた文書です。検索できるように変換されます。";
+        let (bytes, _, _) = SHIFT_JIS.encode(text);
+        assert!(String::from_utf8(bytes.to_vec()).is_err());
+        assert!(is_legacy_text(&bytes));
+        assert_eq!(decode(bytes.into_owned(), None), text);
+    }
+
+    #[test]
+    fn looks_up_encodings_by_label() {
+        assert_eq!(encoding_for_label(" Shift_JIS ").unwrap(), SHIFT_JIS);
+        assert_eq!(encoding_for_label("latin1").unwrap(), WINDOWS_1252);
+        assert!(encoding_for_label("klingon").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use rmcp::Error as McpError;
+use std::fmt;
+
+// Errors of the tools, by the kind of failure, so that clients and tests can tell a missing
+// directory from a malformed query without parsing the message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchError {
+    // A directory or file that does not exist
+    NotFound(String),
+    // A path that had to be a directory
+    NotADirectory(String),
+    // A path that had to be a file
+    NotAFile(String),
+    // An index id the index tool never returned
+    UnknownIndex(String),
+    // A parameter out of range, or options that can't be combined
+    InvalidParams(String),
+    // A keyword, regex or timestamp that can't be parsed
+    QueryParse(String),
+    // A directory so large that indexing it has to be confirmed
+    ConfirmationRequired(String),
+    // Reading files or walking directories failed
+    Io(String),
+    // Building, updating or committing the index failed
+    IndexWrite(String),
+    // Reading or searching the index failed
+    IndexRead(String),
+    // The search did not finish within its timeout
+    Timeout(String),
+    // Anything else, such as a failed background task
+    Internal(String),
+}
+
+impl SearchError {
+    // Machine-readable kind of the error, sent as the code in the data of the MCP error
+    pub fn code(&self) -> &'static str {
+        match self {
+            SearchError::NotFound(_) => "not_found",
+            SearchError::NotADirectory(_) => "not_a_directory",
+            SearchError::NotAFile(_) => "not_a_file",
+            SearchError::UnknownIndex(_) => "unknown_index",
+            SearchError::InvalidParams(_) => "invalid_params",
+            SearchError::QueryParse(_) => "query_parse",
+            SearchError::ConfirmationRequired(_) => "confirmation_required",
+            SearchError::Io(_) => "io",
+            SearchError::IndexWrite(_) => "index_write",
+            SearchError::IndexRead(_) => "index_read",
+            SearchError::Timeout(_) => "timeout",
+            SearchError::Internal(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            SearchError::NotFound(message)
+            | SearchError::NotADirectory(message)
+            | SearchError::NotAFile(message)
+            | SearchError::UnknownIndex(message)
+            | SearchError::InvalidParams(message)
+            | SearchError::QueryParse(message)
+            | SearchError::ConfirmationRequired(message)
+            | SearchError::Io(message)
+            | SearchError::IndexWrite(message)
+            | SearchError::IndexRead(message)
+            | SearchError::Timeout(message)
+            | SearchError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+// Errors caused by the request map to the matching JSON-RPC codes, the rest to internal errors
+impl From<SearchError> for McpError {
+    fn from(error: SearchError) -> Self {
+        let data = Some(serde_json::json!({ "code": error.code() }));
+        let message = error.to_string();
+        match error {
+            SearchError::InvalidParams(_)
+            | SearchError::QueryParse(_)
+            | SearchError::ConfirmationRequired(_) => McpError::invalid_params(message, data),
+            SearchError::NotFound(_)
+            | SearchError::NotADirectory(_)
+            | SearchError::NotAFile(_)
+            | SearchError::UnknownIndex(_) => McpError::resource_not_found(message, data),
+            SearchError::Io(_)
+            | SearchError::IndexWrite(_)
+            | SearchError::IndexRead(_)
+            | SearchError::Timeout(_)
+            | SearchError::Internal(_) => McpError::internal_error(message, data),
+        }
+    }
+}
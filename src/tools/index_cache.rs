@@ -0,0 +1,289 @@
+use crate::tools::indexer::{
+    FileRecords, IndexFields, IndexOptions, UpdatePlan, WriterOptions, build_schema, create_writer,
+    delete_path, index_files,
+};
+use crate::tools::tokenizers::register_tokenizers;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tantivy::Index;
+use tracing;
+
+// Version of the manifest format; bump it whenever the schema or manifest layout changes
+const MANIFEST_VERSION: u32 = 23;
+
+// Manifest stored next to a cached index, describing the files it was built from
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct IndexManifest {
+    version: u32,
+    roots: Vec<String>,
+    // Options the index was built with; a change requires a rebuild
+    options: IndexOptions,
+    files: FileRecords,
+}
+
+// Stable 64-bit FNV-1a hash, used to derive cache folder names
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Roots of a cached index as stored in its manifest, with the paths of its index directory and
+// manifest, keyed by the canonicalized directory paths
+fn cache_paths(cache_dir: &Path, roots: &[PathBuf]) -> (Vec<String>, PathBuf, PathBuf) {
+    let roots: Vec<String> = roots
+        .iter()
+        .map(|root| root.to_string_lossy().to_string())
+        .collect();
+    let key = format!("{:016x}", fnv1a(roots.join("\n").as_bytes()));
+    let index_dir = cache_dir.join(&key);
+    let manifest_path = cache_dir.join(format!("{}.manifest.json", key));
+    (roots, index_dir, manifest_path)
+}
+
+// Discard the cached index of the directories, so that the next build starts from scratch
+// whatever the freshness of the files. Returns whether there was a cached index.
+pub fn invalidate(cache_dir: &Path, roots: &[PathBuf]) -> Result<bool, String> {
+    let (_, index_dir, manifest_path) = cache_paths(cache_dir, roots);
+    let cached = manifest_path.exists() || index_dir.exists();
+    // The manifest goes first, as an index without it is never reused
+    if manifest_path.exists() {
+        fs::remove_file(&manifest_path)
+            .map_err(|e| format!("Cache manifest removal error: {}", e))?;
+    }
+    if index_dir.exists() {
+        fs::remove_dir_all(&index_dir).map_err(|e| format!("Cache removal error: {}", e))?;
+    }
+    Ok(cached)
+}
+
+// Load the cached index and its manifest if it was built for the same roots and options
+fn open_cached(
+    index_dir: &Path,
+    manifest_path: &Path,
+    roots: &[String],
+    options: &IndexOptions,
+) -> Option<(Index, IndexFields, IndexManifest)> {
+    let manifest: IndexManifest = serde_json::from_str(&fs::read_to_string(manifest_path).ok()?)
+        .inspect_err(|e| tracing::warn!("Ignoring unreadable index manifest: {}", e))
+        .ok()?;
+
+    if manifest.version != MANIFEST_VERSION {
+        tracing::info!("Cached index has an outdated format, rebuilding");
+        return None;
+    }
+    if manifest.roots != roots {
+        tracing::info!("Cached index belongs to other directories, rebuilding");
+        return None;
+    }
+    if &manifest.options != options {
+        tracing::info!("Cached index was built with different options, rebuilding");
+        return None;
+    }
+
+    // The directory is memory-mapped, so the OS pages segments in on demand and shares them
+    // between the searches instead of loading the whole index
+    let index = Index::open_in_dir(index_dir)
+        .inspect_err(|e| tracing::warn!("Failed to open cached index: {}", e))
+        .ok()?;
+    register_tokenizers(&index, options);
+    let fields = IndexFields::from_schema(&index.schema()).ok()?;
+    Some((index, fields, manifest))
+}
+
+// Create an empty on-disk index, replacing whatever was cached before
+fn create_fresh(
+    index_dir: &Path,
+    manifest_path: &Path,
+    options: &IndexOptions,
+) -> Result<(Index, IndexFields), String> {
+    if manifest_path.exists() {
+        fs::remove_file(manifest_path)
+            .map_err(|e| format!("Cache manifest removal error: {}", e))?;
+    }
+    if index_dir.exists() {
+        fs::remove_dir_all(index_dir).map_err(|e| format!("Cache removal error: {}", e))?;
+    }
+    fs::create_dir_all(index_dir).map_err(|e| {
+        format!(
+            "Cache directory creation error '{}': {}",
+            index_dir.display(),
+            e
+        )
+    })?;
+
+    let (schema, fields) = build_schema(options);
+    let index = Index::create_in_dir(index_dir, schema)
+        .map_err(|e| format!("Index creation error: {}", e))?;
+    register_tokenizers(&index, options);
+    Ok((index, fields))
+}
+
+// Open the on-disk index of the directories from the cache and bring it up to date, returning
+// the records of the files. Files whose modification time and size are unchanged are neither
+// read nor indexed again; changed, added and removed files are updated in place. After a walk
+// cut short by a deadline or cancellation, files missing from the list may just not have been
+// reached, so they are kept.
+pub fn open_or_build(
+    cache_dir: &Path,
+    roots: &[PathBuf],
+    files: &[PathBuf],
+    walk_complete: bool,
+    options: &IndexOptions,
+    writer_options: &WriterOptions,
+) -> Result<(Index, IndexFields, FileRecords), String> {
+    let (roots, index_dir, manifest_path) = cache_paths(cache_dir, roots);
+
+    let (index, fields, previous) = match open_cached(&index_dir, &manifest_path, &roots, options) {
+        Some((index, fields, manifest)) => (index, fields, Some(manifest.files)),
+        None => {
+            let (index, fields) = create_fresh(&index_dir, &manifest_path, options)?;
+            (index, fields, None)
+        }
+    };
+    let cached = previous.is_some();
+    let previous = previous.unwrap_or_default();
+
+    let plan = UpdatePlan::new(files, &previous, walk_complete);
+    if cached && plan.changed.is_empty() && plan.stale.is_empty() {
+        tracing::info!("Reusing cached index: {}", index_dir.display());
+        return Ok((index, fields, plan.records));
+    }
+
+    // Remove the manifest first so an interrupted update is never treated as fresh
+    if manifest_path.exists() {
+        fs::remove_file(&manifest_path)
+            .map_err(|e| format!("Cache manifest removal error: {}", e))?;
+    }
+
+    tracing::info!(
+        "Updating cached index: {} files to read, {} documents to remove",
+        plan.changed.len(),
+        plan.stale.len()
+    );
+    let mut index_writer = create_writer(&index, writer_options)?;
+    for path in &plan.stale {
+        delete_path(&index_writer, fields, path)?;
+    }
+    let kinds = index_files(
+        &plan.changed,
+        &index_writer,
+        fields,
+        options,
+        writer_options,
+    )?;
+    index_writer
+        .commit()
+        .map_err(|e| format!("Commit error: {}", e))?;
+    let records = plan.finish(kinds);
+
+    let manifest = IndexManifest {
+        version: MANIFEST_VERSION,
+        roots,
+        options: options.clone(),
+        files: records.clone(),
+    };
+    let manifest_json = serde_json::to_string(&manifest)
+        .map_err(|e| format!("Cache manifest serialization error: {}", e))?;
+    fs::write(&manifest_path, manifest_json)
+        .map_err(|e| format!("Cache manifest write error: {}", e))?;
+
+    tracing::info!("Stored index in cache: {}", index_dir.display());
+    Ok((index, fields, records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::Count;
+    use tantivy::query::QueryParser;
+
+    // Index the files directly in the root, as a walk that may have been cut short
+    fn build(
+        cache_dir: &Path,
+        root: &Path,
+        files: &[&str],
+        walk_complete: bool,
+    ) -> (Index, IndexFields) {
+        let files: Vec<PathBuf> = files.iter().map(|name| root.join(name)).collect();
+        let (index, fields, _) = open_or_build(
+            cache_dir,
+            &[root.to_path_buf()],
+            &files,
+            walk_complete,
+            &IndexOptions::default(),
+            &WriterOptions::default(),
+        )
+        .unwrap();
+        (index, fields)
+    }
+
+    fn count(index: &Index, fields: IndexFields, word: &str) -> usize {
+        let query = QueryParser::for_index(index, vec![fields.content])
+            .parse_query(word)
+            .unwrap();
+        index
+            .reader()
+            .unwrap()
+            .searcher()
+            .search(&query, &Count)
+            .unwrap()
+    }
+
+    #[test]
+    fn reuses_the_index_while_files_are_unchanged() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.txt"), "alpha").unwrap();
+        let (index, _) = build(cache.path(), root.path(), &["a.txt"], true);
+        let opstamp = index.load_metas().unwrap().opstamp;
+
+        // Nothing is committed when no file has to be read or removed
+        let (index, fields) = build(cache.path(), root.path(), &["a.txt"], true);
+        assert_eq!(index.load_metas().unwrap().opstamp, opstamp);
+        assert_eq!(count(&index, fields, "alpha"), 1);
+    }
+
+    #[test]
+    fn reads_modified_files_again() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.txt"), "alpha").unwrap();
+        build(cache.path(), root.path(), &["a.txt"], true);
+
+        fs::write(root.path().join("a.txt"), "charlie delta").unwrap();
+        let (index, fields) = build(cache.path(), root.path(), &["a.txt"], true);
+        assert_eq!(count(&index, fields, "alpha"), 0);
+        assert_eq!(count(&index, fields, "charlie"), 1);
+    }
+
+    #[test]
+    fn drops_deleted_files() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.txt"), "alpha").unwrap();
+        fs::write(root.path().join("b.txt"), "bravo").unwrap();
+        build(cache.path(), root.path(), &["a.txt", "b.txt"], true);
+
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+        let (index, fields) = build(cache.path(), root.path(), &["a.txt"], true);
+        assert_eq!(count(&index, fields, "alpha"), 1);
+        assert_eq!(count(&index, fields, "bravo"), 0);
+    }
+
+    #[test]
+    fn keeps_files_a_partial_walk_did_not_reach() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.txt"), "alpha").unwrap();
+        fs::write(root.path().join("b.txt"), "bravo").unwrap();
+        build(cache.path(), root.path(), &["a.txt", "b.txt"], true);
+
+        let (index, fields) = build(cache.path(), root.path(), &["a.txt"], false);
+        assert_eq!(count(&index, fields, "bravo"), 1);
+        // The next complete walk still finds the file unchanged
+        let (index, fields) = build(cache.path(), root.path(), &["a.txt", "b.txt"], true);
+        assert_eq!(count(&index, fields, "alpha"), 1);
+        assert_eq!(count(&index, fields, "bravo"), 1);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::tools::indexer::IndexFields;
+use tantivy::Searcher;
+use tantivy::schema::Field;
+use tantivy::termdict::TermMerger;
+
+// Statistics of one tokenized field of the index
+#[derive(Debug, serde::Serialize)]
+pub struct FieldStats {
+    pub field: String,
+    // Distinct terms across all segments, including terms of deleted documents that were not
+    // merged away yet
+    pub unique_terms: u64,
+    pub total_tokens: u64,
+    // Average number of tokens per document
+    pub average_length: f64,
+}
+
+// Size and shape of an index, to understand the corpus and tune queries and relevance
+#[derive(Debug, serde::Serialize)]
+pub struct CorpusStats {
+    pub num_docs: u64,
+    pub deleted_docs: u64,
+    pub segments: usize,
+    // Bytes taken by the index files
+    pub index_bytes: u64,
+    pub fields: Vec<FieldStats>,
+}
+
+// Gather the statistics of the content and file name fields from a searcher
+pub fn corpus_stats(searcher: &Searcher, fields: IndexFields) -> Result<CorpusStats, String> {
+    let segment_readers = searcher.segment_readers();
+    let index_bytes = searcher
+        .space_usage()
+        .map_err(|e| format!("Index read error: {}", e))?
+        .total()
+        .get_bytes();
+    let text_fields = [fields.content, fields.filename]
+        .into_iter()
+        .chain(fields.content_exact)
+        .chain(fields.content_phonetic)
+        .map(|field| field_stats(searcher, field).map_err(|e| format!("Index read error: {}", e)))
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(CorpusStats {
+        num_docs: searcher.num_docs(),
+        deleted_docs: segment_readers
+            .iter()
+            .map(|segment_reader| u64::from(segment_reader.num_deleted_docs()))
+            .sum(),
+        segments: segment_readers.len(),
+        index_bytes,
+        fields: text_fields,
+    })
+}
+
+fn field_stats(searcher: &Searcher, field: Field) -> tantivy::Result<FieldStats> {
+    let inverted_indexes = searcher
+        .segment_readers()
+        .iter()
+        .map(|segment_reader| segment_reader.inverted_index(field))
+        .collect::<tantivy::Result<Vec<_>>>()?;
+    let total_tokens: u64 = inverted_indexes
+        .iter()
+        .map(|inverted_index| inverted_index.total_num_tokens())
+        .sum();
+
+    // A term present in several segments is counted once by merging their sorted dictionaries
+    let streams = inverted_indexes
+        .iter()
+        .map(|inverted_index| inverted_index.terms().stream())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut merger = TermMerger::new(streams);
+    let mut unique_terms = 0;
+    while merger.advance() {
+        unique_terms += 1;
+    }
+
+    Ok(FieldStats {
+        field: searcher.schema().get_field_name(field).to_string(),
+        unique_terms,
+        total_tokens,
+        average_length: total_tokens as f64 / searcher.num_docs().max(1) as f64,
+    })
+}
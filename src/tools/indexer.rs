@@ -0,0 +1,1859 @@
+use crate::tools::archives::{self, ArchiveEntry};
+use crate::tools::encoding::{self, encoding_for_label};
+use crate::tools::json_lines;
+use crate::tools::pdf;
+use crate::tools::tokenizers::{
+    PHONETIC_TOKENIZER, Segmentation, content_tokenizer, exact_tokenizer,
+};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tantivy::collector::TopDocs;
+use tantivy::query::{RegexQuery, TermQuery};
+use tantivy::schema::{
+    FAST, Field, INDEXED, IndexRecordOption, STORED, STRING, Schema, TEXT, TextFieldIndexing,
+    TextOptions, Value,
+};
+use tantivy::{Index, IndexWriter, Searcher, TantivyDocument, Term, doc};
+use tokio_util::sync::CancellationToken;
+use tracing;
+
+// Blacklist of extensions likely to be binary files
+// Skip extensions that are clearly binary files, unless the index options say otherwise
+pub const BINARY_EXTENSIONS: &[&str] = &[
+    "exe", "dll", "so", "dylib", "bin", "obj", "o", "a", "lib", "png", "jpg", "jpeg", "gif", "bmp",
+    "tiff", "webp", "ico", "mp3", "mp4", "wav", "ogg", "flac", "avi", "mov", "mkv", "zip", "gz",
+    "tar", "7z", "rar", "jar", "war", "pdf", "doc", "docx", "xls", "xlsx", "ppt", "pptx", "db",
+    "sqlite", "mdb", "iso", "dmg", "class",
+];
+
+// Content whose sample has a larger share of control characters is binary
+pub const DEFAULT_MAX_CONTROL_RATIO: f32 = 0.3;
+// Content that is not valid UTF-8 is still text when it has a larger share of ASCII bytes
+pub const DEFAULT_MIN_ASCII_RATIO: f32 = 0.8;
+
+// Thresholds deciding from a sample of its bytes whether content is text
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TextDetection {
+    pub max_control_ratio: f32,
+    pub min_ascii_ratio: f32,
+}
+
+impl Default for TextDetection {
+    fn default() -> Self {
+        Self {
+            max_control_ratio: DEFAULT_MAX_CONTROL_RATIO,
+            min_ascii_ratio: DEFAULT_MIN_ASCII_RATIO,
+        }
+    }
+}
+
+// The default blacklist in the form the index options hold it
+pub fn default_binary_extensions() -> BTreeSet<String> {
+    BINARY_EXTENSIONS
+        .iter()
+        .map(|ext| ext.to_string())
+        .collect()
+}
+
+// Buffer size of the index writer unless another size is given
+pub const DEFAULT_WRITER_BUFFER_BYTES: usize = 50_000_000;
+// Smallest buffer Tantivy accepts for an index writer
+pub const MIN_WRITER_BUFFER_BYTES: usize = 15_000_000;
+// Number of read documents that may wait for the index writer
+const DOCUMENT_CHANNEL_CAPACITY: usize = 64;
+// Files larger than this are skipped unless another limit is given (10 MB)
+pub const DEFAULT_MAX_FILE_SIZE_BYTES: u64 = 10 * 1024 * 1024;
+
+// Names of the fields that are also read as fast fields
+pub const PATH_FIELD: &str = "path";
+pub const MODIFIED_FIELD: &str = "modified";
+pub const SIZE_FIELD: &str = "size";
+pub const EXTENSION_FIELD: &str = "extension";
+
+// Fields of the index schema
+#[derive(Debug, Clone, Copy)]
+pub struct IndexFields {
+    pub path: Field,
+    pub filename: Field,
+    pub content: Field,
+    // Unstemmed copy of the content, indexed in recall mode where the content field is stemmed
+    pub content_exact: Option<Field>,
+    // Soundex codes of the words of the content, indexed in phonetic mode to match names by how
+    // they sound
+    pub content_phonetic: Option<Field>,
+    // Modification time in seconds since the Unix epoch
+    pub modified: Field,
+    // Size of the content in bytes, before decoding
+    pub size: Field,
+    // Lowercase extension of the file name without the leading dot, empty when there is none
+    pub extension: Field,
+}
+
+impl IndexFields {
+    // Look up the fields in the schema of an existing index
+    pub fn from_schema(schema: &Schema) -> Result<Self, String> {
+        let field = |name: &str| {
+            schema
+                .get_field(name)
+                .map_err(|e| format!("Schema error: {}", e))
+        };
+        Ok(Self {
+            path: field(PATH_FIELD)?,
+            filename: field("filename")?,
+            content: field("content")?,
+            content_exact: schema.get_field("content_exact").ok(),
+            content_phonetic: schema.get_field("content_phonetic").ok(),
+            modified: field(MODIFIED_FIELD)?,
+            size: field(SIZE_FIELD)?,
+            extension: field(EXTENSION_FIELD)?,
+        })
+    }
+}
+
+// Counters of the directory processing status
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct IndexStats {
+    pub found_files: usize,
+    pub indexed_files: usize,
+    pub skipped_files: usize,
+    // Breakdown of skipped_files by reason
+    pub skipped_binary: usize,
+    pub skipped_empty: usize,
+    pub skipped_read_error: usize,
+    // Files that kept failing with transient errors, such as timeouts on network filesystems,
+    // after all retries
+    #[serde(default)]
+    pub skipped_unavailable: usize,
+    // PDF documents whose text could not be extracted, such as encrypted or malformed ones
+    #[serde(default)]
+    pub skipped_extraction_failed: usize,
+    // Files skipped because they exceed the maximum file size
+    pub skipped_too_large: usize,
+    // Files left out because the total byte limit of the indexing run was reached
+    pub skipped_byte_limit: usize,
+    // Symbolic links that were not followed; they are not counted as found files
+    pub skipped_symlinks: usize,
+    // Directories and files skipped during the walk because they could not be accessed
+    pub permission_errors: usize,
+}
+
+// How a collected file was handled while indexing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    Indexed,
+    Binary,
+    Empty,
+    TooLarge,
+    ReadError,
+    Unavailable,
+    ExtractionFailed,
+    OverByteLimit,
+}
+
+impl FileKind {
+    // Human-readable description of how a file of this kind is handled
+    pub fn reason(self) -> &'static str {
+        match self {
+            FileKind::Indexed => "indexed",
+            FileKind::Binary => "binary",
+            FileKind::Empty => "empty",
+            FileKind::TooLarge => "too large",
+            FileKind::ReadError => "read error",
+            FileKind::Unavailable => "unavailable after retries",
+            FileKind::ExtractionFailed => "text extraction failed",
+            FileKind::OverByteLimit => "total byte limit reached",
+        }
+    }
+}
+
+// Modification time and size of a file at the time it was indexed
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileStamp {
+    pub modified_secs: u64,
+    pub modified_nanos: u32,
+    pub size: u64,
+}
+
+impl FileStamp {
+    // Read the stamp of a file from its metadata
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+            size: metadata.len(),
+        })
+    }
+}
+
+// What is remembered about a collected file, so that it is not read again while unchanged
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FileRecord {
+    // A missing stamp means the file could not be inspected, so the record is never trusted
+    pub stamp: Option<FileStamp>,
+    pub kind: FileKind,
+}
+
+// Records of the collected files of an index, keyed by path
+pub type FileRecords = BTreeMap<String, FileRecord>;
+
+// What an update of an index has to do, given the records of its last run
+pub struct UpdatePlan {
+    // Records of the files that are unchanged since
+    pub records: FileRecords,
+    // Files to read again, with their stamps taken before reading so that a change during the
+    // read is noticed next time
+    pub changed: Vec<PathBuf>,
+    stamps: Vec<Option<FileStamp>>,
+    // Paths whose documents belong to changed files or to files that are gone
+    pub stale: Vec<String>,
+}
+
+impl UpdatePlan {
+    // Compare the collected files with the records of the last run. Files whose modification
+    // time and size are unchanged are kept, whether they were indexed or skipped. After a walk
+    // cut short by a deadline or cancellation, files missing from the list may just not have
+    // been reached, so their records are kept too.
+    pub fn new(files: &[PathBuf], previous: &FileRecords, walk_complete: bool) -> Self {
+        let mut records = BTreeMap::new();
+        let mut changed = Vec::new();
+        let mut stamps = Vec::new();
+        for path in files {
+            let path_key = path.to_string_lossy().to_string();
+            let stamp = FileStamp::of(path);
+            match previous.get(&path_key) {
+                // Files left out at the byte limit may fit into the budget of this run, and
+                // files that were unavailable may be readable again
+                Some(record)
+                    if stamp.is_some()
+                        && record.stamp == stamp
+                        && record.kind != FileKind::OverByteLimit
+                        && record.kind != FileKind::Unavailable =>
+                {
+                    records.insert(path_key, record.clone());
+                }
+                _ => {
+                    changed.push(path.clone());
+                    stamps.push(stamp);
+                }
+            }
+        }
+        if !walk_complete {
+            let listed: HashSet<String> = files
+                .iter()
+                .map(|path| path.to_string_lossy().to_string())
+                .collect();
+            for (path, record) in previous {
+                if !listed.contains(path) {
+                    records.insert(path.clone(), record.clone());
+                }
+            }
+        }
+        let stale = previous
+            .iter()
+            .filter(|(path, record)| {
+                record.kind == FileKind::Indexed && !records.contains_key(*path)
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        Self {
+            records,
+            changed,
+            stamps,
+            stale,
+        }
+    }
+
+    // Records of all files once the changed ones were read, as reported by index_files. Files
+    // left unread at a deadline get no record, so the next run reads them.
+    pub fn finish(self, kinds: Vec<(PathBuf, FileKind)>) -> FileRecords {
+        let mut records = self.records;
+        let stamps: HashMap<PathBuf, Option<FileStamp>> =
+            self.changed.into_iter().zip(self.stamps).collect();
+        for (path, kind) in kinds {
+            let stamp = stamps.get(&path).cloned().flatten();
+            records.insert(
+                path.to_string_lossy().to_string(),
+                FileRecord { stamp, kind },
+            );
+        }
+        records
+    }
+}
+
+impl IndexStats {
+    // Tally the counters from the records of the collected files
+    pub fn from_records(records: &FileRecords) -> Self {
+        Self::from_kinds(records.values().map(|record| record.kind))
+    }
+
+    // Tally the counters from the way each collected file was handled
+    pub fn from_kinds(kinds: impl IntoIterator<Item = FileKind>) -> Self {
+        let mut stats = Self::default();
+        for kind in kinds {
+            stats.found_files += 1;
+            match kind {
+                FileKind::Indexed => stats.indexed_files += 1,
+                FileKind::Binary => stats.skipped_binary += 1,
+                FileKind::Empty => stats.skipped_empty += 1,
+                FileKind::TooLarge => stats.skipped_too_large += 1,
+                FileKind::ReadError => stats.skipped_read_error += 1,
+                FileKind::Unavailable => stats.skipped_unavailable += 1,
+                FileKind::ExtractionFailed => stats.skipped_extraction_failed += 1,
+                FileKind::OverByteLimit => stats.skipped_byte_limit += 1,
+            }
+            if kind != FileKind::Indexed {
+                stats.skipped_files += 1;
+            }
+        }
+        stats
+    }
+
+    // One-line summary of the counters, including the reasons files were skipped
+    pub fn summary(&self) -> String {
+        format!(
+            "Found files: {}, Indexed: {}, Skipped: {} (binary: {}, empty: {}, read errors: {}, unavailable after retries: {}, extraction failed: {}, too large: {}, over byte limit: {}), Symlinks skipped: {}, Permission errors: {}",
+            self.found_files,
+            self.indexed_files,
+            self.skipped_files,
+            self.skipped_binary,
+            self.skipped_empty,
+            self.skipped_read_error,
+            self.skipped_unavailable,
+            self.skipped_extraction_failed,
+            self.skipped_too_large,
+            self.skipped_byte_limit,
+            self.skipped_symlinks,
+            self.permission_errors
+        )
+    }
+}
+
+// Options controlling how the collected files are read and indexed
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct IndexOptions {
+    // Files larger than this many bytes are skipped without being read
+    pub max_file_size_bytes: u64,
+    // Keep the letter case of terms in file contents
+    pub case_sensitive: bool,
+    // How file contents are split into words
+    pub segmentation: Segmentation,
+    // Reduce English words to their stem so that "running" matches "runs"
+    pub stemming: bool,
+    // Index the content both stemmed and unstemmed, so that queries find inflected forms while
+    // exact matches rank higher. Implies stemming of the content field.
+    pub recall_mode: bool,
+    // Also index the Soundex code of every word, so that "Smith" matches "Smyth"
+    pub phonetic: bool,
+    // Split camelCase and snake_case identifiers into their words
+    pub code_tokenizer: bool,
+    // Encoding assumed for non-UTF-8 files whose encoding can't be detected confidently
+    pub fallback_encoding: Option<String>,
+    // Index the text entries of zip and tar archives instead of skipping the archives
+    pub index_archives: bool,
+    // Decompress gzip files such as rotated logs and index their text
+    pub index_gzip: bool,
+    // Index every line of .jsonl and .ndjson files as a document of its own, under the path of
+    // the file followed by '#' and the line number
+    pub jsonl_mode: bool,
+    // Extract and index the text of PDF documents instead of skipping them as binary
+    pub index_pdf: bool,
+    // Keep the content in the index for snippets, lines and regex scans. Without it the index
+    // is much smaller, but hits only carry their paths and scores.
+    pub store_content: bool,
+    // Index only this many bytes from the start of every file, such as license headers
+    pub content_head_bytes: Option<usize>,
+    // Files with these extensions are skipped as binary without being read (lowercase, without
+    // the leading dot)
+    pub binary_extensions: BTreeSet<String>,
+    // Thresholds of the binary detection of file contents
+    pub text_detection: TextDetection,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            max_file_size_bytes: DEFAULT_MAX_FILE_SIZE_BYTES,
+            case_sensitive: false,
+            segmentation: Segmentation::Default,
+            stemming: false,
+            recall_mode: false,
+            phonetic: false,
+            code_tokenizer: false,
+            fallback_encoding: None,
+            index_archives: false,
+            index_gzip: false,
+            jsonl_mode: false,
+            index_pdf: false,
+            store_content: true,
+            content_head_bytes: None,
+            binary_extensions: default_binary_extensions(),
+            text_detection: TextDetection::default(),
+        }
+    }
+}
+
+// Define schema for Tantivy (file paths, file names, content and modification times)
+pub fn build_schema(options: &IndexOptions) -> (Schema, IndexFields) {
+    let mut schema_builder = Schema::builder();
+    // Paths are indexed untokenized so that documents can be looked up by their exact path,
+    // and kept as a fast field so that hits can be sorted by path
+    let path = schema_builder.add_text_field(PATH_FIELD, STRING | STORED | FAST);
+    // File names are tokenized so that "deployment" finds "deployment-config.yaml"
+    let filename = schema_builder.add_text_field("filename", TEXT);
+
+    // Improve content field settings: explicitly set indexing options
+    let text_indexing = TextFieldIndexing::default()
+        .set_tokenizer(&content_tokenizer(options))
+        .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+    let text_options = TextOptions::default().set_indexing_options(text_indexing);
+    let text_options = if options.store_content {
+        text_options.set_stored()
+    } else {
+        text_options
+    };
+    let content = schema_builder.add_text_field("content", text_options);
+    // In recall mode the content is indexed a second time without stemming. It is not stored,
+    // as snippets and lines are taken from the content field.
+    let content_exact = options.recall_mode.then(|| {
+        let exact_indexing = TextFieldIndexing::default()
+            .set_tokenizer(&exact_tokenizer(options))
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+        schema_builder.add_text_field(
+            "content_exact",
+            TextOptions::default().set_indexing_options(exact_indexing),
+        )
+    });
+    // In phonetic mode the Soundex codes of the words are indexed as well, with positions so
+    // that phrases sound alike too
+    let content_phonetic = options.phonetic.then(|| {
+        let phonetic_indexing = TextFieldIndexing::default()
+            .set_tokenizer(PHONETIC_TOKENIZER)
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+        schema_builder.add_text_field(
+            "content_phonetic",
+            TextOptions::default().set_indexing_options(phonetic_indexing),
+        )
+    });
+    let modified = schema_builder.add_u64_field(MODIFIED_FIELD, INDEXED | STORED | FAST);
+    // Metadata that searches can filter on
+    let size = schema_builder.add_u64_field(SIZE_FIELD, INDEXED | STORED | FAST);
+    let extension = schema_builder.add_text_field(EXTENSION_FIELD, STRING | STORED | FAST);
+
+    (
+        schema_builder.build(),
+        IndexFields {
+            path,
+            filename,
+            content,
+            content_exact,
+            content_phonetic,
+            modified,
+            size,
+            extension,
+        },
+    )
+}
+
+// Extension of a file name as indexed: lowercase, without the leading dot, empty when there is
+// none
+pub fn file_extension(file_name: &str) -> String {
+    Path::new(file_name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+// Function to determine if a file is a text file
+pub fn is_text_file(path: &Path, options: &IndexOptions) -> bool {
+    // 1. First check extensions that are clearly binary
+    if has_binary_extension(&path.to_string_lossy(), &options.binary_extensions) {
+        return false;
+    }
+
+    // 2. Read the beginning of the file and determine if it is binary
+    match read_head(path, Some(TEXT_SAMPLE_BYTES)) {
+        Ok(bytes) => is_text_content(&bytes, options.text_detection),
+        Err(_) => false, // Do not consider files with read errors as text
+    }
+}
+
+// Bytes from the start of a file sampled to tell text from binary content
+const TEXT_SAMPLE_BYTES: usize = 8192;
+
+// Read a whole file, or only its first `head_bytes` bytes
+fn read_head(path: &Path, head_bytes: Option<usize>) -> io::Result<Vec<u8>> {
+    let Some(head_bytes) = head_bytes else {
+        return fs::read(path);
+    };
+    let mut bytes = Vec::with_capacity(head_bytes.min(TEXT_SAMPLE_BYTES));
+    fs::File::open(path)?
+        .take(head_bytes as u64)
+        .read_to_end(&mut bytes)?;
+    truncate_head(&mut bytes, Some(head_bytes));
+    Ok(bytes)
+}
+
+// Cut content down to its first `head_bytes` bytes. A UTF-8 character split at the end is
+// dropped whole, so that the encoding detection doesn't give up on UTF-8 over it.
+fn truncate_head(bytes: &mut Vec<u8>, head_bytes: Option<usize>) {
+    let Some(head_bytes) = head_bytes else {
+        return;
+    };
+    if bytes.len() < head_bytes {
+        return;
+    }
+    bytes.truncate(head_bytes);
+    if let Err(e) = std::str::from_utf8(bytes)
+        && e.error_len().is_none()
+    {
+        bytes.truncate(e.valid_up_to());
+    }
+}
+
+// Cut text down to at most `head_bytes` bytes, at a character boundary
+fn truncate_text(text: &mut String, head_bytes: Option<usize>) {
+    if let Some(head_bytes) = head_bytes
+        && text.len() > head_bytes
+    {
+        let end = (0..=head_bytes)
+            .rev()
+            .find(|&end| text.is_char_boundary(end))
+            .unwrap_or(0);
+        text.truncate(end);
+    }
+}
+
+// Whether the given name has one of the extensions that are clearly binary
+pub fn has_binary_extension(name: &str, binary_extensions: &BTreeSet<String>) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|ext| binary_extensions.contains(&ext.to_string_lossy().to_lowercase()))
+}
+
+// Determine from its beginning whether content is text; empty content is not
+pub fn is_text_content(bytes: &[u8], detection: TextDetection) -> bool {
+    if bytes.is_empty() {
+        return false;
+    }
+    // Sample size (read up to 8KB)
+    let sample_size = std::cmp::min(bytes.len(), TEXT_SAMPLE_BYTES);
+    let sample = &bytes[..sample_size];
+
+    // Detect binary characteristics
+    // A byte order mark identifies Unicode text, including UTF-16 with its NULL bytes
+    if encoding::has_bom(sample) {
+        return true;
+    }
+
+    // 1. Detect NULL bytes (text files do not have NULL bytes)
+    if sample.iter().any(|&b| b == 0) {
+        return false;
+    }
+
+    // 2. Check the ratio of control characters. Form feeds page source files and printouts and
+    // escape starts the ANSI color codes of logs, so neither counts
+    let control_chars_count = sample
+        .iter()
+        .filter(|&&b| {
+            b < 32 && !matches!(b, 9 | 10 | 12 | 13 | 27) // Exclude Tab, LF, FF, CR, ESC
+        })
+        .count();
+
+    // If the ratio of control characters is too high, consider it binary
+    if (control_chars_count as f32 / sample_size as f32) > detection.max_control_ratio {
+        return false;
+    }
+
+    // 3. Check if it is valid UTF-8
+    let is_valid_utf8 = std::str::from_utf8(sample).is_ok();
+
+    // 4. Check the ASCII ratio
+    let ascii_ratio = sample.iter().filter(|&&b| b <= 127).count() as f32 / sample_size as f32;
+
+    // Valid UTF-8 with a high ASCII ratio, or text in a detectable legacy encoding
+    // such as Shift_JIS or Latin-1
+    is_valid_utf8 || ascii_ratio > detection.min_ascii_ratio || encoding::is_legacy_text(sample)
+}
+
+// Callback receiving the number of files processed so far and the number of files to process
+pub type ProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+// Reads failing with a transient error are retried this many times by default
+pub const DEFAULT_READ_RETRIES: u32 = 2;
+// Wait before the first retry by default, doubled before every further one
+pub const DEFAULT_RETRY_BACKOFF_MS: u64 = 50;
+
+// How reads failing with a transient error are retried, as on network filesystems where a read
+// may time out and succeed a moment later
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    // Wait before the first retry
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_READ_RETRIES,
+            backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF_MS),
+        }
+    }
+}
+
+// Whether an I/O error may go away when the operation is repeated. Missing files and permission
+// errors are permanent and never retried.
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::ResourceBusy
+    )
+}
+
+// Failure of a file operation after the retries of the policy
+enum ReadFailure {
+    // The error was permanent, or the operation was not retried
+    Permanent(io::Error),
+    // The error was transient every time
+    Exhausted(io::Error),
+}
+
+// Run a file operation, repeating it with exponential backoff while it fails with a transient
+// error
+fn with_retries<T>(
+    policy: RetryPolicy,
+    path: &Path,
+    mut operation: impl FnMut() -> io::Result<T>,
+) -> Result<T, ReadFailure> {
+    let mut backoff = policy.backoff;
+    for attempt in 0..=policy.retries {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if !is_transient(&e) => return Err(ReadFailure::Permanent(e)),
+            Err(e) if attempt == policy.retries => return Err(ReadFailure::Exhausted(e)),
+            Err(e) => {
+                tracing::debug!(
+                    "Retrying in {:?} (transient error): {} - {}",
+                    backoff,
+                    path.display(),
+                    e
+                );
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+        }
+    }
+    unreachable!("the last attempt returns")
+}
+
+impl<T> From<ReadFailure> for FileOutcome<T> {
+    fn from(failure: ReadFailure) -> Self {
+        match failure {
+            ReadFailure::Permanent(e) => FileOutcome::ReadError(e.to_string()),
+            ReadFailure::Exhausted(e) => FileOutcome::Unavailable(e.to_string()),
+        }
+    }
+}
+
+// Set once the server is shutting down, so that walks and indexing runs stop early and commit
+// what they have instead of being cut off in the middle of writing an index
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Ask every running and future walk and indexing run to stop early
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::Relaxed)
+}
+
+// Whether the request a walk or an indexing run serves was cancelled
+fn cancelled(cancel: Option<&CancellationToken>) -> bool {
+    cancel.is_some_and(CancellationToken::is_cancelled)
+}
+
+// Number of files processed between two progress reports
+const PROGRESS_INTERVAL_FILES: usize = 100;
+
+// Options controlling the resources used while writing the index;
+// unlike IndexOptions they do not change the resulting index
+#[derive(Default, Clone)]
+pub struct WriterOptions {
+    // Number of threads reading files in parallel (None: one per CPU)
+    pub threads: Option<usize>,
+    // Memory budget of the index writer in bytes (None: DEFAULT_WRITER_BUFFER_BYTES)
+    pub buffer_bytes: Option<usize>,
+    // Files not read by this time are left out of the index
+    pub deadline: Option<Instant>,
+    // Files not read when this is cancelled, as when the client drops the request, are left out
+    // of the index
+    pub cancel: Option<CancellationToken>,
+    // Once this many bytes of text were read, the remaining files are left out of the index
+    pub max_total_bytes: Option<u64>,
+    // How reads failing with a transient error are retried
+    pub read_retry: RetryPolicy,
+    // Called every PROGRESS_INTERVAL_FILES files and when all files are processed
+    pub progress: Option<ProgressCallback>,
+}
+
+// Directories skipped by default: version control data, dependencies and build output
+pub const DEFAULT_EXCLUDE_DIRS: &[&str] = &[
+    ".git",
+    ".hg",
+    ".svn",
+    "node_modules",
+    "target",
+    "dist",
+    "build",
+    ".venv",
+    "venv",
+    "__pycache__",
+];
+
+// Options controlling which files are collected from a directory
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    // Maximum depth of subdirectories to descend into (None: unlimited, 0: top-level only)
+    pub max_depth: Option<usize>,
+    // Honor .gitignore, .ignore and global git excludes
+    pub respect_gitignore: bool,
+    // Only collect files whose path relative to the root matches one of these globs
+    pub include_globs: Option<GlobSet>,
+    // Never collect files whose path relative to the root matches one of these globs
+    pub exclude_globs: Option<GlobSet>,
+    // Follow symbolic links to files and directories instead of skipping them
+    pub follow_symlinks: bool,
+    // Collect files and directories whose name starts with a dot, such as .git or .env
+    pub include_hidden: bool,
+    // Only collect files with one of these extensions (lowercase, without the leading dot)
+    pub extensions: Option<HashSet<String>>,
+    // Names of directories whose whole subtree is skipped, such as node_modules
+    pub exclude_dirs: HashSet<String>,
+    // Only collect files modified at or after this time
+    pub modified_after: Option<SystemTime>,
+    // Stop collecting files once this time has passed
+    pub deadline: Option<Instant>,
+    // Stop collecting files once this is cancelled, as when the client drops the request
+    pub cancel: Option<CancellationToken>,
+    // Stop collecting files once more than this many were found
+    pub max_files: Option<usize>,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            respect_gitignore: true,
+            include_globs: None,
+            exclude_globs: None,
+            follow_symlinks: false,
+            include_hidden: false,
+            extensions: None,
+            modified_after: None,
+            exclude_dirs: HashSet::new(),
+            deadline: None,
+            cancel: None,
+            max_files: None,
+        }
+    }
+}
+
+// Files collected from a directory
+#[derive(Debug, Default)]
+pub struct CollectedFiles {
+    pub files: Vec<PathBuf>,
+    // Symbolic links skipped because they are not followed
+    pub skipped_symlinks: usize,
+    // Entries that could not be accessed because of missing permissions
+    pub permission_errors: usize,
+    // Whether the deadline passed, or the request was cancelled, before the whole directory was
+    // walked
+    pub timed_out: bool,
+    // Whether the walk stopped because more than the maximum number of files were found
+    pub over_max_files: bool,
+}
+
+// Compile glob patterns into a set, reporting the first invalid pattern
+pub fn build_glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).map_err(|e| format!("Invalid glob pattern '{}': {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Glob set build error: {}", e))
+}
+
+impl WalkOptions {
+    // Check the include/exclude globs; excludes take precedence over includes
+    fn matches_globs(&self, relative_path: &Path) -> bool {
+        if self
+            .exclude_globs
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(relative_path))
+        {
+            return false;
+        }
+        match &self.include_globs {
+            Some(include) => include.is_match(relative_path),
+            None => true,
+        }
+    }
+
+    // Check the extension filter, ignoring the letter case of the extension
+    fn matches_extension(&self, path: &Path) -> bool {
+        let Some(extensions) = &self.extensions else {
+            return true;
+        };
+        path.extension()
+            .is_some_and(|ext| extensions.contains(&ext.to_string_lossy().to_lowercase()))
+    }
+}
+
+// Normalize extensions such as ".RS" or "toml" to lowercase without the leading dot
+pub fn build_extension_set(extensions: &[String]) -> HashSet<String> {
+    extensions
+        .iter()
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+// Files tracked by git under the directory, as paths joined to it, or None when the directory
+// is not inside a git work tree or git can't be run
+pub fn git_tracked_files(dir_path: &Path) -> Option<HashSet<PathBuf>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir_path)
+        .args(["ls-files", "-z", "--cached"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    // Paths are listed relative to the directory git runs in
+    Some(
+        output
+            .stdout
+            .split(|&b| b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| dir_path.join(String::from_utf8_lossy(path).as_ref()))
+            .collect(),
+    )
+}
+
+// Function to recursively collect the files in a directory
+pub fn process_directory(dir_path: &Path, options: &WalkOptions) -> Result<CollectedFiles, String> {
+    // Only an unreadable root fails the search; unreadable entries below it are skipped
+    fs::read_dir(dir_path)
+        .map_err(|e| format!("Directory read error '{}': {}", dir_path.display(), e))?;
+
+    let mut builder = WalkBuilder::new(dir_path);
+    builder
+        .standard_filters(options.respect_gitignore)
+        // Set after the standard filters so that it applies even when gitignore is not honored
+        .hidden(!options.include_hidden)
+        .follow_links(options.follow_symlinks)
+        // The walker counts the root itself as depth 0
+        .max_depth(options.max_depth.map(|max_depth| max_depth + 1));
+    // Enter every directory only once, however many links lead to it, so that links to
+    // an ancestor or between sibling trees can neither loop nor duplicate files
+    let visited = options.follow_symlinks.then(|| {
+        let visited: HashSet<PathBuf> = dir_path.canonicalize().into_iter().collect();
+        Mutex::new(visited)
+    });
+    let exclude_dirs = options.exclude_dirs.clone();
+    if visited.is_some() || !exclude_dirs.is_empty() {
+        builder.filter_entry(move |entry| {
+            if entry.depth() == 0
+                || !entry
+                    .file_type()
+                    .is_some_and(|file_type| file_type.is_dir())
+            {
+                return true;
+            }
+            // Excluded directories are pruned before the walker descends into them
+            if exclude_dirs.contains(entry.file_name().to_string_lossy().as_ref()) {
+                tracing::debug!("Skipped (excluded directory): {}", entry.path().display());
+                return false;
+            }
+            match (&visited, entry.path().canonicalize()) {
+                (Some(visited), Ok(canonical)) => visited
+                    .lock()
+                    .map(|mut visited| visited.insert(canonical))
+                    .unwrap_or(true),
+                _ => true,
+            }
+        });
+    }
+
+    let mut collected = CollectedFiles::default();
+    for entry in builder.build() {
+        if shutdown_requested()
+            || cancelled(options.cancel.as_ref())
+            || options
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            collected.timed_out = true;
+            break;
+        }
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e)
+                if e.io_error()
+                    .is_some_and(|io_error| io_error.kind() == ErrorKind::PermissionDenied) =>
+            {
+                collected.permission_errors += 1;
+                tracing::warn!("Skipped (permission denied): {}", e);
+                continue;
+            }
+            Err(e) => {
+                // Malformed ignore files, symlink loops and vanished entries do not abort the search
+                tracing::warn!("Skipped entry: {}", e);
+                continue;
+            }
+        };
+
+        // Links are reported as links only when they are not followed
+        if entry.depth() > 0 && entry.path_is_symlink() && !options.follow_symlinks {
+            collected.skipped_symlinks += 1;
+            tracing::debug!("Skipped (symlink): {}", entry.path().display());
+            continue;
+        }
+
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+
+        // Match globs against the path relative to the search root
+        let relative_path = entry.path().strip_prefix(dir_path).unwrap_or(entry.path());
+        if !options.matches_globs(relative_path) {
+            tracing::debug!("Skipped (glob filter): {}", entry.path().display());
+            continue;
+        }
+        if !options.matches_extension(entry.path()) {
+            tracing::debug!("Skipped (extension filter): {}", entry.path().display());
+            continue;
+        }
+        // Files whose modification time can't be read are kept rather than silently dropped
+        if let Some(modified_after) = options.modified_after
+            && entry
+                .metadata()
+                .ok()
+                .and_then(|metadata| metadata.modified().ok())
+                .is_some_and(|modified| modified < modified_after)
+        {
+            tracing::debug!(
+                "Skipped (modified before cutoff): {}",
+                entry.path().display()
+            );
+            continue;
+        }
+        collected.files.push(entry.into_path());
+        if options
+            .max_files
+            .is_some_and(|max_files| collected.files.len() > max_files)
+        {
+            collected.over_max_files = true;
+            break;
+        }
+    }
+    Ok(collected)
+}
+
+// Result of reading and classifying a single collected file
+enum FileOutcome<T> {
+    Indexed(T),
+    TooLarge(u64),
+    NonText,
+    Empty,
+    ReadError(String),
+    // Failed with a transient error after all retries
+    Unavailable(String),
+    // The text of a document could not be extracted
+    ExtractionFailed(String),
+    OverByteLimit,
+}
+
+impl<T> FileOutcome<T> {
+    // How the file is handled, without the read content
+    fn kind(&self) -> FileKind {
+        match self {
+            FileOutcome::Indexed(_) => FileKind::Indexed,
+            FileOutcome::TooLarge(_) => FileKind::TooLarge,
+            FileOutcome::NonText => FileKind::Binary,
+            FileOutcome::Empty => FileKind::Empty,
+            FileOutcome::ReadError(_) => FileKind::ReadError,
+            FileOutcome::Unavailable(_) => FileKind::Unavailable,
+            FileOutcome::ExtractionFailed(_) => FileKind::ExtractionFailed,
+            FileOutcome::OverByteLimit => FileKind::OverByteLimit,
+        }
+    }
+}
+
+// Bytes of text that an indexing run may still read, shared by the reading threads
+struct ByteBudget {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl ByteBudget {
+    fn new(limit: Option<u64>) -> Option<Self> {
+        limit.map(|limit| Self {
+            limit,
+            used: AtomicU64::new(0),
+        })
+    }
+
+    // Claim the bytes of a file before reading it. Once a file no longer fits, the budget stays
+    // exhausted, so that every later file is left out as well.
+    fn reserve(&self, bytes: u64) -> bool {
+        self.used
+            .fetch_add(bytes, Ordering::Relaxed)
+            .saturating_add(bytes)
+            <= self.limit
+    }
+}
+
+// Decoded content of a text file and its modification time in seconds since the epoch
+struct TextFile {
+    content: String,
+    modified: u64,
+    // Bytes read before decoding
+    size: u64,
+}
+
+// Modification time in seconds since the Unix epoch as stored in the index, 0 when unknown
+fn modified_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_secs())
+}
+
+// Read and classify a single file, decoding its content when it is indexable text
+fn load_file(
+    path: &Path,
+    options: &IndexOptions,
+    budget: Option<&ByteBudget>,
+    retry: RetryPolicy,
+) -> FileOutcome<TextFile> {
+    // Check the size before reading so huge files never get loaded into memory. When only the
+    // head of the file is indexed, the limit applies to the bytes actually read.
+    let metadata = match with_retries(retry, path, || fs::metadata(path)) {
+        Ok(metadata) => metadata,
+        Err(failure) => return failure.into(),
+    };
+    let read_size = options
+        .content_head_bytes
+        .map_or(metadata.len(), |head_bytes| {
+            metadata.len().min(head_bytes as u64)
+        });
+    if read_size > options.max_file_size_bytes {
+        return FileOutcome::TooLarge(metadata.len());
+    }
+    let modified = modified_secs(&metadata);
+
+    // More universal text file determination
+    if !is_text_file(path, options) {
+        return FileOutcome::NonText;
+    }
+    if let Some(budget) = budget
+        && !budget.reserve(read_size)
+    {
+        return FileOutcome::OverByteLimit;
+    }
+
+    match with_retries(retry, path, || read_head(path, options.content_head_bytes))
+        .map(|bytes| encoding::decode(bytes, fallback_encoding(options)))
+    {
+        Ok(content) if content.trim().is_empty() => FileOutcome::Empty,
+        Ok(content) => FileOutcome::Indexed(TextFile {
+            content,
+            modified,
+            size: metadata.len(),
+        }),
+        Err(failure) => failure.into(),
+    }
+}
+
+// Extract the text of a PDF document to be indexed like a text file. Documents whose text
+// can't be extracted are skipped and counted on their own, and those without any text, such as
+// scanned pages, as empty.
+fn load_pdf(
+    path: &Path,
+    options: &IndexOptions,
+    budget: Option<&ByteBudget>,
+    retry: RetryPolicy,
+) -> FileOutcome<TextFile> {
+    let metadata = match with_retries(retry, path, || fs::metadata(path)) {
+        Ok(metadata) => metadata,
+        Err(failure) => return failure.into(),
+    };
+    if metadata.len() > options.max_file_size_bytes {
+        return FileOutcome::TooLarge(metadata.len());
+    }
+    if let Some(budget) = budget
+        && !budget.reserve(metadata.len())
+    {
+        return FileOutcome::OverByteLimit;
+    }
+    match pdf::extract_text(path) {
+        Ok(content) if content.trim().is_empty() => FileOutcome::Empty,
+        Ok(mut content) => FileOutcome::Indexed(TextFile {
+            content: {
+                truncate_text(&mut content, options.content_head_bytes);
+                content
+            },
+            modified: modified_secs(&metadata),
+            size: metadata.len(),
+        }),
+        Err(e) => FileOutcome::ExtractionFailed(e),
+    }
+}
+
+// Encoding assumed for content whose encoding can't be detected confidently
+fn fallback_encoding(options: &IndexOptions) -> Option<&'static encoding_rs::Encoding> {
+    // The label was validated when the options were built
+    options
+        .fallback_encoding
+        .as_deref()
+        .and_then(|label| encoding_for_label(label).ok())
+}
+
+// Read the text entries of an archive, each indexed under its virtual path inside the archive.
+// Entries are held to the same size limit and text detection as files, and the archive counts
+// as non-text when it contains no text at all.
+fn load_archive(
+    path: &Path,
+    options: &IndexOptions,
+    budget: Option<&ByteBudget>,
+    retry: RetryPolicy,
+) -> FileOutcome<Vec<(String, TextFile)>> {
+    let metadata = match with_retries(retry, path, || fs::metadata(path)) {
+        Ok(metadata) => metadata,
+        Err(failure) => return failure.into(),
+    };
+    let modified = modified_secs(&metadata);
+    let entries = match archives::read_entries(path, options.max_file_size_bytes) {
+        Ok(entries) => entries,
+        Err(e) => return FileOutcome::ReadError(e),
+    };
+
+    let mut texts = Vec::new();
+    for ArchiveEntry { name, mut bytes } in entries {
+        if has_binary_extension(&name, &options.binary_extensions)
+            || !is_text_content(&bytes, options.text_detection)
+        {
+            continue;
+        }
+        let size = bytes.len() as u64;
+        truncate_head(&mut bytes, options.content_head_bytes);
+        // Entries that no longer fit are left out, like files
+        if let Some(budget) = budget
+            && !budget.reserve(bytes.len() as u64)
+        {
+            if texts.is_empty() {
+                return FileOutcome::OverByteLimit;
+            }
+            break;
+        }
+        let content = encoding::decode(bytes, fallback_encoding(options));
+        if !content.trim().is_empty() {
+            texts.push((
+                name,
+                TextFile {
+                    content,
+                    modified,
+                    size,
+                },
+            ));
+        }
+    }
+    if texts.is_empty() {
+        FileOutcome::NonText
+    } else {
+        FileOutcome::Indexed(texts)
+    }
+}
+
+// Decompress a gzip file and read it like a text file, to be indexed under the virtual path of
+// its only entry. The size limit applies to the decompressed content, which stops being read
+// as soon as it goes beyond the limit.
+fn load_gzip(
+    path: &Path,
+    options: &IndexOptions,
+    budget: Option<&ByteBudget>,
+    retry: RetryPolicy,
+) -> FileOutcome<(String, TextFile)> {
+    let metadata = match with_retries(retry, path, || fs::metadata(path)) {
+        Ok(metadata) => metadata,
+        Err(failure) => return failure.into(),
+    };
+    let modified = modified_secs(&metadata);
+    let mut bytes = match archives::read_gzip(path, options.max_file_size_bytes) {
+        Ok(Some(bytes)) => bytes,
+        // Only known to be larger than the limit
+        Ok(None) => return FileOutcome::TooLarge(options.max_file_size_bytes.saturating_add(1)),
+        Err(e) => return FileOutcome::ReadError(e),
+    };
+
+    let name = archives::gzip_entry_name(path);
+    if has_binary_extension(&name, &options.binary_extensions)
+        || !is_text_content(&bytes, options.text_detection)
+    {
+        return FileOutcome::NonText;
+    }
+    let size = bytes.len() as u64;
+    truncate_head(&mut bytes, options.content_head_bytes);
+    if let Some(budget) = budget
+        && !budget.reserve(bytes.len() as u64)
+    {
+        return FileOutcome::OverByteLimit;
+    }
+    let content = encoding::decode(bytes, fallback_encoding(options));
+    if content.trim().is_empty() {
+        return FileOutcome::Empty;
+    }
+    FileOutcome::Indexed((
+        name,
+        TextFile {
+            content,
+            modified,
+            size,
+        },
+    ))
+}
+
+// Read and classify a single file, without keeping the content
+fn classify_file(
+    path: &Path,
+    options: &IndexOptions,
+    budget: Option<&ByteBudget>,
+    retry: RetryPolicy,
+) -> FileKind {
+    if options.index_archives && archives::is_archive(path) {
+        load_archive(path, options, budget, retry).kind()
+    } else if options.index_gzip && archives::is_gzip(path) {
+        load_gzip(path, options, budget, retry).kind()
+    } else if options.index_pdf && pdf::is_pdf(path) {
+        load_pdf(path, options, budget, retry).kind()
+    } else {
+        load_file(path, options, budget, retry).kind()
+    }
+}
+
+// Read and classify a single file, building its documents when it is indexable text. An
+// archive yields a document for each of its text entries, a gzip file one for its content, a
+// PDF document one for its extracted text, and a JSON lines file in JSON lines mode one for
+// each record.
+fn read_file(
+    path: &Path,
+    fields: IndexFields,
+    options: &IndexOptions,
+    budget: Option<&ByteBudget>,
+    retry: RetryPolicy,
+) -> FileOutcome<Vec<TantivyDocument>> {
+    let text_document = |path: String, file_name: String, text: TextFile| {
+        // Recall and phonetic mode index the content again in fields of their own
+        let copies: Vec<(Field, String)> = [fields.content_exact, fields.content_phonetic]
+            .into_iter()
+            .flatten()
+            .map(|field| (field, text.content.clone()))
+            .collect();
+        let mut document = doc!(
+            fields.path => path,
+            fields.extension => file_extension(&file_name),
+            fields.filename => file_name,
+            fields.content => text.content,
+            fields.modified => text.modified,
+            fields.size => text.size,
+        );
+        for (field, content) in copies {
+            document.add_text(field, content);
+        }
+        document
+    };
+    if options.index_archives && archives::is_archive(path) {
+        return match load_archive(path, options, budget, retry) {
+            FileOutcome::Indexed(texts) => FileOutcome::Indexed(
+                texts
+                    .into_iter()
+                    .map(|(name, text)| {
+                        let file_name = Path::new(&name)
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        text_document(archives::entry_path(path, &name), file_name, text)
+                    })
+                    .collect(),
+            ),
+            FileOutcome::TooLarge(size) => FileOutcome::TooLarge(size),
+            FileOutcome::NonText => FileOutcome::NonText,
+            FileOutcome::Empty => FileOutcome::Empty,
+            FileOutcome::ReadError(e) => FileOutcome::ReadError(e),
+            FileOutcome::Unavailable(e) => FileOutcome::Unavailable(e),
+            FileOutcome::ExtractionFailed(e) => FileOutcome::ExtractionFailed(e),
+            FileOutcome::OverByteLimit => FileOutcome::OverByteLimit,
+        };
+    }
+    if options.index_gzip && archives::is_gzip(path) {
+        return match load_gzip(path, options, budget, retry) {
+            FileOutcome::Indexed((name, text)) => FileOutcome::Indexed(vec![text_document(
+                archives::entry_path(path, &name),
+                name,
+                text,
+            )]),
+            FileOutcome::TooLarge(size) => FileOutcome::TooLarge(size),
+            FileOutcome::NonText => FileOutcome::NonText,
+            FileOutcome::Empty => FileOutcome::Empty,
+            FileOutcome::ReadError(e) => FileOutcome::ReadError(e),
+            FileOutcome::Unavailable(e) => FileOutcome::Unavailable(e),
+            FileOutcome::ExtractionFailed(e) => FileOutcome::ExtractionFailed(e),
+            FileOutcome::OverByteLimit => FileOutcome::OverByteLimit,
+        };
+    }
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let outcome = if options.index_pdf && pdf::is_pdf(path) {
+        load_pdf(path, options, budget, retry)
+    } else {
+        load_file(path, options, budget, retry)
+    };
+    match outcome {
+        FileOutcome::Indexed(text) if options.jsonl_mode && json_lines::is_json_lines(path) => {
+            FileOutcome::Indexed(
+                json_lines::records(&text.content)
+                    .map(|(line_number, record)| {
+                        text_document(
+                            json_lines::record_path(path, line_number),
+                            file_name.clone(),
+                            TextFile {
+                                content: record.to_string(),
+                                modified: text.modified,
+                                size: record.len() as u64,
+                            },
+                        )
+                    })
+                    .collect(),
+            )
+        }
+        FileOutcome::Indexed(text) => FileOutcome::Indexed(vec![text_document(
+            path.to_string_lossy().to_string(),
+            file_name,
+            text,
+        )]),
+        FileOutcome::TooLarge(size) => FileOutcome::TooLarge(size),
+        FileOutcome::NonText => FileOutcome::NonText,
+        FileOutcome::Empty => FileOutcome::Empty,
+        FileOutcome::ReadError(e) => FileOutcome::ReadError(e),
+        FileOutcome::Unavailable(e) => FileOutcome::Unavailable(e),
+        FileOutcome::ExtractionFailed(e) => FileOutcome::ExtractionFailed(e),
+        FileOutcome::OverByteLimit => FileOutcome::OverByteLimit,
+    }
+}
+
+// Build the thread pool reading files; 0 lets rayon pick the number of threads from the CPUs
+fn reader_pool(writer_options: &WriterOptions) -> Result<rayon::ThreadPool, String> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(writer_options.threads.unwrap_or(0))
+        .build()
+        .map_err(|e| format!("Thread pool error: {}", e))
+}
+
+// Classify the collected files in parallel without indexing them, as a dry run
+pub fn classify_files(
+    files: &[PathBuf],
+    options: &IndexOptions,
+    writer_options: &WriterOptions,
+) -> Result<Vec<(PathBuf, FileKind)>, String> {
+    let pool = reader_pool(writer_options)?;
+    let budget = ByteBudget::new(writer_options.max_total_bytes);
+    pool.install(|| {
+        files
+            .par_iter()
+            .map(|path| {
+                if cancelled(writer_options.cancel.as_ref()) {
+                    return Err("Classifying files was cancelled".to_string());
+                }
+                let kind = classify_file(path, options, budget.as_ref(), writer_options.read_retry);
+                Ok((path.clone(), kind))
+            })
+            .collect()
+    })
+}
+
+// Hash the content of a file that would be indexed as text, with its size in bytes. Files
+// that would be skipped, including empty ones, have no hash.
+fn hash_file(
+    path: &Path,
+    options: &IndexOptions,
+    retry: RetryPolicy,
+) -> Option<(u64, blake3::Hash)> {
+    let metadata = with_retries(retry, path, || fs::metadata(path)).ok()?;
+    if metadata.len() > options.max_file_size_bytes || !is_text_file(path, options) {
+        return None;
+    }
+    match with_retries(retry, path, || fs::read(path)) {
+        Ok(bytes) if bytes.iter().all(u8::is_ascii_whitespace) => None,
+        Ok(bytes) => Some((bytes.len() as u64, blake3::hash(&bytes))),
+        Err(ReadFailure::Permanent(e) | ReadFailure::Exhausted(e)) => {
+            tracing::debug!("Skipped (read error): {} - {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// Hash the raw content of the text files among the collected files in parallel, to find files
+// with identical content. Archives are hashed as the files they are, not by their entries.
+pub fn hash_files(
+    files: &[PathBuf],
+    options: &IndexOptions,
+    writer_options: &WriterOptions,
+) -> Result<Vec<(PathBuf, u64, blake3::Hash)>, String> {
+    let pool = reader_pool(writer_options)?;
+    Ok(pool.install(|| {
+        files
+            .par_iter()
+            .filter_map(|path| {
+                hash_file(path, options, writer_options.read_retry)
+                    .map(|(bytes, hash)| (path.clone(), bytes, hash))
+            })
+            .collect()
+    }))
+}
+
+// Read the text files among the collected files in parallel and add them to the index,
+// reporting how each file was handled
+pub fn index_files(
+    files: &[PathBuf],
+    index_writer: &IndexWriter,
+    fields: IndexFields,
+    options: &IndexOptions,
+    writer_options: &WriterOptions,
+) -> Result<Vec<(PathBuf, FileKind)>, String> {
+    let pool = reader_pool(writer_options)?;
+
+    // Files are read on the pool while this thread feeds the writer;
+    // the bounded channel keeps the number of documents held in memory small
+    let (sender, receiver) = mpsc::sync_channel(DOCUMENT_CHANNEL_CAPACITY);
+    let deadline = writer_options.deadline;
+    let cancel = writer_options.cancel.as_ref();
+    let retry = writer_options.read_retry;
+    let budget = ByteBudget::new(writer_options.max_total_bytes);
+    let budget = budget.as_ref();
+
+    thread::scope(|scope| -> Result<Vec<(PathBuf, FileKind)>, String> {
+        scope.spawn(move || {
+            pool.install(|| {
+                // Sending fails once the receiver is gone, which stops the remaining reads,
+                // and files not yet read when the deadline passes, the request is cancelled or
+                // the server shuts down are left out
+                let _ = files.par_iter().try_for_each_with(sender, |sender, path| {
+                    if shutdown_requested()
+                        || cancelled(cancel)
+                        || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+                    {
+                        return Err(());
+                    }
+                    sender
+                        .send((path, read_file(path, fields, options, budget, retry)))
+                        .map_err(|_| ())
+                });
+            })
+        });
+
+        let mut kinds = Vec::with_capacity(files.len());
+        for (path, outcome) in receiver {
+            let kind = match outcome {
+                FileOutcome::Indexed(documents) => {
+                    for document in documents {
+                        index_writer
+                            .add_document(document)
+                            .map_err(|e| format!("Document addition error: {}", e))?;
+                    }
+                    tracing::debug!("Indexed: {}", path.display());
+                    FileKind::Indexed
+                }
+                FileOutcome::TooLarge(size) => {
+                    tracing::debug!("Skipped (too large, {} bytes): {}", size, path.display());
+                    FileKind::TooLarge
+                }
+                FileOutcome::NonText => {
+                    tracing::debug!("Skipped (non-text): {}", path.display());
+                    FileKind::Binary
+                }
+                FileOutcome::Empty => {
+                    tracing::debug!("Skipped (empty file): {}", path.display());
+                    FileKind::Empty
+                }
+                FileOutcome::ReadError(e) => {
+                    // Skip and continue on read errors
+                    tracing::debug!("Skipped (read error): {} - {}", path.display(), e);
+                    FileKind::ReadError
+                }
+                FileOutcome::Unavailable(e) => {
+                    // Unlike permanent read errors, these may deserve the user's attention
+                    tracing::warn!(
+                        "Skipped (unavailable after retries): {} - {}",
+                        path.display(),
+                        e
+                    );
+                    FileKind::Unavailable
+                }
+                FileOutcome::ExtractionFailed(e) => {
+                    tracing::debug!(
+                        "Skipped (text extraction failed): {} - {}",
+                        path.display(),
+                        e
+                    );
+                    FileKind::ExtractionFailed
+                }
+                FileOutcome::OverByteLimit => {
+                    tracing::debug!("Skipped (total byte limit reached): {}", path.display());
+                    FileKind::OverByteLimit
+                }
+            };
+            kinds.push((path.clone(), kind));
+
+            if let Some(progress) = &writer_options.progress
+                && (kinds.len() % PROGRESS_INTERVAL_FILES == 0 || kinds.len() == files.len())
+            {
+                progress(kinds.len(), files.len());
+            }
+        }
+        Ok(kinds)
+    })
+}
+
+// Create a writer for the index with the configured buffer size
+pub fn create_writer(index: &Index, writer_options: &WriterOptions) -> Result<IndexWriter, String> {
+    let buffer_bytes = writer_options
+        .buffer_bytes
+        .unwrap_or(DEFAULT_WRITER_BUFFER_BYTES);
+    if buffer_bytes < MIN_WRITER_BUFFER_BYTES {
+        return Err(format!(
+            "Writer buffer of {} bytes is too small; Tantivy requires at least {} bytes.",
+            buffer_bytes, MIN_WRITER_BUFFER_BYTES
+        ));
+    }
+    index
+        .writer(buffer_bytes)
+        .map_err(|e| format!("Index writer error: {}", e))
+}
+
+// Index the collected files into the given index and commit, returning the records of the files
+pub fn write_index(
+    index: &Index,
+    files: &[PathBuf],
+    fields: IndexFields,
+    options: &IndexOptions,
+    writer_options: &WriterOptions,
+) -> Result<FileRecords, String> {
+    let mut index_writer = create_writer(index, writer_options)?;
+
+    let plan = UpdatePlan::new(files, &FileRecords::new(), true);
+    let kinds = index_files(
+        &plan.changed,
+        &index_writer,
+        fields,
+        options,
+        writer_options,
+    )?;
+
+    index_writer
+        .commit()
+        .map_err(|e| format!("Commit error: {}", e))?;
+    Ok(plan.finish(kinds))
+}
+
+// Delete the documents of a file, including those of the entries when it is an archive or a
+// gzip file, and those of the records when it is a JSON lines file
+pub fn delete_path(
+    index_writer: &IndexWriter,
+    fields: IndexFields,
+    path: &str,
+) -> Result<(), String> {
+    index_writer.delete_term(Term::from_field_text(fields.path, path));
+    let entries = if archives::has_entries(Path::new(path)) {
+        Some(format!(
+            "{}{}.*",
+            regex::escape(path),
+            regex::escape(archives::ENTRY_SEPARATOR)
+        ))
+    } else if json_lines::is_json_lines(Path::new(path)) {
+        Some(format!(
+            "{}{}[0-9]+",
+            regex::escape(path),
+            regex::escape(&json_lines::RECORD_SEPARATOR.to_string())
+        ))
+    } else {
+        None
+    };
+    if let Some(entries) = entries {
+        let query = RegexQuery::from_pattern(&entries, fields.path)
+            .map_err(|e| format!("Query error: {}", e))?;
+        index_writer
+            .delete_query(Box::new(query))
+            .map_err(|e| format!("Document deletion error: {}", e))?;
+    }
+    Ok(())
+}
+
+// How an index was brought up to date with its directories
+#[derive(Debug)]
+pub struct IndexUpdate {
+    // Records of the collected files after the update
+    pub records: FileRecords,
+    // Changed and new files that were read again
+    pub read_files: usize,
+    // Documents of changed and removed files that were deleted
+    pub removed_documents: usize,
+}
+
+// Bring an index up to date with the files collected now, given the records of the files when
+// it was last built or updated and a searcher over its current documents. Documents of removed
+// files and of files whose modification time or size changed are deleted, and changed and new
+// files are read again; unchanged files, including skipped ones, are neither read nor indexed.
+pub fn update_index(
+    index: &Index,
+    searcher: &Searcher,
+    files: &[PathBuf],
+    previous: &FileRecords,
+    fields: IndexFields,
+    options: &IndexOptions,
+    writer_options: &WriterOptions,
+) -> Result<IndexUpdate, String> {
+    let plan = UpdatePlan::new(files, previous, true);
+
+    // Entries of an archive and records of a JSON lines file are counted by the file they
+    // belong to
+    let mut documents: HashMap<String, usize> = HashMap::new();
+    for path in indexed_modification_times(searcher)?.into_keys() {
+        let path = json_lines::container_path(&path);
+        *documents
+            .entry(archives::container_path(path).to_string())
+            .or_default() += 1;
+    }
+    let removed_documents = plan
+        .stale
+        .iter()
+        .map(|path| documents.get(path).copied().unwrap_or(0))
+        .sum();
+
+    let mut index_writer = create_writer(index, writer_options)?;
+    for path in &plan.stale {
+        delete_path(&index_writer, fields, path)?;
+    }
+    let kinds = index_files(
+        &plan.changed,
+        &index_writer,
+        fields,
+        options,
+        writer_options,
+    )?;
+    index_writer
+        .commit()
+        .map_err(|e| format!("Commit error: {}", e))?;
+
+    let read_files = plan.changed.len();
+    let records = plan.finish(kinds);
+    Ok(IndexUpdate {
+        records,
+        read_files,
+        removed_documents,
+    })
+}
+
+// Modification times of the live documents of an index, keyed by path. Unlike the term
+// dictionary, the fast fields skip documents that were deleted but not merged away yet.
+pub fn indexed_modification_times(searcher: &Searcher) -> Result<HashMap<String, u64>, String> {
+    let mut times = HashMap::new();
+    for segment_reader in searcher.segment_readers() {
+        let fast_fields = segment_reader.fast_fields();
+        let Some(paths) = fast_fields
+            .str(PATH_FIELD)
+            .map_err(|e| format!("Index read error: {}", e))?
+        else {
+            continue;
+        };
+        let modified = fast_fields
+            .u64(MODIFIED_FIELD)
+            .map_err(|e| format!("Index read error: {}", e))?;
+        for doc in segment_reader.doc_ids_alive() {
+            let Some(ord) = paths.term_ords(doc).next() else {
+                continue;
+            };
+            let mut path = String::new();
+            paths
+                .ord_to_str(ord, &mut path)
+                .map_err(|e| format!("Index read error: {}", e))?;
+            times.insert(path, modified.first(doc).unwrap_or(0));
+        }
+    }
+    Ok(times)
+}
+
+// Paths of all live documents in an index, sorted
+pub fn indexed_paths(searcher: &Searcher) -> Result<Vec<String>, String> {
+    let mut paths: Vec<String> = indexed_modification_times(searcher)?.into_keys().collect();
+    paths.sort();
+    Ok(paths)
+}
+
+// Read the stored content of the document indexed under the given path
+pub fn stored_content(
+    searcher: &Searcher,
+    fields: IndexFields,
+    path: &str,
+) -> Result<Option<String>, String> {
+    let query = TermQuery::new(
+        Term::from_field_text(fields.path, path),
+        IndexRecordOption::Basic,
+    );
+    let top_docs = searcher
+        .search(&query, &TopDocs::with_limit(1))
+        .map_err(|e| format!("Search error: {}", e))?;
+    let Some((_, doc_address)) = top_docs.first() else {
+        return Ok(None);
+    };
+
+    let document: TantivyDocument = searcher.doc(*doc_address).map_err(|e| e.to_string())?;
+    Ok(document
+        .get_first(fields.content)
+        .and_then(|v| v.as_str())
+        .map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_text(bytes: &[u8]) -> bool {
+        is_text_content(bytes, TextDetection::default())
+    }
+
+    #[test]
+    fn ansi_colored_logs_are_text() {
+        let line = "\x1b[1;31mERROR\x1b[0m \x1b[2m12:00:01\x1b[0m \x1b[33mretrying\x1b[0m\n";
+        assert!(is_text(line.repeat(200).as_bytes()));
+        // Escapes around single characters, as in progress bars
+        assert!(is_text("\x1b[32m#\x1b[0m".repeat(500).as_bytes()));
+    }
+
+    #[test]
+    fn form_feeds_are_text() {
+        assert!(is_text("page\x0c".repeat(1000).as_bytes()));
+    }
+
+    #[test]
+    fn null_bytes_and_control_heavy_content_are_binary() {
+        assert!(!is_text(b"text\0with a null byte"));
+        assert!(!is_text(&[0x01, 0x02, 0x03, b'a'].repeat(100)));
+        assert!(!is_text(b""));
+    }
+
+    #[test]
+    fn control_ratio_threshold_is_configurable() {
+        // 40% control characters
+        let bytes = [0x01, 0x02, b'a', b'b', b'c'].repeat(100);
+        assert!(!is_text(&bytes));
+        let lenient = TextDetection {
+            max_control_ratio: 0.5,
+            ..TextDetection::default()
+        };
+        assert!(is_text_content(&bytes, lenient));
+    }
+
+    #[test]
+    fn ascii_ratio_threshold_is_configurable() {
+        // Not valid UTF-8 and 75% ASCII
+        let bytes = [b'a', b'b', b'c', 0xff].repeat(100);
+        let lenient = TextDetection {
+            min_ascii_ratio: 0.5,
+            ..TextDetection::default()
+        };
+        assert!(is_text_content(&bytes, lenient));
+    }
+
+    #[test]
+    fn transient_errors_are_retried_and_permanent_ones_are_not() {
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::ZERO,
+        };
+        let path = Path::new("flaky.txt");
+
+        let mut attempts = 0;
+        let result = with_retries(policy, path, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::Error::from(ErrorKind::TimedOut))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert!(matches!(result, Ok(3)));
+
+        let mut attempts = 0;
+        let result = with_retries(policy, path, || -> io::Result<()> {
+            attempts += 1;
+            Err(io::Error::from(ErrorKind::WouldBlock))
+        });
+        assert!(matches!(result, Err(ReadFailure::Exhausted(_))));
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result = with_retries(policy, path, || -> io::Result<()> {
+            attempts += 1;
+            Err(io::Error::from(ErrorKind::PermissionDenied))
+        });
+        assert!(matches!(result, Err(ReadFailure::Permanent(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn cancelled_walks_stop_before_collecting_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "notes").unwrap();
+        let cancel = CancellationToken::new();
+        let options = WalkOptions {
+            cancel: Some(cancel.clone()),
+            ..WalkOptions::default()
+        };
+        assert_eq!(
+            process_directory(dir.path(), &options).unwrap().files.len(),
+            1
+        );
+
+        cancel.cancel();
+        let collected = process_directory(dir.path(), &options).unwrap();
+        assert!(collected.files.is_empty());
+        assert!(collected.timed_out);
+    }
+}
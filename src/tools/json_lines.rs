@@ -0,0 +1,71 @@
+use std::path::Path;
+
+// Separates the path of a JSON lines file from the number of a record in it, as in
+// "events.jsonl#42"
+pub const RECORD_SEPARATOR: char = '#';
+
+// Whether the file holds one JSON record per line, by its .jsonl or .ndjson extension
+pub fn is_json_lines(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        let extension = extension.to_string_lossy().to_lowercase();
+        extension == "jsonl" || extension == "ndjson"
+    })
+}
+
+// Path under which a record of a JSON lines file is indexed, by its line number starting at 1
+pub fn record_path(path: &Path, line_number: usize) -> String {
+    format!(
+        "{}{}{}",
+        path.to_string_lossy(),
+        RECORD_SEPARATOR,
+        line_number
+    )
+}
+
+// Split an indexed path into the JSON lines file and the line number of the record, or None
+// when the path is not that of a record
+pub fn split_record_path(path: &str) -> Option<(&str, usize)> {
+    let (file, line_number) = path.rsplit_once(RECORD_SEPARATOR)?;
+    if !is_json_lines(Path::new(file)) || !line_number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((file, line_number.parse().ok()?))
+}
+
+// The JSON lines file containing an indexed record, or the path itself when it is not a record
+pub fn container_path(path: &str) -> &str {
+    split_record_path(path).map_or(path, |(file, _)| file)
+}
+
+// The records of a JSON lines file with their line numbers; blank lines are left out
+pub fn records(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_record_paths() {
+        let path = record_path(Path::new("/data/events.jsonl"), 42);
+        assert_eq!(path, "/data/events.jsonl#42");
+        assert_eq!(split_record_path(&path), Some(("/data/events.jsonl", 42)));
+        assert_eq!(container_path(&path), "/data/events.jsonl");
+        assert_eq!(container_path("/notes/todo.md#3"), "/notes/todo.md#3");
+        assert_eq!(
+            container_path("/data/events.jsonl#x"),
+            "/data/events.jsonl#x"
+        );
+    }
+
+    #[test]
+    fn numbers_non_blank_lines() {
+        let records: Vec<_> = records("{\"a\":1}\n\n{\"a\":2}\r\n").collect();
+        assert_eq!(records, vec![(1, "{\"a\":1}"), (3, "{\"a\":2}")]);
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Range;
+use tantivy::query::Query;
+use tantivy::schema::Field;
+use tantivy::tokenizer::{TextAnalyzer, TokenStream};
+
+// Collect the texts of the query terms that target the given field
+pub fn query_terms(query: &dyn Query, field: Field) -> HashSet<String> {
+    let mut terms = HashSet::new();
+    query.query_terms(&mut |term, _| {
+        if term.field() == field
+            && let Some(text) = term.value().as_str()
+        {
+            terms.insert(text.to_string());
+        }
+    });
+    terms
+}
+
+// Check whether a piece of text contains any of the terms once analyzed like the field
+pub fn contains_any_term(analyzer: &mut TextAnalyzer, text: &str, terms: &HashSet<String>) -> bool {
+    let mut stream = analyzer.token_stream(text);
+    while stream.advance() {
+        if terms.contains(&stream.token().text) {
+            return true;
+        }
+    }
+    false
+}
+
+// Number of occurrences of any of the terms in a piece of text once analyzed like the field
+pub fn count_matches(analyzer: &mut TextAnalyzer, text: &str, terms: &HashSet<String>) -> usize {
+    if terms.is_empty() {
+        return 0;
+    }
+    let mut count = 0;
+    let mut stream = analyzer.token_stream(text);
+    while stream.advance() {
+        if terms.contains(&stream.token().text) {
+            count += 1;
+        }
+    }
+    count
+}
+
+// The terms that occur in a piece of text once analyzed like the field, in sorted order
+pub fn matched_terms(
+    analyzer: &mut TextAnalyzer,
+    text: &str,
+    terms: &HashSet<String>,
+) -> BTreeSet<String> {
+    let mut matched = BTreeSet::new();
+    let mut stream = analyzer.token_stream(text);
+    while matched.len() < terms.len() && stream.advance() {
+        if terms.contains(&stream.token().text) {
+            matched.insert(stream.token().text.clone());
+        }
+    }
+    matched
+}
+
+// Line numbers (1-based) of the lines containing any of the terms
+pub fn matching_lines(
+    analyzer: &mut TextAnalyzer,
+    content: &str,
+    terms: &HashSet<String>,
+    max_lines: usize,
+) -> Vec<usize> {
+    if terms.is_empty() {
+        return Vec::new();
+    }
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| contains_any_term(analyzer, line, terms))
+        .map(|(index, _)| index + 1)
+        .take(max_lines)
+        .collect()
+}
+
+// Marks where a long line was cut
+const ELLIPSIS: &str = "…";
+
+// How the lines around matches are extracted
+#[derive(Debug, Clone, Copy)]
+pub struct LineOptions {
+    // Lines of context before and after each matching line, when requested
+    pub context: Option<usize>,
+    // Longer lines, as in minified or single-line files, are clipped to this many characters
+    pub max_chars: usize,
+}
+
+// Clip a line to at most `max_chars` characters around its first highlighted range, marking
+// the cuts with ellipses. The ranges are moved to the clipped text, and those cut off dropped.
+pub fn clip_line(
+    line: &str,
+    ranges: &[Range<usize>],
+    max_chars: usize,
+) -> (String, Vec<Range<usize>>) {
+    if line.char_indices().nth(max_chars).is_none() {
+        return (line.to_string(), ranges.to_vec());
+    }
+    // Keep a quarter of the window before the first match as its context
+    let focus = ranges.first().map_or(0, |range| range.start);
+    let start = line[..focus]
+        .char_indices()
+        .rev()
+        .take(max_chars / 4)
+        .last()
+        .map_or(focus, |(i, _)| i);
+    let end = line[start..]
+        .char_indices()
+        .nth(max_chars)
+        .map_or(line.len(), |(i, _)| start + i);
+
+    let prefix = if start > 0 { ELLIPSIS } else { "" };
+    let suffix = if end < line.len() { ELLIPSIS } else { "" };
+    let shifted = ranges
+        .iter()
+        .filter(|range| range.start >= start && range.end <= end)
+        .map(|range| range.start - start + prefix.len()..range.end - start + prefix.len())
+        .collect();
+    (
+        format!("{}{}{}", prefix, &line[start..end], suffix),
+        shifted,
+    )
+}
+
+// A line of a context block, numbered from 1
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ContextLine {
+    pub line: usize,
+    pub text: String,
+    // Whether the line itself contains a query term
+    pub matched: bool,
+}
+
+// Blocks of the matching lines with up to `context` lines before and after each of them, like
+// grep -C. Blocks that overlap or touch are merged into one, and long lines are clipped.
+pub fn context_blocks(
+    content: &str,
+    matches: &[usize],
+    context: usize,
+    max_chars: usize,
+) -> Vec<Vec<ContextLine>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let matched: HashSet<usize> = matches.iter().copied().collect();
+    let mut blocks: Vec<Vec<ContextLine>> = Vec::new();
+    // Last line number already added to a block
+    let mut last_end = 0;
+    for &line in matches {
+        let start = line.saturating_sub(context).max(1);
+        let end = line.saturating_add(context).min(lines.len());
+        if blocks.is_empty() || start > last_end + 1 {
+            blocks.push(Vec::new());
+        }
+        if let Some(block) = blocks.last_mut() {
+            for number in start.max(last_end + 1)..=end {
+                block.push(ContextLine {
+                    line: number,
+                    text: clip_line(lines[number - 1], &[], max_chars).0,
+                    matched: matched.contains(&number),
+                });
+            }
+        }
+        last_end = last_end.max(end);
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clips_a_huge_single_line_around_the_match() {
+        let mut line = "a".repeat(5 * 1024 * 1024);
+        let needle = line.len() / 2;
+        line.replace_range(needle..needle + 6, "needle");
+
+        let (clipped, ranges) = clip_line(&line, &[needle..needle + 6], 200);
+        assert!(clipped.chars().count() <= 202);
+        assert!(clipped.starts_with(ELLIPSIS) && clipped.ends_with(ELLIPSIS));
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(&clipped[ranges[0].clone()], "needle");
+    }
+
+    #[test]
+    fn keeps_short_lines_whole() {
+        let (clipped, ranges) = clip_line("short line", &[6..10], 200);
+        assert_eq!(clipped, "short line");
+        assert_eq!(ranges, vec![6..10]);
+    }
+
+    #[test]
+    fn bounds_the_context_of_a_huge_single_line_file() {
+        let content = format!("{{\"key\": \"{}\"}}", "x".repeat(5 * 1024 * 1024));
+        let blocks = context_blocks(&content, &[1], 2, 500);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].len(), 1);
+        assert!(blocks[0][0].text.chars().count() <= 501);
+        assert!(blocks[0][0].matched);
+    }
+}
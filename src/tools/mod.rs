@@ -1 +1,17 @@
+pub mod archives;
+pub mod bm25;
+pub mod encoding;
+pub mod error;
+pub mod index_cache;
+pub mod index_stats;
+pub mod indexer;
+pub mod json_lines;
+pub mod matching;
+pub mod paths;
+pub mod pdf;
+pub mod prompts;
+pub mod query_syntax;
+pub mod replace_preview;
 pub mod search_tool;
+pub mod tokenizers;
+pub mod watcher;
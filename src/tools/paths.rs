@@ -0,0 +1,95 @@
+use std::env;
+
+// Home directory of the user running the server
+fn home_dir() -> Option<String> {
+    env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .ok()
+        .filter(|home| !home.is_empty())
+}
+
+// Expand a leading ~ to the home directory and $VAR or ${VAR} to the value of the environment
+// variable, as a shell would. ~user and a $ that doesn't start a variable name are kept as is.
+pub fn expand_path(path: &str) -> Result<String, String> {
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            let home = home_dir()
+                .ok_or_else(|| format!("Cannot expand '~' in '{}': no home directory", path))?;
+            format!("{}{}", home, rest)
+        }
+        _ => path.to_string(),
+    };
+
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path.as_str();
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remainder) = match after.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced
+                    .find('}')
+                    .ok_or_else(|| format!("Unterminated '${{' in '{}'", path))?;
+                (&braced[..end], &braced[end + 1..])
+            }
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let value = env::var(name).map_err(|_| {
+            format!(
+                "Cannot expand '{}': the environment variable '{}' is not set",
+                path, name
+            )
+        })?;
+        expanded.push_str(&value);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_the_home_directory_and_variables() {
+        let home = home_dir().unwrap();
+        assert_eq!(expand_path("~").unwrap(), home);
+        assert_eq!(
+            expand_path("~/Documents").unwrap(),
+            format!("{}/Documents", home)
+        );
+        assert_eq!(
+            expand_path("$HOME/project").unwrap(),
+            format!("{}/project", home)
+        );
+        assert_eq!(
+            expand_path("${HOME}_backup").unwrap(),
+            format!("{}_backup", home)
+        );
+    }
+
+    #[test]
+    fn keeps_text_that_is_not_expandable() {
+        assert_eq!(expand_path("~user/notes").unwrap(), "~user/notes");
+        assert_eq!(expand_path("/tmp/a~b").unwrap(), "/tmp/a~b");
+        assert_eq!(expand_path("/tmp/$/x").unwrap(), "/tmp/$/x");
+        assert_eq!(expand_path("/tmp/cost$").unwrap(), "/tmp/cost$");
+    }
+
+    #[test]
+    fn rejects_unset_variables_and_unterminated_braces() {
+        assert!(expand_path("$FILE_SEARCH_TEST_UNSET_VARIABLE/x").is_err());
+        assert!(expand_path("${HOME/x").is_err());
+    }
+}
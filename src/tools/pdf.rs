@@ -0,0 +1,24 @@
+use std::path::Path;
+
+// Whether this build can extract the text of PDF documents, as enabled by the pdf feature
+pub const SUPPORTED: bool = cfg!(feature = "pdf");
+
+// Whether the file is a PDF document, by its extension
+pub fn is_pdf(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.to_string_lossy().eq_ignore_ascii_case("pdf"))
+}
+
+// Extract the text of a PDF document. Malformed documents can make the extractor panic, which
+// is reported as an extraction error like any other.
+#[cfg(feature = "pdf")]
+pub fn extract_text(path: &Path) -> Result<String, String> {
+    std::panic::catch_unwind(|| pdf_extract::extract_text(path))
+        .map_err(|_| "PDF extraction error: the document could not be parsed".to_string())?
+        .map_err(|e| format!("PDF extraction error: {}", e))
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn extract_text(_path: &Path) -> Result<String, String> {
+    Err("PDF extraction error: this build has no PDF support, enable the pdf feature".to_string())
+}
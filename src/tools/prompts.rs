@@ -0,0 +1,107 @@
+use rmcp::Error as McpError;
+use rmcp::model::{
+    GetPromptResult, JsonObject, Prompt, PromptArgument, PromptMessage, PromptMessageRole,
+};
+
+const FIND_TODOS: &str = "find-todos";
+const SEARCH_IN_DIR: &str = "search-in-dir";
+
+// Describe an argument of a prompt template
+fn argument(name: &str, description: &str, required: bool) -> PromptArgument {
+    PromptArgument {
+        name: name.to_string(),
+        description: Some(description.to_string()),
+        required: Some(required),
+    }
+}
+
+// Read a string argument of a prompt request
+fn string_argument<'a>(arguments: Option<&'a JsonObject>, name: &str) -> Option<&'a str> {
+    arguments?
+        .get(name)?
+        .as_str()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+// Read a required string argument, reporting invalid params when it is missing
+fn required_argument<'a>(
+    arguments: Option<&'a JsonObject>,
+    prompt: &str,
+    name: &str,
+) -> Result<&'a str, McpError> {
+    string_argument(arguments, name).ok_or_else(|| {
+        McpError::invalid_params(
+            format!("Prompt '{}' requires the '{}' argument", prompt, name),
+            None,
+        )
+    })
+}
+
+// Prompt templates offered by the server
+pub fn list() -> Vec<Prompt> {
+    vec![
+        Prompt::new(
+            FIND_TODOS,
+            Some("Find TODO and FIXME comments in a directory"),
+            Some(vec![argument(
+                "directory",
+                "Path to the directory to search",
+                true,
+            )]),
+        ),
+        Prompt::new(
+            SEARCH_IN_DIR,
+            Some("Search a directory for a keyword and summarize the matching files"),
+            Some(vec![
+                argument("directory", "Path to the directory to search", true),
+                argument("keyword", "Keyword to search for", true),
+            ]),
+        ),
+    ]
+}
+
+// Expand a prompt template with the given arguments
+pub fn get(name: &str, arguments: Option<&JsonObject>) -> Result<GetPromptResult, McpError> {
+    let (description, text) = match name {
+        FIND_TODOS => {
+            let directory = required_argument(arguments, name, "directory")?;
+            (
+                "Find TODO and FIXME comments",
+                format!(
+                    "Use the search tool to find open work items in the directory '{}'. \
+                     Search for the keyword \"TODO FIXME\" with line_numbers set to true, \
+                     then list every match grouped by file with its line numbers and a one-line \
+                     summary of what remains to be done. Read a file with read_file_content \
+                     when the snippet is not enough to understand the comment.",
+                    directory
+                ),
+            )
+        }
+        SEARCH_IN_DIR => {
+            let directory = required_argument(arguments, name, "directory")?;
+            let keyword = required_argument(arguments, name, "keyword")?;
+            (
+                "Search a directory for a keyword",
+                format!(
+                    "Use the search tool to search the directory '{}' for the keyword '{}'. \
+                     Summarize the most relevant files, explaining for each one why it matches \
+                     and quoting the relevant snippet. Read the top hits with read_file_content \
+                     when more context is needed.",
+                    directory, keyword
+                ),
+            )
+        }
+        _ => {
+            return Err(McpError::invalid_params(
+                format!("Unknown prompt: {}", name),
+                None,
+            ));
+        }
+    };
+
+    Ok(GetPromptResult {
+        description: Some(description.to_string()),
+        messages: vec![PromptMessage::new_text(PromptMessageRole::User, text)],
+    })
+}
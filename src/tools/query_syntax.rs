@@ -0,0 +1,295 @@
+use rmcp::schemars;
+use tantivy::query::{BooleanQuery, BoostQuery, Occur, Query, RegexQuery};
+use tantivy::schema::Field;
+
+// How the search keyword is interpreted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryMode {
+    // Tantivy's query syntax with +/-, AND, OR, NOT, parentheses and quoted phrases
+    #[default]
+    Boolean,
+    // Every word is a literal term and no character has a special meaning
+    Simple,
+}
+
+// Characters with a special meaning in the regular expressions of Tantivy's term automata
+const REGEX_META_CHARACTERS: &str = "\\.+*?()|[]{}^$#&-~";
+
+// Words the query parser treats as operators
+const OPERATOR_WORDS: &[&str] = &["AND", "OR", "NOT", "IN", "TO"];
+
+// Structural tokens of a boolean query, as far as needed to validate it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyntaxToken {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Term,
+}
+
+// Split a boolean query into structural tokens with their 1-based character positions
+fn syntax_tokens(keyword: &str) -> Result<Vec<(SyntaxToken, usize)>, String> {
+    let chars: Vec<char> = keyword.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push((SyntaxToken::Open, i + 1));
+                i += 1;
+            }
+            ')' => {
+                tokens.push((SyntaxToken::Close, i + 1));
+                i += 1;
+            }
+            _ => {
+                // A term runs until whitespace or a parenthesis, except inside a quoted phrase
+                let start = i;
+                let mut quote_start = None;
+                while i < chars.len() {
+                    let c = chars[i];
+                    if quote_start.is_some() {
+                        if c == '\\' {
+                            i += 1;
+                        } else if c == '"' {
+                            quote_start = None;
+                        }
+                    } else if c == '"' {
+                        quote_start = Some(i);
+                    } else if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    i += 1;
+                }
+                if let Some(quote_start) = quote_start {
+                    return Err(format!("Unclosed quote at position {}", quote_start + 1));
+                }
+
+                let word: String = chars[start..i].iter().collect();
+                let token = match word.as_str() {
+                    "AND" => SyntaxToken::And,
+                    "OR" => SyntaxToken::Or,
+                    "NOT" => SyntaxToken::Not,
+                    // A bare +/- may only prefix a group
+                    "+" | "-" if chars.get(i) == Some(&'(') => continue,
+                    "+" | "-" => {
+                        return Err(format!(
+                            "Operator '{}' at position {} must be directly followed by a term",
+                            word,
+                            start + 1
+                        ));
+                    }
+                    _ => SyntaxToken::Term,
+                };
+                tokens.push((token, start + 1));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Check the structure of a boolean query, pointing at the offending token when it is malformed
+pub fn check_boolean_syntax(keyword: &str) -> Result<(), String> {
+    let tokens = syntax_tokens(keyword)?;
+    let mut open_groups = Vec::new();
+
+    for (i, &(token, position)) in tokens.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|i| tokens[i].0);
+        let next = tokens.get(i + 1).map(|&(token, _)| token);
+        match token {
+            SyntaxToken::And | SyntaxToken::Or => {
+                let name = if token == SyntaxToken::And {
+                    "AND"
+                } else {
+                    "OR"
+                };
+                let valid_previous =
+                    matches!(previous, Some(SyntaxToken::Term | SyntaxToken::Close));
+                let valid_next = matches!(
+                    next,
+                    Some(SyntaxToken::Term | SyntaxToken::Open | SyntaxToken::Not)
+                );
+                if !valid_previous || !valid_next {
+                    return Err(format!(
+                        "Operator '{}' at position {} needs a term on both sides",
+                        name, position
+                    ));
+                }
+            }
+            SyntaxToken::Not => {
+                if !matches!(next, Some(SyntaxToken::Term | SyntaxToken::Open)) {
+                    return Err(format!(
+                        "Operator 'NOT' at position {} must be followed by a term",
+                        position
+                    ));
+                }
+            }
+            SyntaxToken::Open => {
+                if next == Some(SyntaxToken::Close) {
+                    return Err(format!("Empty parentheses at position {}", position));
+                }
+                open_groups.push(position);
+            }
+            SyntaxToken::Close => {
+                if open_groups.pop().is_none() {
+                    return Err(format!("Unmatched ')' at position {}", position));
+                }
+            }
+            SyntaxToken::Term => {}
+        }
+    }
+
+    match open_groups.pop() {
+        Some(position) => Err(format!("Unclosed '(' at position {}", position)),
+        None => Ok(()),
+    }
+}
+
+// Rewrite a keyword so that the query parser reads every word as a literal term. Special
+// characters are dropped like the tokenizer drops punctuation, and operator words are quoted.
+pub fn literal_query(keyword: &str) -> String {
+    let words: String = keyword
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    words
+        .split_whitespace()
+        .map(|word| {
+            if OPERATOR_WORDS.contains(&word) {
+                format!("\"{}\"", word)
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Give every quoted phrase of a boolean query the slop, so that its words may be up to that many
+// positions apart. Phrases with their own slop, written as "..."~N, keep it.
+pub fn with_phrase_slop(keyword: &str, slop: u32) -> String {
+    let mut result = String::with_capacity(keyword.len());
+    let mut chars = keyword.chars().peekable();
+    let mut in_phrase = false;
+    while let Some(c) = chars.next() {
+        result.push(c);
+        if in_phrase && c == '\\' {
+            if let Some(escaped) = chars.next() {
+                result.push(escaped);
+            }
+        } else if c == '"' {
+            in_phrase = !in_phrase;
+            if !in_phrase && chars.peek() != Some(&'~') {
+                result.push_str(&format!("~{}", slop));
+            }
+        }
+    }
+    result
+}
+
+// A field searched by wildcard queries, and whether its terms are lowercased when indexed
+pub struct WildcardField {
+    pub field: Field,
+    pub lowercase: bool,
+    pub boost: f32,
+}
+
+// Translate a wildcard pattern into a regular expression over whole terms. '*' matches any
+// characters and '?' a single one, and every pattern is implicitly a prefix. A leading wildcard
+// is rejected because it has to be checked against every term of the index.
+fn wildcard_regex(pattern: &str, lowercase: bool) -> Result<String, String> {
+    let literal = pattern.trim_end_matches('*');
+    if literal.is_empty() || literal.starts_with(['*', '?']) {
+        return Err(format!(
+            "Leading wildcards are not supported: '{}'. Start the pattern with at least one literal character.",
+            pattern
+        ));
+    }
+    let pattern = literal;
+
+    let mut regex = String::with_capacity(pattern.len() + 2);
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c if REGEX_META_CHARACTERS.contains(c) => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            c if lowercase => regex.extend(c.to_lowercase()),
+            c => regex.push(c),
+        }
+    }
+    regex.push_str(".*");
+    Ok(regex)
+}
+
+// Build a query matching documents where any word of the keyword, read as a wildcard pattern,
+// matches a term of one of the fields. All matching terms score the same.
+pub fn wildcard_query(keyword: &str, fields: &[WildcardField]) -> Result<Box<dyn Query>, String> {
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+    for pattern in keyword.split_whitespace() {
+        for wildcard_field in fields {
+            let regex = wildcard_regex(pattern, wildcard_field.lowercase)?;
+            let query = RegexQuery::from_pattern(&regex, wildcard_field.field)
+                .map_err(|e| format!("Invalid wildcard pattern '{}': {}", pattern, e))?;
+            clauses.push((
+                Occur::Should,
+                Box::new(BoostQuery::new(Box::new(query), wildcard_field.boost)),
+            ));
+        }
+    }
+    Ok(Box::new(BooleanQuery::new(clauses)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_well_formed_boolean_queries() {
+        for keyword in [
+            "panic AND NOT test",
+            "timeout OR deadline",
+            "(timeout OR deadline) AND retry",
+            "+(error failure) -test",
+            "\"error (handling\" OR panic",
+        ] {
+            assert_eq!(check_boolean_syntax(keyword), Ok(()), "{}", keyword);
+        }
+    }
+
+    #[test]
+    fn reports_unbalanced_quotes_and_parentheses_with_their_position() {
+        let error = |keyword| check_boolean_syntax(keyword).unwrap_err();
+        assert_eq!(error("foo \"bar"), "Unclosed quote at position 5");
+        assert_eq!(error("(timeout OR deadline"), "Unclosed '(' at position 1");
+        assert_eq!(error("timeout)"), "Unmatched ')' at position 8");
+        assert_eq!(error("()"), "Empty parentheses at position 1");
+    }
+
+    #[test]
+    fn reports_operators_without_operands() {
+        let error = |keyword| check_boolean_syntax(keyword).unwrap_err();
+        assert_eq!(
+            error("panic AND"),
+            "Operator 'AND' at position 7 needs a term on both sides"
+        );
+        assert_eq!(
+            error("OR deadline"),
+            "Operator 'OR' at position 1 needs a term on both sides"
+        );
+        assert_eq!(
+            error("panic NOT"),
+            "Operator 'NOT' at position 7 must be followed by a term"
+        );
+        assert_eq!(
+            error("- panic"),
+            "Operator '-' at position 1 must be directly followed by a term"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use crate::tools::indexer::IndexFields;
+use regex::Regex;
+use tantivy::collector::TopDocs;
+use tantivy::query::AllQuery;
+use tantivy::schema::Value;
+use tantivy::{Searcher, TantivyDocument};
+
+// Maximum number of changed lines shown per file
+const MAX_PREVIEW_LINES: usize = 100;
+
+// Proposed replacements in a single file
+struct FilePreview {
+    path: String,
+    replacements: usize,
+    changed_lines: usize,
+    // Changed lines as (1-based line number, old line, new line)
+    lines: Vec<(usize, String, String)>,
+}
+
+// Apply the replacement line by line to the stored content of every indexed file and describe
+// the changes as a unified diff, without writing anything. Files are listed by path, and at most
+// `limit` of them are shown in full.
+pub fn preview(
+    searcher: &Searcher,
+    fields: IndexFields,
+    pattern: &Regex,
+    replacement: &str,
+    limit: usize,
+) -> Result<String, String> {
+    let num_docs = (searcher.num_docs() as usize).max(1);
+    let documents = searcher
+        .search(&AllQuery, &TopDocs::with_limit(num_docs))
+        .map_err(|e| format!("Search error: {}", e))?;
+
+    let mut previews = Vec::new();
+    for (_, doc_address) in documents {
+        let document: TantivyDocument = searcher.doc(doc_address).map_err(|e| e.to_string())?;
+        let content = document
+            .get_first(fields.content)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+
+        let mut preview = FilePreview {
+            path: document
+                .get_first(fields.path)
+                .and_then(|v| v.as_str())
+                .unwrap_or("Unknown path")
+                .to_string(),
+            replacements: 0,
+            changed_lines: 0,
+            lines: Vec::new(),
+        };
+        for (index, line) in content.lines().enumerate() {
+            let count = pattern.find_iter(line).count();
+            if count == 0 {
+                continue;
+            }
+            let replaced = pattern.replace_all(line, replacement);
+            if replaced == line {
+                continue;
+            }
+            preview.replacements += count;
+            preview.changed_lines += 1;
+            if preview.lines.len() < MAX_PREVIEW_LINES {
+                preview
+                    .lines
+                    .push((index + 1, line.to_string(), replaced.into_owned()));
+            }
+        }
+        if preview.replacements > 0 {
+            previews.push(preview);
+        }
+    }
+
+    previews.sort_by(|a, b| a.path.cmp(&b.path));
+    if previews.is_empty() {
+        return Ok(format!(
+            "No changes: '{}' does not occur in the indexed files.",
+            pattern.as_str()
+        ));
+    }
+
+    let total: usize = previews.iter().map(|preview| preview.replacements).sum();
+    let mut result_str = format!(
+        "Preview of {} replacements in {} files (nothing was written):\n",
+        total,
+        previews.len()
+    );
+    for preview in previews.iter().take(limit) {
+        result_str.push_str(&format!("--- {path}\n+++ {path}\n", path = preview.path));
+        for (line, old, new) in &preview.lines {
+            result_str.push_str(&format!("@@ -{line} +{line} @@\n-{old}\n+{new}\n"));
+        }
+        if preview.changed_lines > preview.lines.len() {
+            result_str.push_str(&format!(
+                "... {} more changed lines in {} not shown\n",
+                preview.changed_lines - preview.lines.len(),
+                preview.path
+            ));
+        }
+    }
+    if previews.len() > limit {
+        result_str.push_str(&format!(
+            "... {} more files not shown\n",
+            previews.len() - limit
+        ));
+    }
+    Ok(result_str)
+}
@@ -1,367 +1,5480 @@
-use rmcp::model::{Implementation, ProtocolVersion, ServerCapabilities, ServerInfo};
-use rmcp::{ServerHandler, schemars, tool};
+use crate::tools::archives::{self, ENTRY_SEPARATOR};
+use crate::tools::bm25::{self, Bm25Params, weighted_terms};
+use crate::tools::encoding::{self, encoding_for_label};
+use crate::tools::error::SearchError;
+use crate::tools::index_cache;
+use crate::tools::index_stats;
+use crate::tools::indexer::{
+    CollectedFiles, DEFAULT_EXCLUDE_DIRS, DEFAULT_MAX_CONTROL_RATIO, DEFAULT_MAX_FILE_SIZE_BYTES,
+    DEFAULT_MIN_ASCII_RATIO, DEFAULT_READ_RETRIES, DEFAULT_RETRY_BACKOFF_MS, EXTENSION_FIELD,
+    FileKind, FileRecords, IndexFields, IndexOptions, IndexStats, MIN_WRITER_BUFFER_BYTES,
+    MODIFIED_FIELD, PATH_FIELD, ProgressCallback, RetryPolicy, SIZE_FIELD, TextDetection,
+    WalkOptions, WriterOptions, build_extension_set, build_glob_set, build_schema, classify_files,
+    create_writer, default_binary_extensions, git_tracked_files, hash_files, indexed_paths,
+    is_text_content, is_text_file, process_directory, request_shutdown, stored_content,
+    update_index, write_index,
+};
+use crate::tools::json_lines;
+use crate::tools::matching::{
+    ContextLine, LineOptions, clip_line, context_blocks, count_matches, matched_terms,
+    matching_lines, query_terms,
+};
+use crate::tools::paths::expand_path;
+use crate::tools::pdf;
+use crate::tools::prompts;
+use crate::tools::query_syntax::{
+    QueryMode, WildcardField, check_boolean_syntax, literal_query, wildcard_query, with_phrase_slop,
+};
+use crate::tools::replace_preview;
+use crate::tools::tokenizers::{Segmentation, register_tokenizers};
+use crate::tools::watcher::{self, Watcher};
+use globset::{Glob, GlobMatcher};
+use lru::LruCache;
+use regex::{Regex, RegexBuilder};
+use rmcp::model::{
+    AnnotateAble, GetPromptRequestParam, GetPromptResult, Implementation, ListPromptsResult,
+    ListResourcesResult, LoggingLevel, LoggingMessageNotificationParam, PaginatedRequestParam,
+    ProtocolVersion, RawResource, ReadResourceRequestParam, ReadResourceResult, ResourceContents,
+    ResourceUpdatedNotificationParam, ServerCapabilities, ServerInfo, SubscribeRequestParam,
+    UnsubscribeRequestParam,
+};
+use rmcp::service::RequestContext;
+use rmcp::{Error as McpError, Peer, RoleServer, ServerHandler, schemars, tool};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs;
-use std::path::Path;
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
-use tantivy::schema::{STORED, Schema, TextFieldIndexing, TextOptions, Value};
-use tantivy::{Index, TantivyDocument, doc};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::ops::{Bound, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tantivy::collector::{Count, DocSetCollector, TopDocs};
+use tantivy::query::{
+    AllQuery, BooleanQuery, ConstScoreQuery, EnableScoring, Occur, Query, QueryParser,
+    QueryParserError, RangeQuery, TermQuery,
+};
+use tantivy::schema::{Field, IndexRecordOption, Value};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{
+    DocAddress, DocId, DocSet, Index, IndexReader, ReloadPolicy, Score, Searcher, SegmentReader,
+    TERMINATED, TantivyDocument, Term,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
 use tracing;
 
-// Search parameters: directory path and search keyword
+// Indexing parameters shared by the index and search tools
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, schemars::JsonSchema)]
+pub struct IndexingParams {
+    #[schemars(
+        description = "Directory in which to persist the index; when set, the index is reused across calls until files in the searched directory change"
+    )]
+    pub index_cache_dir: Option<String>,
+    #[schemars(
+        description = "Maximum depth of subdirectories to descend into (default: unlimited; 0 searches only the top-level directory)"
+    )]
+    pub max_depth: Option<usize>,
+    #[schemars(
+        description = "Skip files excluded by .gitignore, .ignore and global git excludes (default: true)"
+    )]
+    pub respect_gitignore: Option<bool>,
+    #[schemars(
+        description = "Only index files whose path relative to the directory matches one of these globs (e.g. '*.md')"
+    )]
+    pub include_globs: Option<Vec<String>>,
+    #[schemars(
+        description = "Never index files whose path relative to the directory matches one of these globs (e.g. '*.lock'); takes precedence over include_globs"
+    )]
+    pub exclude_globs: Option<Vec<String>>,
+    #[schemars(
+        description = "Names of directories to skip entirely wherever they appear, e.g. ['vendor', 'coverage']; added to the default exclusions"
+    )]
+    pub exclude_dirs: Option<Vec<String>>,
+    #[schemars(
+        description = "Skip the directories .git, .hg, .svn, node_modules, target, dist, build, .venv, venv and __pycache__ (default: true)"
+    )]
+    pub default_exclude_dirs: Option<bool>,
+    #[schemars(
+        description = "Only index files with one of these extensions, e.g. ['rs', 'toml']; case-insensitive, with or without the leading dot"
+    )]
+    pub extensions: Option<Vec<String>>,
+    #[schemars(
+        description = "Extensions to skip as binary in addition to the built-in list (exe, png, zip, pdf, ...), e.g. ['dat', 'pak']; case-insensitive, with or without the leading dot"
+    )]
+    pub extra_binary_extensions: Option<Vec<String>>,
+    #[schemars(
+        description = "Extensions to read even though they are on the binary list, e.g. ['bin'] for firmware dumps that are actually text; the content is still checked for binary data, and this takes precedence over extra_binary_extensions"
+    )]
+    pub treat_as_text_extensions: Option<Vec<String>>,
+    #[schemars(
+        description = "Largest share of control characters, from 0 to 1, in the first 8 KB of a file that is still text; tabs, line breaks, form feeds and escapes don't count (default: 0.3)"
+    )]
+    pub max_control_ratio: Option<f32>,
+    #[schemars(
+        description = "Smallest share of ASCII bytes, from 0 to 1, in the first 8 KB of a file that is not valid UTF-8 for it to count as text (default: 0.8)"
+    )]
+    pub min_ascii_ratio: Option<f32>,
+    #[schemars(
+        description = "Skip files larger than this many bytes without reading them (default: 10485760, i.e. 10 MB)"
+    )]
+    pub max_file_size_bytes: Option<u64>,
+    #[schemars(
+        description = "Index only the first this many bytes of every file, e.g. 4096 to find license headers or frontmatter in huge files cheaply; only the head of plain text files is read, and max_file_size_bytes applies to it (default: the whole file)"
+    )]
+    pub content_head_bytes: Option<usize>,
+    #[schemars(description = "Number of threads reading files in parallel (default: one per CPU)")]
+    pub threads: Option<usize>,
+    #[schemars(
+        description = "Times to retry reading a file that fails with a transient error such as a timeout on a network filesystem, at most 10; permanent errors such as denied permissions are never retried, and files still failing are counted as unavailable (default: 2)"
+    )]
+    pub read_retries: Option<u32>,
+    #[schemars(
+        description = "Milliseconds to wait before the first retry of a read, doubled before every further retry (default: 50)"
+    )]
+    pub retry_backoff_ms: Option<u64>,
+    #[schemars(
+        description = "Stop reading files once this many bytes of text were read, leaving the remaining files out of the index and reporting the results as partial; protects against indexing huge trees by accident (default: unlimited)"
+    )]
+    pub max_total_bytes: Option<u64>,
+    #[schemars(
+        description = "Number of files beyond which the walk stops and asks for confirm_large instead of indexing, so that a whole disk or home folder is not indexed by accident; 0 disables the check (default: 100000)"
+    )]
+    pub large_directory_threshold: Option<usize>,
+    #[schemars(
+        description = "Index directories with more files than large_directory_threshold anyway, after a request was refused with a confirmation_required error (default: false)"
+    )]
+    pub confirm_large: Option<bool>,
+    #[schemars(
+        description = "Memory budget of the index writer in bytes, at least 15000000 (default: 50000000); larger buffers index big trees faster at the cost of memory"
+    )]
+    pub writer_buffer_bytes: Option<usize>,
+    #[schemars(
+        description = "Send a log notification to the client every 100 indexed files so long indexing runs show progress (default: false)"
+    )]
+    pub report_progress: Option<bool>,
+    #[schemars(
+        description = "Only walk and classify the files, returning the files that would be indexed and why the others would be skipped, without indexing or searching (default: false)"
+    )]
+    pub dry_run: Option<bool>,
+    #[schemars(
+        description = "When the reader of the index picks up new commits: 'on_commit' (default) reloads it shortly after every commit, 'manual' only when the update tool changed the index, without a background watcher per index"
+    )]
+    pub reload_policy: Option<ReloadMode>,
+    #[schemars(
+        description = "Match file contents case-sensitively, e.g. to tell ERROR from error (default: false); cached indexes are rebuilt when this changes"
+    )]
+    pub case_sensitive: Option<bool>,
+    #[schemars(
+        description = "Follow symbolic links to files and directories; each directory is entered only once so link cycles can't loop (default: false, links are skipped and counted)"
+    )]
+    pub follow_symlinks: Option<bool>,
+    #[schemars(
+        description = "Also index hidden files and directories whose name starts with a dot, such as .github or .env (default: false)"
+    )]
+    pub include_hidden: Option<bool>,
+    #[schemars(
+        description = "Only index files tracked by git, as listed by git ls-files, leaving out untracked scratch files; directories outside a git work tree are indexed as usual (default: false)"
+    )]
+    pub git_tracked_only: Option<bool>,
+    #[schemars(
+        description = "Only index files modified at or after this RFC 3339 timestamp, e.g. '2024-05-01T12:00:00Z' or '2024-05-01T12:00:00+09:00'; older files are skipped during the walk without being read (default: no cutoff)"
+    )]
+    pub modified_after: Option<String>,
+    #[schemars(
+        description = "How file contents are split into words: 'default' splits on whitespace and punctuation, 'cjk' additionally splits Chinese, Japanese and Korean text into character bigrams so words can be found without spaces (default: 'default')"
+    )]
+    pub segmentation: Option<Segmentation>,
+    #[schemars(
+        description = "Reduce English words to their stem at index and query time so that e.g. 'running' matches 'runs' (default: false, for exact word matching)"
+    )]
+    pub stemming: Option<bool>,
+    #[schemars(
+        description = "Index the content twice, stemmed and unstemmed, and search both: inflected forms are found as with stemming, while files containing the exact words rank higher, weighted by exact_boost and stemmed_boost (default: false; implies stemming, doubles the size of the content index)"
+    )]
+    pub recall_mode: Option<bool>,
+    #[schemars(
+        description = "Also index the Soundex code of every word and search it, so that misspelled names such as 'Smith' and 'Smyth' match each other; sound-alike matches rank below exact ones (default: false)"
+    )]
+    pub phonetic: Option<bool>,
+    #[schemars(
+        description = "Split camelCase and snake_case identifiers into their words at index and query time, so that 'user' finds 'getUserById' (default: false)"
+    )]
+    pub code_tokenizer: Option<bool>,
+    #[schemars(
+        description = "Encoding assumed for non-UTF-8 files whose encoding can't be detected confidently, as a WHATWG label such as 'shift_jis', 'latin1' or 'gbk' (default: the best guess of the detector)"
+    )]
+    pub fallback_encoding: Option<String>,
+    #[schemars(
+        description = "Index the text files inside .zip, .tar, .tar.gz and .tgz archives under virtual paths such as 'docs.zip!/guide/intro.md' (default: false, archives are skipped as binary)"
+    )]
+    pub index_archives: Option<bool>,
+    #[schemars(
+        description = "Decompress .gz files such as rotated logs and index their text under virtual paths such as 'app.log.gz!/app.log'; the size limit applies to the decompressed content (default: false, .gz files are skipped as binary)"
+    )]
+    pub index_gzip: Option<bool>,
+    #[schemars(
+        description = "Index every line of .jsonl and .ndjson files as a separate document under a path such as 'events.jsonl#42', so hits point at the matching record (default: false, each file is one document)"
+    )]
+    pub jsonl_mode: Option<bool>,
+    #[schemars(
+        description = "Extract and index the text of PDF documents instead of skipping them as binary; documents whose text can't be extracted are skipped and counted (default: false, requires a build with the pdf feature)"
+    )]
+    pub index_pdf: Option<bool>,
+    #[schemars(
+        description = "Store the file contents in the index (default: true). Turning it off roughly halves the memory of the index, but hits then only carry their path and score: snippets, match counts, line numbers, context lines, regex searches and replace previews need the stored content"
+    )]
+    pub store_content: Option<bool>,
+}
+
+impl IndexingParams {
+    // The parameters without those that only change how a request runs, not what it indexes
+    fn affecting_contents(&self) -> Self {
+        Self {
+            threads: None,
+            large_directory_threshold: None,
+            confirm_large: None,
+            report_progress: None,
+            dry_run: None,
+            ..self.clone()
+        }
+    }
+}
+
+// Index parameters: directory path and indexing options
 #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
-pub struct SearchParams {
-    #[schemars(description = "Path to the directory to search")]
+pub struct IndexParams {
+    #[schemars(description = "Path to the directory to index")]
     pub directory: String,
-    #[schemars(description = "Keyword to search for")]
-    pub keyword: String,
+    #[schemars(description = "Additional directories to index into the same index")]
+    pub directories: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub indexing: IndexingParams,
 }
 
-// File content parameters: file path
+// Update parameters: the index to bring up to date
 #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
-pub struct FileContentParams {
-    #[schemars(description = "Path to the file to read")]
-    pub file_path: String,
+pub struct UpdateParams {
+    #[schemars(description = "Id of an index returned by the index tool")]
+    pub index_id: String,
+    #[schemars(
+        description = "Send a logging notification to the client every 100 files read (default: false)"
+    )]
+    pub report_progress: Option<bool>,
 }
 
-// Main tool struct
-#[derive(Debug, Clone)]
-pub struct SearchTool;
+// Reindex parameters: the directories or index to rebuild from scratch
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct ReindexParams {
+    #[schemars(
+        description = "Path to the directory whose index to rebuild (required unless index_id is given or the server has a default directory)"
+    )]
+    pub directory: Option<String>,
+    #[schemars(description = "Additional directories indexed together with it")]
+    pub directories: Option<Vec<String>>,
+    #[schemars(
+        description = "Id of an index returned by the index tool, to rebuild it in place with its own directories and options; indexing options are then ignored"
+    )]
+    pub index_id: Option<String>,
+    #[serde(flatten)]
+    pub indexing: IndexingParams,
+}
 
-#[tool(tool_box)]
-impl SearchTool {
-    pub fn new() -> Self {
-        Self {}
-    }
+// Filters on the metadata of the files, combined with the query
+#[derive(Debug, Default, Clone, serde::Deserialize, schemars::JsonSchema)]
+pub struct SearchFilters {
+    #[schemars(
+        description = "Only match files of at least this many bytes; archive and gzip entries are measured uncompressed"
+    )]
+    pub min_size: Option<u64>,
+    #[schemars(description = "Only match files of at most this many bytes")]
+    pub max_size: Option<u64>,
+    #[schemars(
+        description = "Only match files with this extension, e.g. 'md'; case-insensitive, with or without the leading dot, and an empty string matches files without an extension"
+    )]
+    pub extension: Option<String>,
+}
 
-    /// Read and return the content of a specified file
-    #[tool(description = "Read the content of a file from the specified path")]
-    async fn read_file_content(
-        &self,
-        #[tool(aggr)] params: FileContentParams,
-    ) -> Result<String, String> {
-        // Validate file path
-        let file_path = Path::new(&params.file_path);
+// Boost of the files whose path matches a pattern, to tune relevance to a project's layout
+#[derive(Debug, Clone, serde::Deserialize, schemars::JsonSchema)]
+pub struct PathBoost {
+    #[schemars(
+        description = "Glob matched against the path of a file relative to its searched directory, e.g. 'docs/**' for a directory or '*.md' for an extension; '*' also matches across directories"
+    )]
+    pub pattern: String,
+    #[schemars(
+        description = "Positive factor the relevance score of matching files is multiplied by, e.g. 2 to favor them or 0.5 to demote them"
+    )]
+    pub factor: f32,
+}
 
-        // Check if the path exists
-        if !file_path.exists() {
-            return Err(format!(
-                "The specified path '{}' does not exist",
-                params.file_path
-            ));
-        }
+// Search parameters: directory path or index id, and search keyword
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct SearchParams {
+    #[schemars(
+        description = "Path to the directory to search (required unless index_id is given or the server has a default directory)"
+    )]
+    pub directory: Option<String>,
+    #[schemars(
+        description = "Additional directories to search; all directories are indexed together and results are ranked as one list with absolute paths"
+    )]
+    pub directories: Option<Vec<String>>,
+    #[schemars(
+        description = "Id of an index built with the index tool; when given, the directory is not indexed again and indexing options are ignored"
+    )]
+    pub index_id: Option<String>,
+    #[schemars(
+        description = "Keyword to search for; wrap words in double quotes (e.g. \"error handling\") to match them as an exact phrase, escaping quotes inside a phrase with a backslash. Prefix a term with a field name to search only that field: content:timeout, filename:config, path:/exact/full/path or modified:[1700000000 TO *] (seconds since the epoch)"
+    )]
+    pub keyword: String,
+    #[schemars(
+        description = "Drop files containing any of these words or phrases from the results, e.g. [\"test\", \"mock data\"] to find errors outside of tests; simpler than writing -term in the keyword (default: none)"
+    )]
+    pub exclude_keywords: Option<Vec<String>>,
+    #[schemars(
+        description = "Only match files whose metadata passes these filters, e.g. {\"min_size\": 1048576} with keyword 'license' for files over 1 MB mentioning a license (default: no filters)"
+    )]
+    pub filters: Option<SearchFilters>,
+    #[schemars(
+        description = "Match the whole keyword as an exact phrase, as if it were wrapped in double quotes; embedded quotes are escaped automatically (default: false)"
+    )]
+    pub phrase: Option<bool>,
+    #[schemars(
+        description = "Let the words of phrases be up to this many positions apart, so that \"database connection\" with slop 1 also matches 'database pooled connection'; applies to the whole keyword in phrase mode and to every quoted phrase in boolean mode, while unquoted words match anywhere anyway (default: 0, words must be adjacent and in order)"
+    )]
+    pub slop: Option<u32>,
+    #[schemars(
+        description = "How the keyword is interpreted: 'boolean' (default) supports +term, -term, AND, OR, NOT, parentheses and quoted phrases; 'simple' treats every word literally, ignoring operators and special characters"
+    )]
+    pub query_mode: Option<QueryMode>,
+    #[schemars(
+        description = "Match every word of the keyword as a prefix, e.g. 'config' finds 'configuration'; '*' and '?' inside a word match any characters and a single character, and leading wildcards are rejected (default: false)"
+    )]
+    pub prefix: Option<bool>,
+    #[schemars(
+        description = "Maximum number of hits returned (default: 10, capped at 1000, must be at least 1)"
+    )]
+    pub limit: Option<usize>,
+    #[schemars(
+        description = "Number of top-ranked hits to skip, for paging through results (default: 0)"
+    )]
+    pub offset: Option<usize>,
+    #[schemars(
+        description = "Output format of the results: 'text' (default), 'json', or 'html' for a list of hits linking to the files with matched terms in <mark> tags, for clients that render rich text"
+    )]
+    pub format: Option<OutputFormat>,
+    #[schemars(
+        description = "Tolerate typos by matching terms within a Levenshtein distance; each word of a multi-word query is matched fuzzily on its own (default: false)"
+    )]
+    pub fuzzy: Option<bool>,
+    #[schemars(
+        description = "Maximum edit distance for fuzzy matching (default: 1, at most 2 as supported by Tantivy)"
+    )]
+    pub fuzzy_distance: Option<u8>,
+    #[schemars(
+        description = "Report the line numbers where any of the query terms appear in each hit (default: false)"
+    )]
+    pub line_numbers: Option<bool>,
+    #[schemars(
+        description = "Return the matching lines of each hit with this many lines of context before and after them, like grep -C (default: no context blocks)"
+    )]
+    pub context_lines: Option<usize>,
+    #[schemars(
+        description = "Clip lines of context blocks and regex snippets to this many characters around the match, marking the cuts with '…', so minified or single-line files don't return huge results (default: 500)"
+    )]
+    pub max_line_chars: Option<usize>,
+    #[schemars(
+        description = "Drop hits whose relevance score is below this threshold (default: no threshold)"
+    )]
+    pub min_score: Option<f32>,
+    #[schemars(
+        description = "Add 'normalized_score' to every hit: its score divided by the best score of the returned hits, from 0 to 1, for thresholds and display; it is relative to this query only, not an absolute measure of relevance (default: false)"
+    )]
+    pub normalize_scores: Option<bool>,
+    #[schemars(
+        description = "Return only the number of matching files instead of hits; much cheaper for large result sets (default: false)"
+    )]
+    pub count_only: Option<bool>,
+    #[schemars(
+        description = "Only tell whether any file matches, stopping at the first match without scoring or snippets, and return it as a yes/no with the first matching path; the cheapest answer to 'does any file mention X' (default: false)"
+    )]
+    pub exists_only: Option<bool>,
+    #[schemars(
+        description = "Stop indexing after this many milliseconds and search the files indexed so far, reporting the results as partial (default: no timeout)"
+    )]
+    pub timeout_ms: Option<u64>,
+    #[schemars(
+        description = "Order of the hits: 'relevance' (default), 'modified_desc' for the most recently modified file first, 'modified_asc', or 'path_asc'"
+    )]
+    pub sort_by: Option<SortBy>,
+    #[schemars(
+        description = "BM25 term frequency saturation k1, from 0 to 10; lower values make repeated terms count less (default: 1.2)"
+    )]
+    pub bm25_k1: Option<f32>,
+    #[schemars(
+        description = "BM25 length normalization b, from 0 (ignore document length) to 1 (full normalization) (default: 0.75)"
+    )]
+    pub bm25_b: Option<f32>,
+    #[schemars(
+        description = "Boost of matches of the unstemmed content in an index built with recall_mode, relative to file name matches weighted 2 (default: 2)"
+    )]
+    pub exact_boost: Option<f32>,
+    #[schemars(
+        description = "Boost of matches of the stemmed content in an index built with recall_mode (default: 1)"
+    )]
+    pub stemmed_boost: Option<f32>,
+    #[schemars(
+        description = "Regular expression matched line by line against the stored content of the files matching the keyword; the keyword may be empty to scan all files, which requires an explicit limit"
+    )]
+    pub regex: Option<String>,
+    #[schemars(
+        description = "Return up to this many excerpts per hit, each around different matches, best first: the best one as snippet and the others as more_snippets, for a fuller picture of long documents matching in several places; at most 10 (default: 1)"
+    )]
+    pub max_snippets: Option<usize>,
+    #[schemars(
+        description = "Only match the keyword as a substring of file names, without reading or indexing file contents; much faster on large trees (default: false)"
+    )]
+    pub filename_only: Option<bool>,
+    #[schemars(
+        description = "List every indexed text file instead of searching, with an empty keyword; files are sorted by path unless sort_by says otherwise and paged with limit and offset, so the tool doubles as a filtered file lister (default: false)"
+    )]
+    pub list_all: Option<bool>,
+    #[schemars(
+        description = "Instead of searching, hash the content of every text file with BLAKE3 and return the groups of files with identical content, the groups wasting the most bytes first, for cleaning up copies; the keyword is ignored, and limit and offset page through the groups (default: false)"
+    )]
+    pub find_duplicates: Option<bool>,
+    #[schemars(
+        description = "Collapse the matching files into their parent directories with the number of matching files in each, most matches first, for an overview of where matches concentrate; limit and offset page through the directories (default: false)"
+    )]
+    pub group_by_dir: Option<bool>,
+    #[schemars(
+        description = "Return at most this many hits from any single parent directory, filling the page with lower-ranked hits from other directories, so that one folder can't dominate the results (default: no cap)"
+    )]
+    pub max_per_dir: Option<usize>,
+    #[schemars(
+        description = "Multiply the scores of the files matching path patterns, e.g. [{\"pattern\": \"docs/**\", \"factor\": 2}, {\"pattern\": \"tests/**\", \"factor\": 0.5}] to rank documentation above tests; the factors of all matching patterns are multiplied (default: none)"
+    )]
+    pub boosts: Option<Vec<PathBoost>>,
+    #[schemars(
+        description = "Send every hit to the client as a logging notification as soon as it is collected, before the complete result is returned (default: false)"
+    )]
+    pub stream: Option<bool>,
+    #[schemars(
+        description = "Marker inserted before each matched term in snippets (default: '**' in text, '<mark>' in HTML, none in JSON)"
+    )]
+    pub highlight_pre: Option<String>,
+    #[schemars(
+        description = "Marker inserted after each matched term in snippets (default: '**' in text, '</mark>' in HTML, none in JSON)"
+    )]
+    pub highlight_post: Option<String>,
+    #[schemars(
+        description = "Add 'highlights' to every JSON hit: the [start, end] byte offsets of the matched terms within the returned snippet, for clients doing their own highlighting; they point at the terms inside any markers (default: false)"
+    )]
+    pub highlight_offsets: Option<bool>,
+    #[serde(flatten)]
+    pub indexing: IndexingParams,
+}
 
-        // Check if the path is a file
-        if !file_path.is_file() {
-            return Err(format!(
-                "The specified path '{}' is not a file",
-                params.file_path
-            ));
+// Output format of the search results
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Html,
+}
+
+// Order in which the search hits are returned
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    // Highest relevance score first
+    #[default]
+    Relevance,
+    // Most recently modified file first
+    ModifiedDesc,
+    // Least recently modified file first
+    ModifiedAsc,
+    // Alphabetically by path
+    PathAsc,
+}
+
+// When the reader of an index reloads to see new commits
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadMode {
+    // Reload shortly after every commit
+    #[default]
+    OnCommit,
+    // Reload only when explicitly told to
+    Manual,
+}
+
+impl From<ReloadMode> for ReloadPolicy {
+    fn from(mode: ReloadMode) -> Self {
+        match mode {
+            ReloadMode::OnCommit => ReloadPolicy::OnCommitWithDelay,
+            ReloadMode::Manual => ReloadPolicy::Manual,
         }
+    }
+}
 
-        // Try to read the file content
-        match fs::read_to_string(file_path) {
-            Ok(content) => {
-                if content.is_empty() {
-                    Ok("File is empty.".to_string())
-                } else {
-                    Ok(content)
+// Callback receiving each hit as soon as it is collected
+pub type HitCallback = Arc<dyn Fn(&Hit) + Send + Sync>;
+
+// A single search hit: file path, relevance score and an excerpt of the content
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Hit {
+    pub path: String,
+    pub score: f32,
+    // Score relative to the best hit of the page, from 0 to 1, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalized_score: Option<f32>,
+    // Modification time of the file as an RFC 3339 timestamp in UTC, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    pub snippet: String,
+    // Number of occurrences of the query terms in the file content
+    pub match_count: usize,
+    // Query terms found in the file content or name, telling e.g. which side of an OR query
+    // made the file match
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matched_terms: Vec<String>,
+    // Byte ranges of the matched terms within the snippet
+    #[serde(skip)]
+    pub highlighted: Vec<Range<usize>>,
+    // Line numbers (1-based) containing any of the query terms, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<Vec<usize>>,
+    // Matching lines with their surrounding lines, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<Vec<ContextLine>>>,
+    // Start and end byte offsets of the matched terms within the returned snippet, when
+    // requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub highlights: Option<Vec<(usize, usize)>>,
+    // Further excerpts around other matches, best first, when more than one snippet was asked
+    // for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub more_snippets: Vec<String>,
+    // Byte ranges of the matched terms within each of the further excerpts
+    #[serde(skip)]
+    pub more_highlighted: Vec<Vec<Range<usize>>>,
+}
+
+// Structured search results returned in JSON format
+#[derive(Debug, serde::Serialize)]
+pub struct SearchResults {
+    // Absolute paths of the searched directories
+    pub roots: Vec<String>,
+    pub hits: Vec<Hit>,
+    // Indexing counters, reported with every response to help debug file filters
+    #[serde(flatten)]
+    pub stats: IndexStats,
+    // Number of hits in this page
+    pub total_hits: usize,
+    // Number of documents matching the query across all pages
+    pub total_matches: usize,
+    pub offset: usize,
+    // Number of hits in this page dropped for scoring below min_score
+    pub below_min_score: usize,
+    // Whether indexing stopped at the timeout, so only part of the files were searched
+    pub timed_out: bool,
+}
+
+// Matching files of one directory, when hits are grouped by directory
+#[derive(Debug, serde::Serialize)]
+pub struct DirectoryHits {
+    pub directory: String,
+    // Number of matching files directly inside the directory
+    pub matches: usize,
+}
+
+// Structured results of a search grouped by directory
+#[derive(Debug, serde::Serialize)]
+pub struct GroupedResults {
+    pub roots: Vec<String>,
+    pub directories: Vec<DirectoryHits>,
+    #[serde(flatten)]
+    pub stats: IndexStats,
+    // Number of directories containing matches across all pages
+    pub total_directories: usize,
+    pub total_matches: usize,
+    pub offset: usize,
+    pub timed_out: bool,
+}
+
+// Parent directory of an indexed path, which hits are grouped and capped by
+fn parent_directory(path: &str) -> String {
+    Path::new(path)
+        .parent()
+        .map(|parent| parent.display().to_string())
+        .unwrap_or_default()
+}
+
+// Keep at most `max_per_dir` of the ranked items of every parent directory, in order, so that
+// lower-ranked items from other directories take the places of the dropped ones
+fn cap_per_directory<T>(
+    items: impl IntoIterator<Item = (T, String)>,
+    max_per_dir: usize,
+) -> Vec<T> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    items
+        .into_iter()
+        .filter_map(|(item, path)| {
+            let count = counts.entry(parent_directory(&path)).or_default();
+            *count += 1;
+            (*count <= max_per_dir).then_some(item)
+        })
+        .collect()
+}
+
+// Identity of the physical file behind an indexed path: the canonical path of the file, or of
+// the archive or JSON lines file holding a virtual path with the rest of the path appended
+fn physical_file(path: &str) -> String {
+    let container = json_lines::container_path(archives::container_path(path));
+    match fs::canonicalize(container) {
+        Ok(canonical) => format!(
+            "{}{}",
+            canonical.to_string_lossy(),
+            &path[container.len()..]
+        ),
+        Err(_) => path.to_string(),
+    }
+}
+
+// Keep one of the ranked items reaching the same physical file under several paths: the
+// highest-scoring one, or the first among equals, at its own rank
+fn distinct_files<T>(
+    items: Vec<T>,
+    score: impl Fn(&T) -> Score,
+    path: impl Fn(&T) -> &str,
+) -> Vec<T> {
+    let files: Vec<String> = items.iter().map(|item| physical_file(path(item))).collect();
+    let mut best: HashMap<&str, usize> = HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        best.entry(file)
+            .and_modify(|best| {
+                if score(&items[i]) > score(&items[*best]) {
+                    *best = i;
                 }
+            })
+            .or_insert(i);
+    }
+    let kept: HashSet<usize> = best.into_values().collect();
+    let found = items.len();
+    let items: Vec<T> = items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| kept.contains(i))
+        .map(|(_, item)| item)
+        .collect();
+    if items.len() < found {
+        tracing::info!(
+            "Collapsed {} duplicate paths of the same files",
+            found - items.len()
+        );
+    }
+    items
+}
+
+// Compile the path boosts of a search, rejecting invalid patterns and factors
+fn path_boosts(boosts: &[PathBoost]) -> Result<Vec<(GlobMatcher, f32)>, SearchError> {
+    boosts
+        .iter()
+        .map(|boost| {
+            if !(boost.factor.is_finite() && boost.factor > 0.0) {
+                return Err(SearchError::InvalidParams(format!(
+                    "The factor of the boost pattern '{}' must be a positive number.",
+                    boost.pattern
+                )));
             }
-            Err(e) => {
-                // Handle binary files or read errors
-                tracing::error!("Error reading file '{}': {}", file_path.display(), e);
+            let glob = Glob::new(&boost.pattern).map_err(|e| {
+                SearchError::InvalidParams(format!(
+                    "Invalid glob pattern '{}': {}",
+                    boost.pattern, e
+                ))
+            })?;
+            Ok((glob.compile_matcher(), boost.factor))
+        })
+        .collect()
+}
 
-                // Try to read as binary and check if it's a binary file
-                match fs::read(file_path) {
-                    Ok(bytes) => {
-                        // Check if it seems to be a binary file
-                        if bytes.iter().any(|&b| b == 0)
-                            || bytes
-                                .iter()
-                                .filter(|&&b| b < 32 && b != 9 && b != 10 && b != 13)
-                                .count()
-                                > bytes.len() / 10
-                        {
-                            Err(format!(
-                                "The file '{}' appears to be a binary file and cannot be displayed as text",
-                                params.file_path
-                            ))
-                        } else {
-                            Err(format!(
-                                "The file '{}' could not be read as text: {}",
-                                params.file_path, e
-                            ))
-                        }
-                    }
-                    Err(read_err) => Err(format!(
-                        "Error reading file '{}': {}",
-                        params.file_path, read_err
-                    )),
-                }
+// Product of the factors of the boost patterns matching the path relative to its root
+fn boost_factor(boosts: &[(GlobMatcher, f32)], roots: &[PathBuf], path: &str) -> f32 {
+    let path = Path::new(path);
+    let relative = roots
+        .iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path);
+    boosts
+        .iter()
+        .filter(|(matcher, _)| matcher.is_match(relative))
+        .map(|(_, factor)| factor)
+        .product()
+}
+
+// Count the matching files of every parent directory, the directories with most matches first
+fn group_by_directory(paths: impl IntoIterator<Item = String>) -> Vec<DirectoryHits> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for path in paths {
+        *counts.entry(parent_directory(&path)).or_default() += 1;
+    }
+    let mut directories: Vec<DirectoryHits> = counts
+        .into_iter()
+        .map(|(directory, matches)| DirectoryHits { directory, matches })
+        .collect();
+    directories.sort_by(|a, b| {
+        b.matches
+            .cmp(&a.matches)
+            .then_with(|| a.directory.cmp(&b.directory))
+    });
+    directories
+}
+
+// Path of the first live document matching a query, found without scoring and without
+// visiting the documents after it
+fn first_match(
+    searcher: &Searcher,
+    query: &dyn Query,
+    path_field: Field,
+) -> Result<Option<String>, SearchError> {
+    let search_error =
+        |e: tantivy::TantivyError| SearchError::IndexRead(format!("Search error: {}", e));
+    let weight = query
+        .weight(EnableScoring::disabled_from_searcher(searcher))
+        .map_err(search_error)?;
+    for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+        let mut scorer = weight.scorer(segment_reader, 1.0).map_err(search_error)?;
+        let alive_bitset = segment_reader.alive_bitset();
+        let mut doc = scorer.doc();
+        while doc != TERMINATED {
+            if alive_bitset.is_none_or(|alive_bitset| alive_bitset.is_alive(doc)) {
+                let retrieved_doc: TantivyDocument = searcher
+                    .doc(DocAddress::new(segment_ord as u32, doc))
+                    .map_err(|e| SearchError::IndexRead(e.to_string()))?;
+                return Ok(Some(
+                    retrieved_doc
+                        .get_first(path_field)
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("Unknown path")
+                        .to_string(),
+                ));
             }
+            doc = scorer.advance();
         }
     }
+    Ok(None)
+}
 
-    /// Perform full-text search for keywords on text files (such as .txt, .md, etc.) in the specified directory
-    #[tool(description = "Search for keywords in text files within the specified directory")]
-    async fn search(&self, #[tool(aggr)] params: SearchParams) -> Result<String, String> {
-        // 1. Define schema for Tantivy (file paths and content)
-        let mut schema_builder = Schema::builder();
-        let path_field = schema_builder.add_text_field("path", STORED);
-
-        // Improve content field settings: explicitly set indexing options
-        let text_indexing = TextFieldIndexing::default().set_tokenizer("default");
-        let text_options = TextOptions::default()
-            .set_indexing_options(text_indexing)
-            .set_stored();
-        let content_field = schema_builder.add_text_field("content", text_options);
-
-        let schema = schema_builder.build();
-
-        // 2. Create in-memory index
-        let index = Index::create_in_ram(schema.clone());
-
-        // 3. Create index writer (adjust buffer size as needed)
-        let mut index_writer = index
-            .writer(50_000_000)
-            .map_err(|e| format!("Index writer error: {}", e))?;
-
-        // Count the number of files added to the index
-        let mut indexed_files_count = 0;
-        // Track directory processing status (for debugging)
-        let mut found_files_count = 0;
-        let mut skipped_files_count = 0;
-
-        // 4. Read text files in the specified directory and add them to the index
-        let dir_path = Path::new(&params.directory);
-        if !dir_path.is_dir() {
-            return Err(format!(
-                "The specified path '{}' is not a directory",
-                params.directory
-            ));
+// Response of an exists_only search: whether any file matches, and the first one that does
+fn exists_output(
+    format: OutputFormat,
+    path: Option<String>,
+    keyword: &str,
+    summary: &str,
+) -> String {
+    match (format, path) {
+        (OutputFormat::Json, path) => {
+            serde_json::json!({ "exists": path.is_some(), "path": path }).to_string()
         }
+        (_, Some(path)) => format!("Yes, '{}' is found in {}\n{}", keyword, path, summary),
+        (_, None) => format!("No, no file matches '{}'\n{}", keyword, summary),
+    }
+}
 
-        // Blacklist of extensions likely to be binary files
-        // Skip extensions that are clearly binary files
-        let binary_extensions = [
-            "exe", "dll", "so", "dylib", "bin", "obj", "o", "a", "lib", "png", "jpg", "jpeg",
-            "gif", "bmp", "tiff", "webp", "ico", "mp3", "mp4", "wav", "ogg", "flac", "avi", "mov",
-            "mkv", "zip", "gz", "tar", "7z", "rar", "jar", "war", "pdf", "doc", "docx", "xls",
-            "xlsx", "ppt", "pptx", "db", "sqlite", "mdb", "iso", "dmg", "class",
-        ];
-
-        // Function to determine if a file is a text file
-        fn is_text_file(path: &Path, binary_extensions: &[&str]) -> bool {
-            // 1. First check extensions that are clearly binary
-            if let Some(ext) = path.extension() {
-                let ext_str = ext.to_string_lossy().to_lowercase();
-                if binary_extensions.iter().any(|&bin_ext| bin_ext == ext_str) {
-                    return false;
-                }
-            }
-
-            // 2. Read the beginning of the file and determine if it is binary
-            match fs::read(path) {
-                Ok(bytes) if !bytes.is_empty() => {
-                    // Sample size (read up to 8KB)
-                    let sample_size = std::cmp::min(bytes.len(), 8192);
-                    let sample = &bytes[..sample_size];
-
-                    // Detect binary characteristics
-                    // 1. Detect NULL bytes (text files do not have NULL bytes)
-                    if sample.iter().any(|&b| b == 0) {
-                        return false;
-                    }
+// Paths of all the documents matching a query
+fn matching_paths(
+    searcher: &Searcher,
+    query: &dyn Query,
+    path_field: Field,
+) -> Result<Vec<String>, SearchError> {
+    let doc_addresses = searcher
+        .search(query, &DocSetCollector)
+        .map_err(|e| SearchError::IndexRead(format!("Search error: {}", e)))?;
+    doc_addresses
+        .into_iter()
+        .map(|doc_address| {
+            let doc: TantivyDocument = searcher
+                .doc(doc_address)
+                .map_err(|e| SearchError::IndexRead(e.to_string()))?;
+            Ok(doc
+                .get_first(path_field)
+                .and_then(|v| v.as_str())
+                .unwrap_or("Unknown path")
+                .to_string())
+        })
+        .collect()
+}
 
-                    // 2. Check the ratio of control characters
-                    let control_chars_count = sample
-                        .iter()
-                        .filter(|&&b| {
-                            b < 32 && b != 9 && b != 10 && b != 13 // Exclude Tab, LF, CR
-                        })
-                        .count();
-
-                    // If the ratio of control characters is too high, consider it binary
-                    if (control_chars_count as f32 / sample_size as f32) > 0.3 {
-                        return false;
-                    }
+// A file a dry run would not index, and why
+#[derive(Debug, serde::Serialize)]
+pub struct SkippedFile {
+    pub path: String,
+    pub reason: FileKind,
+}
 
-                    // 3. Check if it is valid UTF-8
-                    let is_valid_utf8 = std::str::from_utf8(sample).is_ok();
+// Structured dry run results returned in JSON format
+#[derive(Debug, serde::Serialize)]
+pub struct DryRunResults {
+    // Files that would be indexed
+    pub files: Vec<String>,
+    pub skipped: Vec<SkippedFile>,
+    #[serde(flatten)]
+    pub stats: IndexStats,
+}
 
-                    // 4. Check the ASCII ratio
-                    let ascii_ratio =
-                        sample.iter().filter(|&&b| b <= 127).count() as f32 / sample_size as f32;
+// Files sharing the same content
+#[derive(Debug, serde::Serialize)]
+pub struct DuplicateGroup {
+    // BLAKE3 hash of the content, in hex
+    pub hash: String,
+    // Size of each of the files
+    pub bytes: u64,
+    pub files: Vec<String>,
+}
 
-                    // Valid UTF-8 with a high ASCII ratio, or specific non-UTF-8 encoding characteristics
-                    is_valid_utf8 || ascii_ratio > 0.8
-                }
-                _ => false, // Do not consider files with read errors or size 0 as text
-            }
+impl DuplicateGroup {
+    // Bytes taken by all the copies but one
+    fn wasted_bytes(&self) -> u64 {
+        self.bytes * (self.files.len() as u64 - 1)
+    }
+}
+
+// Structured duplicate report returned in JSON format
+#[derive(Debug, serde::Serialize)]
+pub struct DuplicateResults {
+    pub roots: Vec<String>,
+    pub groups: Vec<DuplicateGroup>,
+    pub total_groups: usize,
+    // Files in any of the groups, including the first copy of each
+    pub duplicate_files: usize,
+    pub wasted_bytes: u64,
+    pub hashed_files: usize,
+    pub offset: usize,
+    pub timed_out: bool,
+}
+
+// Serialize a response as pretty-printed JSON
+fn to_pretty_json(value: &impl serde::Serialize) -> Result<String, SearchError> {
+    serde_json::to_string_pretty(value)
+        .map_err(|e| SearchError::Internal(format!("Serialization error: {}", e)))
+}
+
+impl SearchResults {
+    // Serialize the results as pretty-printed JSON
+    fn to_json(&self) -> Result<String, SearchError> {
+        to_pretty_json(self)
+    }
+}
+
+// Environment variable setting how many recently built indexes are kept in memory
+pub const INDEX_CACHE_SIZE_ENV: &str = "FILE_SEARCH_INDEX_CACHE_SIZE";
+// Number of recently built indexes kept unless configured otherwise
+pub const DEFAULT_INDEX_CACHE_SIZE: usize = 4;
+// Environment variable limiting how many searches and indexing runs may work at the same time
+pub const MAX_CONCURRENT_SEARCHES_ENV: &str = "FILE_SEARCH_MAX_CONCURRENT_SEARCHES";
+// Number of concurrent searches and indexing runs allowed unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_SEARCHES: usize = 4;
+// Environment variable naming the directory searched when a request names none
+pub const DEFAULT_DIRECTORY_ENV: &str = "FILE_SEARCH_DEFAULT_DIRECTORY";
+// Command line argument naming the directory searched when a request names none
+pub const DEFAULT_DIRECTORY_ARG: &str = "--default-directory";
+// Error of requests that name no directory and no index, without a default directory
+const NO_DIRECTORY_MESSAGE: &str = "Either a directory or an index_id must be specified, or a default directory configured with --default-directory or FILE_SEARCH_DEFAULT_DIRECTORY.";
+// Default number of hits returned when no limit is given
+const DEFAULT_RESULT_LIMIT: usize = 10;
+// Upper bound for the number of hits so a caller can't exhaust memory
+const MAX_RESULT_LIMIT: usize = 1000;
+// Edit distance used for fuzzy matching when none is given
+const DEFAULT_FUZZY_DISTANCE: u8 = 1;
+// Largest edit distance supported by Tantivy's fuzzy term queries
+const MAX_FUZZY_DISTANCE: u8 = 2;
+// Boost applied to file name matches relative to content matches
+const FILENAME_BOOST: f32 = 2.0;
+// Boosts of the unstemmed and stemmed content in recall mode, so that exact words rank higher
+const DEFAULT_EXACT_BOOST: f32 = 2.0;
+const DEFAULT_STEMMED_BOOST: f32 = 1.0;
+// Boost of the Soundex codes in phonetic mode, so that sound-alikes rank below the spelling
+// searched for
+const PHONETIC_BOOST: f32 = 0.5;
+// Maximum number of matching line numbers reported per hit
+const MAX_REPORTED_LINES: usize = 100;
+// Lines of context blocks and regex snippets are clipped to this many characters by default
+const DEFAULT_MAX_LINE_CHARS: usize = 500;
+// Maximum number of characters in a content snippet
+const SNIPPET_MAX_CHARS: usize = 160;
+// Most excerpts returned per hit
+const MAX_SNIPPETS: usize = 10;
+// Fields that can be targeted with field:value in a query
+const QUERYABLE_FIELDS: &[&str] = &[
+    "content",
+    "filename",
+    "path",
+    MODIFIED_FIELD,
+    SIZE_FIELD,
+    EXTENSION_FIELD,
+];
+// Time allowed after the timeout for searching what was indexed until then
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+// Directories with more files than this are only indexed when confirmed
+const DEFAULT_LARGE_DIRECTORY_THRESHOLD: usize = 100_000;
+// Upper bound for the retries of a read, so that a dead mount can't stall indexing for long
+const MAX_READ_RETRIES: u32 = 10;
+
+// Quote a keyword so the query parser matches it as a single phrase
+fn phrase_query(keyword: &str) -> String {
+    let escaped = keyword.trim().replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+// Combine the query with a MUST_NOT clause for every excluded keyword. Each keyword is matched
+// exactly as a phrase in the contents and file names, even when the query itself is fuzzy.
+fn with_exclusions(
+    query: Box<dyn Query>,
+    built: &BuiltIndex,
+    exclude_keywords: &[String],
+) -> Result<Box<dyn Query>, SearchError> {
+    let query_parser = QueryParser::for_index(
+        &built.index,
+        vec![built.fields.content, built.fields.filename],
+    );
+    let mut clauses = Vec::new();
+    for keyword in exclude_keywords {
+        if keyword.trim().is_empty() {
+            continue;
         }
+        let excluded = query_parser
+            .parse_query(&phrase_query(keyword))
+            .map_err(|e| {
+                SearchError::QueryParse(format!(
+                    "Query parse error in excluded keyword '{}': {}",
+                    keyword, e
+                ))
+            })?;
+        clauses.push((Occur::MustNot, excluded));
+    }
+    if clauses.is_empty() {
+        return Ok(query);
+    }
+    clauses.insert(0, (Occur::Must, query));
+    Ok(Box::new(BooleanQuery::new(clauses)))
+}
 
-        // Function to recursively process directory entries
-        fn process_directory(
-            dir_path: &Path,
-            index_writer: &mut tantivy::IndexWriter,
-            path_field: tantivy::schema::Field,
-            content_field: tantivy::schema::Field,
-            binary_extensions: &[&str],
-            indexed_files_count: &mut usize,
-            found_files_count: &mut usize,
-            skipped_files_count: &mut usize,
-        ) -> Result<(), String> {
-            for entry in fs::read_dir(dir_path)
-                .map_err(|e| format!("Directory read error '{}': {}", dir_path.display(), e))?
-            {
-                let entry = entry.map_err(|e| format!("Entry read error: {}", e))?;
-                let path = entry.path();
-
-                if path.is_dir() {
-                    // Recursively process subdirectories (add depth limit if needed)
-                    process_directory(
-                        &path,
-                        index_writer,
-                        path_field,
-                        content_field,
-                        binary_extensions,
-                        indexed_files_count,
-                        found_files_count,
-                        skipped_files_count,
-                    )?;
-                } else if path.is_file() {
-                    *found_files_count += 1;
-
-                    // More universal text file determination
-                    if is_text_file(&path, binary_extensions) {
-                        match fs::read_to_string(&path) {
-                            Ok(content) => {
-                                if !content.trim().is_empty() {
-                                    index_writer
-                                        .add_document(doc!(
-                                            path_field => path.to_string_lossy().to_string(),
-                                            content_field => content,
-                                        ))
-                                        .map_err(|e| format!("Document addition error: {}", e))?;
-                                    *indexed_files_count += 1;
-                                    tracing::debug!("Indexed: {}", path.display());
-                                } else {
-                                    *skipped_files_count += 1;
-                                    tracing::debug!("Skipped (empty file): {}", path.display());
-                                }
-                            }
-                            Err(e) => {
-                                // Skip and continue on read errors
-                                *skipped_files_count += 1;
-                                tracing::debug!("Skipped (read error): {} - {}", path.display(), e);
-                            }
+// Combine the query with a MUST clause for every metadata filter. The filters don't score, so
+// that they leave the ranking of the query alone.
+fn with_filters(
+    query: Box<dyn Query>,
+    fields: IndexFields,
+    filters: Option<&SearchFilters>,
+) -> Box<dyn Query> {
+    let Some(filters) = filters else {
+        return query;
+    };
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, query)];
+    if filters.min_size.is_some() || filters.max_size.is_some() {
+        let size = RangeQuery::new_u64_bounds(
+            SIZE_FIELD.to_string(),
+            filters.min_size.map_or(Bound::Unbounded, Bound::Included),
+            filters.max_size.map_or(Bound::Unbounded, Bound::Included),
+        );
+        clauses.push((
+            Occur::Must,
+            Box::new(ConstScoreQuery::new(Box::new(size), 0.0)),
+        ));
+    }
+    if let Some(extension) = &filters.extension {
+        let extension = extension.trim().trim_start_matches('.').to_lowercase();
+        let extension = TermQuery::new(
+            Term::from_field_text(fields.extension, &extension),
+            IndexRecordOption::Basic,
+        );
+        clauses.push((
+            Occur::Must,
+            Box::new(ConstScoreQuery::new(Box::new(extension), 0.0)),
+        ));
+    }
+    if clauses.len() == 1 {
+        return clauses.remove(0).1;
+    }
+    Box::new(BooleanQuery::new(clauses))
+}
+
+// Response of a count_only search: the bare number, or a JSON object holding it
+fn count_output(format: OutputFormat, count: usize) -> String {
+    match format {
+        OutputFormat::Text | OutputFormat::Html => count.to_string(),
+        OutputFormat::Json => serde_json::json!({ "count": count }).to_string(),
+    }
+}
+
+// Drop the sort keys of hits ranked by a key, keeping their relevance scores
+fn strip_sort_keys<K>(hits: Vec<((K, Score), DocAddress)>) -> Vec<(Score, DocAddress)> {
+    hits.into_iter()
+        .map(|((_, score), doc_address)| (score, doc_address))
+        .collect()
+}
+
+// Path of a document read from the fast field, without loading the stored document
+fn indexed_path(searcher: &Searcher, doc_address: DocAddress) -> tantivy::Result<String> {
+    let mut path = String::new();
+    if let Some(column) = searcher
+        .segment_reader(doc_address.segment_ord)
+        .fast_fields()
+        .str(PATH_FIELD)?
+        && let Some(ord) = column.term_ords(doc_address.doc_id).next()
+    {
+        column.ord_to_str(ord, &mut path)?;
+    }
+    Ok(path)
+}
+
+// Run the query and collect a page of hits in the requested order. Orders other than relevance
+// rank by a sort key read from a fast field, with the score as tie-breaker so it can be reported.
+fn top_hits(
+    searcher: &Searcher,
+    query: &dyn Query,
+    collector: TopDocs,
+    sort_by: SortBy,
+) -> tantivy::Result<Vec<(Score, DocAddress)>> {
+    // Modification time of a document, 0 when unknown
+    let modified_of = |segment_reader: &SegmentReader| {
+        let column = segment_reader.fast_fields().u64(MODIFIED_FIELD).ok();
+        move |doc: DocId| {
+            column
+                .as_ref()
+                .and_then(|column| column.first(doc))
+                .unwrap_or(0)
+        }
+    };
+
+    match sort_by {
+        SortBy::Relevance => searcher.search(query, &collector),
+        SortBy::ModifiedDesc => searcher
+            .search(
+                query,
+                &collector.tweak_score(move |segment_reader: &SegmentReader| {
+                    let modified = modified_of(segment_reader);
+                    move |doc: DocId, score: Score| (modified(doc), score)
+                }),
+            )
+            .map(strip_sort_keys),
+        SortBy::ModifiedAsc => searcher
+            .search(
+                query,
+                &collector.tweak_score(move |segment_reader: &SegmentReader| {
+                    let modified = modified_of(segment_reader);
+                    move |doc: DocId, score: Score| (Reverse(modified(doc)), score)
+                }),
+            )
+            .map(strip_sort_keys),
+        SortBy::PathAsc => searcher
+            .search(
+                query,
+                &collector.tweak_score(|segment_reader: &SegmentReader| {
+                    let column = segment_reader.fast_fields().str(PATH_FIELD).ok().flatten();
+                    move |doc: DocId, score: Score| {
+                        let mut path = String::new();
+                        if let Some(column) = &column
+                            && let Some(ord) = column.term_ords(doc).next()
+                        {
+                            let _ = column.ord_to_str(ord, &mut path);
                         }
-                    } else {
-                        *skipped_files_count += 1;
-                        tracing::debug!("Skipped (non-text): {}", path.display());
+                        (Reverse(path), score)
                     }
-                }
+                }),
+            )
+            .map(strip_sort_keys),
+    }
+}
+
+// Sort the highlighted ranges and merge the ones that overlap or touch, so that adjacent
+// matches get a single pair of markers
+fn merge_ranges(highlighted: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut sorted = highlighted.to_vec();
+    sorted.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// Wrap the highlighted ranges of a snippet in the given markers
+fn highlight_snippet(snippet: &str, highlighted: &[Range<usize>], pre: &str, post: &str) -> String {
+    highlight_with(snippet, highlighted, pre, post, str::to_string)
+}
+
+// Highlight the matched terms of a snippet for HTML: the text is escaped, the markers aren't
+fn html_snippet(snippet: &str, highlighted: &[Range<usize>], pre: &str, post: &str) -> String {
+    highlight_with(snippet, highlighted, pre, post, html_escape)
+}
+
+// Up to `max_snippets` excerpts of the content around different matches, best first, each with
+// the byte ranges of its matched terms. Every excerpt found is blanked out before looking for
+// the next one, which keeps the byte offsets of the rest of the content and keeps the excerpts
+// from overlapping.
+fn content_snippets(
+    generator: &SnippetGenerator,
+    content: &str,
+    max_snippets: usize,
+) -> Vec<(String, Vec<Range<usize>>)> {
+    let mut text = content.to_string();
+    let mut snippets = Vec::new();
+    while snippets.len() < max_snippets {
+        let snippet = generator.snippet(&text);
+        let fragment = snippet.fragment();
+        let Some(start) = (!snippet.highlighted().is_empty())
+            .then(|| text.find(fragment))
+            .flatten()
+        else {
+            break;
+        };
+        // A fragment next to a blanked one may start or end with its blanks
+        let leading = fragment.len() - fragment.trim_start().len();
+        let highlighted = snippet
+            .highlighted()
+            .iter()
+            .map(|range| range.start - leading..range.end - leading)
+            .collect();
+        snippets.push((fragment.trim().to_string(), highlighted));
+        let end = start + fragment.len();
+        text.replace_range(start..end, &" ".repeat(end - start));
+    }
+    snippets
+}
+
+// Mark the matched terms of JSON snippets, which stay plain unless markers were requested, and
+// report where the terms are in the returned snippets when asked to
+fn finish_json_snippets(hits: &mut [Hit], (pre, post): (&str, &str), offsets: bool) {
+    for hit in hits {
+        if offsets {
+            // Every merged range is preceded by its own marker and the pairs of those before it
+            let ranges = merge_ranges(&hit.highlighted);
+            hit.highlights = Some(
+                ranges
+                    .iter()
+                    .enumerate()
+                    .map(|(i, range)| {
+                        let shift = pre.len() * (i + 1) + post.len() * i;
+                        (range.start + shift, range.end + shift)
+                    })
+                    .collect(),
+            );
+        }
+        if !pre.is_empty() || !post.is_empty() {
+            hit.snippet = highlight_snippet(&hit.snippet, &hit.highlighted, pre, post);
+            for (snippet, highlighted) in hit.more_snippets.iter_mut().zip(&hit.more_highlighted) {
+                *snippet = highlight_snippet(snippet, highlighted, pre, post);
             }
-            Ok(())
         }
+    }
+}
 
-        // Execute directory processing
-        tracing::info!("Target directory for search: {}", dir_path.display());
-        process_directory(
-            dir_path,
-            &mut index_writer,
-            path_field,
-            content_field,
-            &binary_extensions,
-            &mut indexed_files_count,
-            &mut found_files_count,
-            &mut skipped_files_count,
-        )?;
+// Markers around matched terms unless others are given: bold in text, <mark> in HTML and none
+// in JSON
+fn default_markers(format: OutputFormat) -> (&'static str, &'static str) {
+    match format {
+        OutputFormat::Text => ("**", "**"),
+        OutputFormat::Json => ("", ""),
+        OutputFormat::Html => ("<mark>", "</mark>"),
+    }
+}
 
-        tracing::info!(
-            "Processing complete: Found files={}, Indexed={}, Skipped={}",
-            found_files_count,
-            indexed_files_count,
-            skipped_files_count
-        );
+// Escape text for HTML content and attribute values
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
 
-        // Return an error if no files were indexed
-        if indexed_files_count == 0 {
-            return Ok(format!(
-                "No text files suitable for indexing were found in the specified directory '{}'.\nFound files: {}, Skipped: {}\nSupported extensions: {:?}",
-                params.directory, found_files_count, skipped_files_count, binary_extensions
+// Modification time in seconds since the Unix epoch as an RFC 3339 timestamp in UTC; 0 stands
+// for an unknown time
+fn modified_timestamp(secs: u64) -> Option<String> {
+    if secs == 0 {
+        return None;
+    }
+    chrono::DateTime::from_timestamp(i64::try_from(secs).ok()?, 0)
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+// Modification time of an indexed document, from the stored field
+fn stored_modified(document: &TantivyDocument, fields: IndexFields) -> Option<String> {
+    document
+        .get_first(fields.modified)
+        .and_then(|v| v.as_u64())
+        .and_then(modified_timestamp)
+}
+
+// Modification time appended to the heading of a hit in text and HTML, empty when unknown
+fn modified_label(hit: &Hit) -> String {
+    hit.modified
+        .as_deref()
+        .map(|modified| format!(", Modified: {}", modified))
+        .unwrap_or_default()
+}
+
+// Score of a hit as shown in text and HTML, with the normalized score when there is one
+fn score_label(hit: &Hit) -> String {
+    match hit.normalized_score {
+        Some(normalized_score) => format!(
+            "Score: {:.2}, Normalized: {:.2}",
+            hit.score, normalized_score
+        ),
+        None => format!("Score: {:.2}", hit.score),
+    }
+}
+
+// Scale the scores of the hits so that the best one among them scores 1, the others in
+// proportion. Hits that all score 0 are equally good.
+fn normalize_scores(hits: &mut [Hit], best_score: f32) {
+    for hit in hits {
+        hit.normalized_score = Some(if best_score > 0.0 {
+            (hit.score / best_score).clamp(0.0, 1.0)
+        } else {
+            1.0
+        });
+    }
+}
+
+// Send a hit to the stream in the form it takes in the JSON result, normalized and highlighted
+// the same way
+fn stream_hit(on_hit: &HitCallback, hit: &Hit, params: &SearchParams, best_score: Option<f32>) {
+    let mut hit = hit.clone();
+    if params.normalize_scores.unwrap_or(false)
+        && let Some(best_score) = best_score
+    {
+        normalize_scores(std::slice::from_mut(&mut hit), best_score);
+    }
+    let (default_pre, default_post) = default_markers(OutputFormat::Json);
+    finish_json_snippets(
+        std::slice::from_mut(&mut hit),
+        (
+            params.highlight_pre.as_deref().unwrap_or(default_pre),
+            params.highlight_post.as_deref().unwrap_or(default_post),
+        ),
+        params.highlight_offsets.unwrap_or(false),
+    );
+    on_hit(&hit);
+}
+
+// Render hits as an HTML list with a link to every file, for clients that display rich text
+fn html_results(
+    heading: &str,
+    notes: &[String],
+    hits: &[Hit],
+    (pre, post): (&str, &str),
+    summary: &str,
+) -> String {
+    let mut html = format!("<p>{}</p>\n", html_escape(heading));
+    for note in notes {
+        html.push_str(&format!("<p>{}</p>\n", html_escape(note)));
+    }
+    html.push_str("<ul>\n");
+    for hit in hits {
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a> ({}, Matches: {}{})<br>\n<code>{}</code>",
+            html_escape(&file_uri(&hit.path)),
+            html_escape(&hit.path),
+            score_label(hit),
+            hit.match_count,
+            modified_label(hit),
+            html_snippet(&hit.snippet, &hit.highlighted, pre, post).replace('\n', " ")
+        ));
+        for (snippet, highlighted) in hit.more_snippets.iter().zip(&hit.more_highlighted) {
+            html.push_str(&format!(
+                "<br>\n<code>{}</code>",
+                html_snippet(snippet, highlighted, pre, post).replace('\n', " ")
+            ));
+        }
+        if !hit.matched_terms.is_empty() {
+            html.push_str(&format!(
+                "<br>\nTerms: {}",
+                html_escape(&hit.matched_terms.join(", "))
             ));
         }
+        if let Some(lines) = &hit.lines {
+            let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+            html.push_str(&format!("<br>\nLines: {}", lines.join(", ")));
+        }
+        if let Some(blocks) = &hit.context {
+            for block in blocks {
+                html.push_str("\n<pre>");
+                for line in block {
+                    let separator = if line.matched { ':' } else { '-' };
+                    html.push_str(&format!(
+                        "{}{} {}\n",
+                        line.line,
+                        separator,
+                        html_escape(&line.text)
+                    ));
+                }
+                html.push_str("</pre>");
+            }
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str(&format!("</ul>\n<pre>{}</pre>", html_escape(summary)));
+    html
+}
 
-        // 5. Commit the index
-        index_writer
-            .commit()
-            .map_err(|e| format!("Commit error: {}", e))?;
+// Insert the markers around the matched ranges of a snippet, passing the text between them
+// through the given function
+fn highlight_with(
+    snippet: &str,
+    highlighted: &[Range<usize>],
+    pre: &str,
+    post: &str,
+    text: impl Fn(&str) -> String,
+) -> String {
+    let highlighted = merge_ranges(highlighted);
+    let mut result =
+        String::with_capacity(snippet.len() + highlighted.len() * (pre.len() + post.len()));
+    let mut last = 0;
+    for range in highlighted {
+        result.push_str(&text(&snippet[last..range.start]));
+        result.push_str(pre);
+        result.push_str(&text(&snippet[range.start..range.end]));
+        result.push_str(post);
+        last = range.end;
+    }
+    result.push_str(&text(&snippet[last..]));
+    result
+}
 
-        // 6. Generate reader and searcher for searching
-        let reader = index.reader().map_err(|e| e.to_string())?;
-        let searcher = reader.searcher();
+// Scan the stored content of the candidate files with a regular expression, line by line and in
+// the requested order. Files without a matching line are dropped, and the scan stops once
+// `stop_after` matching files were found.
+fn regex_hits(
+    searcher: &Searcher,
+    candidates: &dyn Query,
+    regex: &Regex,
+    fields: IndexFields,
+    sort_by: SortBy,
+    line_options: LineOptions,
+    stop_after: Option<usize>,
+) -> Result<Vec<Hit>, SearchError> {
+    let num_docs = (searcher.num_docs() as usize).max(1);
+    let candidates = top_hits(searcher, candidates, TopDocs::with_limit(num_docs), sort_by)
+        .map_err(|e| SearchError::IndexRead(format!("Search error: {}", e)))?;
 
-        // 7. Parse query containing the keyword
-        let query_parser = QueryParser::for_index(&index, vec![content_field]);
+    let mut hits = Vec::new();
+    for (score, doc_address) in candidates {
+        if stop_after.is_some_and(|stop_after| hits.len() >= stop_after) {
+            break;
+        }
+        let retrieved_doc: TantivyDocument = searcher
+            .doc(doc_address)
+            .map_err(|e| SearchError::IndexRead(format!("Document read error: {}", e)))?;
+        let content = retrieved_doc
+            .get_first(fields.content)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
 
-        // Ensure the keyword is not empty
-        if params.keyword.trim().is_empty() {
-            return Err("Search keyword is empty. Please enter a valid keyword.".into());
+        // The first matching line becomes the snippet, with the matches highlighted
+        let mut lines = Vec::new();
+        let mut match_count = 0;
+        let mut first_line = None;
+        for (index, line) in content.lines().enumerate() {
+            let ranges: Vec<Range<usize>> = regex.find_iter(line).map(|m| m.range()).collect();
+            if ranges.is_empty() {
+                continue;
+            }
+            match_count += ranges.len();
+            if lines.len() < MAX_REPORTED_LINES {
+                lines.push(index + 1);
+            }
+            if first_line.is_none() {
+                first_line = Some(clip_line(line, &ranges, line_options.max_chars));
+            }
         }
+        let Some((snippet, highlighted)) = first_line else {
+            continue;
+        };
+
+        let path = retrieved_doc
+            .get_first(fields.path)
+            .and_then(|v| v.as_str())
+            .unwrap_or("Unknown path");
+        hits.push(Hit {
+            path: path.to_string(),
+            score,
+            snippet,
+            match_count,
+            highlighted: highlighted
+                .into_iter()
+                .filter(|range| !range.is_empty())
+                .collect(),
+            context: line_options.context.map(|context_lines| {
+                context_blocks(content, &lines, context_lines, line_options.max_chars)
+            }),
+            lines: Some(lines),
+            matched_terms: Vec::new(),
+            normalized_score: None,
+            modified: stored_modified(&retrieved_doc, fields),
+            highlights: None,
+            more_snippets: Vec::new(),
+            more_highlighted: Vec::new(),
+        });
+    }
+    Ok(hits)
+}
 
-        let query = query_parser
-            .parse_query(&params.keyword)
-            .map_err(|e| format!("Query parse error: {}", e))?;
+// An index built for one or more directories, ready to be searched
+#[derive(Clone)]
+pub struct BuiltIndex {
+    pub roots: Vec<PathBuf>,
+    pub index: Index,
+    // Reader shared by all searches of the index, so that segments are memory-mapped and
+    // loaded once instead of on every call
+    pub reader: IndexReader,
+    pub fields: IndexFields,
+    pub stats: IndexStats,
+    pub index_options: IndexOptions,
+    // Parameters the index was built with, to walk the directories again when updating it
+    pub params: IndexingParams,
+    // Whether indexing stopped at the deadline before all files were indexed
+    pub timed_out: bool,
+    // Fingerprint of the collected files when the index was built, to tell whether the
+    // directories changed since
+    pub fingerprint: u64,
+    // Modification time, size and handling of every collected file, telling an update which
+    // files changed since
+    pub files: FileRecords,
+}
 
-        // 8. Retrieve top 10 search results
-        let top_docs = searcher
-            .search(&query, &TopDocs::with_limit(10))
-            .map_err(|e| format!("Search error: {}", e))?;
+impl BuiltIndex {
+    // Human-readable list of the indexed directories
+    fn roots_display(&self) -> String {
+        root_names(&self.roots).join(", ")
+    }
 
-        // 9. Concatenate file paths from search results into a string
-        let mut result_str = String::new();
-        for (score, doc_address) in &top_docs {
-            let retrieved_doc: TantivyDocument =
-                searcher.doc(*doc_address).map_err(|e| e.to_string())?;
-            let path_value = retrieved_doc
-                .get_first(path_field)
-                .and_then(|v| v.as_str())
-                .unwrap_or("Unknown path");
-            result_str.push_str(&format!("Hit: {} (Score: {:.2})\n", path_value, score));
+    // Whether overlapping roots or followed links may have indexed a file under several paths
+    fn may_hold_duplicates(&self) -> bool {
+        self.roots.len() > 1 || self.params.follow_symlinks.unwrap_or(false)
+    }
+}
+
+// Paths of the roots as reported in search results
+fn root_names(roots: &[PathBuf]) -> Vec<String> {
+    roots
+        .iter()
+        .map(|root| root.display().to_string())
+        .collect()
+}
+
+// Combine the single directory and the directory list of the parameters
+fn collect_directories(directory: Option<&str>, directories: Option<&[String]>) -> Vec<String> {
+    directory
+        .map(str::to_string)
+        .into_iter()
+        .chain(directories.unwrap_or_default().iter().cloned())
+        .collect()
+}
+
+// Report indexing progress to the client as logging notifications. Must be created on the
+// async runtime; the callback itself may be invoked from any thread.
+fn progress_notifier(peer: Peer<RoleServer>) -> ProgressCallback {
+    let send = log_sender(peer);
+    Arc::new(move |processed, total| {
+        send(serde_json::json!({
+            "message": format!("Indexed {} of {} files", processed, total),
+            "processed": processed,
+            "total": total,
+        }))
+    })
+}
+
+// Send every hit to the client as a logging notification as soon as it is collected
+fn hit_notifier(peer: Peer<RoleServer>) -> HitCallback {
+    let send = log_sender(peer);
+    Arc::new(move |hit| {
+        send(serde_json::json!({
+            "message": format!("Hit: {} (Score: {:.2})", hit.path, hit.score),
+            "hit": hit,
+        }))
+    })
+}
+
+// Forward data to the client as logging notifications, in the order it was sent. Must be
+// created on the async runtime; the returned function may be called from any thread.
+fn log_sender(peer: Peer<RoleServer>) -> impl Fn(serde_json::Value) + Send + Sync + 'static {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(data) = receiver.recv().await {
+            let notification = LoggingMessageNotificationParam {
+                level: LoggingLevel::Info,
+                logger: Some("file-search-mcp".into()),
+                data,
+            };
+            if let Err(e) = peer.notify_logging_message(notification).await {
+                tracing::debug!("Failed to send notification: {}", e);
+            }
         }
+    });
+    move |data| {
+        // The forwarding task only stops once every sender is gone
+        let _ = sender.send(data);
+    }
+}
 
-        if result_str.is_empty() {
-            Ok(format!(
-                "No search results for keyword '{}'. Number of indexed files: {}",
-                params.keyword, indexed_files_count
-            ))
+// Validate the specified directories and resolve them to the roots to walk
+fn resolve_roots(directories: &[String]) -> Result<Vec<PathBuf>, SearchError> {
+    if directories.is_empty() {
+        return Err(SearchError::InvalidParams(
+            "At least one directory must be specified.".into(),
+        ));
+    }
+
+    // 1. Expand ~ and environment variables like a shell, then validate the directories
+    let mut roots = Vec::with_capacity(directories.len());
+    for requested in directories {
+        let directory = expand_path(requested).map_err(SearchError::InvalidParams)?;
+        // Name the expansion in errors, as it is what was actually looked up
+        let shown = if directory == *requested {
+            format!("'{}'", directory)
         } else {
-            Ok(format!(
-                "Search results ({} hits):\n{}",
-                top_docs.len(),
-                result_str
-            ))
+            format!("'{}' (expanded from '{}')", directory, requested)
+        };
+        let dir_path = Path::new(&directory);
+        if !dir_path.exists() {
+            return Err(SearchError::NotFound(format!(
+                "The specified directory {} does not exist",
+                shown
+            )));
+        }
+        if !dir_path.is_dir() {
+            return Err(SearchError::NotADirectory(format!(
+                "The specified path {} is not a directory",
+                shown
+            )));
         }
+
+        // Roots are canonicalized so that every hit has an absolute path that doesn't depend
+        // on the working directory of the server, and cached indexes are keyed by them
+        let root = dir_path.canonicalize().map_err(|e| {
+            SearchError::Io(format!("Path resolution error '{}': {}", directory, e))
+        })?;
+        roots.push(root);
     }
+    Ok(roots)
 }
 
-#[tool(tool_box)]
-impl ServerHandler for SearchTool {
-    fn get_info(&self) -> ServerInfo {
-        ServerInfo {
-            protocol_version: ProtocolVersion::V_2024_11_05,
-            capabilities: ServerCapabilities::builder()
-                .enable_prompts()
-                .enable_resources()
-                .enable_tools()
-                .build(),
-            server_info: Implementation::from_build_env(),
+// Parse an RFC 3339 timestamp such as 2024-05-01T12:00:00Z or 2024-05-01T12:00:00+09:00
+fn parse_timestamp(timestamp: &str) -> Result<SystemTime, SearchError> {
+    chrono::DateTime::parse_from_rfc3339(timestamp.trim())
+        .map(SystemTime::from)
+        .map_err(|e| {
+            SearchError::QueryParse(format!(
+                "Invalid timestamp '{}': {}. Use RFC 3339, e.g. 2024-05-01T12:00:00Z",
+                timestamp, e
+            ))
+        })
+}
+
+// Collect the files in the roots, honoring the walk options of the parameters
+fn collect_files(
+    roots: &[PathBuf],
+    params: &IndexingParams,
+    deadline: Option<Instant>,
+    cancel: Option<&CancellationToken>,
+) -> Result<CollectedFiles, SearchError> {
+    let mut exclude_dirs: HashSet<String> = params.exclude_dirs.iter().flatten().cloned().collect();
+    if params.default_exclude_dirs.unwrap_or(true) {
+        exclude_dirs.extend(DEFAULT_EXCLUDE_DIRS.iter().map(|name| name.to_string()));
+    }
+    let walk_options = WalkOptions {
+        max_depth: params.max_depth,
+        respect_gitignore: params.respect_gitignore.unwrap_or(true),
+        include_globs: params
+            .include_globs
+            .as_deref()
+            .map(build_glob_set)
+            .transpose()
+            .map_err(SearchError::InvalidParams)?,
+        exclude_globs: params
+            .exclude_globs
+            .as_deref()
+            .map(build_glob_set)
+            .transpose()
+            .map_err(SearchError::InvalidParams)?,
+        follow_symlinks: params.follow_symlinks.unwrap_or(false),
+        include_hidden: params.include_hidden.unwrap_or(false),
+        extensions: params.extensions.as_deref().map(build_extension_set),
+        exclude_dirs,
+        modified_after: params
+            .modified_after
+            .as_deref()
+            .map(parse_timestamp)
+            .transpose()?,
+        deadline,
+        cancel: cancel.cloned(),
+        max_files: None,
+    };
+    // Unless confirmed, the walk stops just beyond the threshold, which keeps the count quick
+    let threshold = Some(
+        params
+            .large_directory_threshold
+            .unwrap_or(DEFAULT_LARGE_DIRECTORY_THRESHOLD),
+    )
+    .filter(|threshold| *threshold > 0 && !params.confirm_large.unwrap_or(false));
+    let mut collected = CollectedFiles::default();
+    for root in roots {
+        tracing::info!("Target directory for indexing: {}", root.display());
+        let walk_options = WalkOptions {
+            max_files: threshold.map(|threshold| threshold.saturating_sub(collected.files.len())),
+            ..walk_options.clone()
+        };
+        let mut root_files = process_directory(root, &walk_options).map_err(SearchError::Io)?;
+        if let Some(threshold) = threshold
+            && root_files.over_max_files
+        {
+            return Err(SearchError::ConfirmationRequired(format!(
+                "The directories to index hold more than {} files (the count passed it in '{}'), so indexing them may take minutes. Narrow the search with include_globs, extensions, exclude_dirs or max_depth, or pass confirm_large: true to index them anyway.",
+                threshold,
+                root.display()
+            )));
+        }
+        if params.git_tracked_only.unwrap_or(false) {
+            match git_tracked_files(root) {
+                Some(tracked) => {
+                    let found = root_files.files.len();
+                    root_files.files.retain(|path| tracked.contains(path));
+                    tracing::info!(
+                        "Skipped {} files not tracked by git",
+                        found - root_files.files.len()
+                    );
+                }
+                None => tracing::warn!(
+                    "{} is not inside a git work tree, indexing untracked files too",
+                    root.display()
+                ),
+            }
+        }
+        collected.files.extend(root_files.files);
+        collected.skipped_symlinks += root_files.skipped_symlinks;
+        collected.permission_errors += root_files.permission_errors;
+        collected.timed_out |= root_files.timed_out;
+    }
+    Ok(collected)
+}
+
+// Options baked into the index, resolved from the parameters
+fn index_options(params: &IndexingParams) -> Result<IndexOptions, SearchError> {
+    let index_pdf = params.index_pdf.unwrap_or(false);
+    if index_pdf && !pdf::SUPPORTED {
+        return Err(SearchError::InvalidParams(
+            "index_pdf requires a build of the server with the pdf feature.".into(),
+        ));
+    }
+    if params.content_head_bytes == Some(0) {
+        return Err(SearchError::InvalidParams(
+            "content_head_bytes must be at least 1.".into(),
+        ));
+    }
+    Ok(IndexOptions {
+        max_file_size_bytes: params
+            .max_file_size_bytes
+            .unwrap_or(DEFAULT_MAX_FILE_SIZE_BYTES),
+        case_sensitive: params.case_sensitive.unwrap_or(false),
+        segmentation: params.segmentation.unwrap_or_default(),
+        // Recall mode stems the content field and adds an unstemmed copy of it
+        stemming: params.stemming.unwrap_or(false) || params.recall_mode.unwrap_or(false),
+        recall_mode: params.recall_mode.unwrap_or(false),
+        phonetic: params.phonetic.unwrap_or(false),
+        code_tokenizer: params.code_tokenizer.unwrap_or(false),
+        // Store the canonical name so that equivalent labels share a cached index
+        fallback_encoding: params
+            .fallback_encoding
+            .as_deref()
+            .map(|label| encoding_for_label(label).map(|encoding| encoding.name().to_string()))
+            .transpose()
+            .map_err(SearchError::InvalidParams)?,
+        index_archives: params.index_archives.unwrap_or(false),
+        index_gzip: params.index_gzip.unwrap_or(false),
+        jsonl_mode: params.jsonl_mode.unwrap_or(false),
+        index_pdf,
+        store_content: params.store_content.unwrap_or(true),
+        content_head_bytes: params.content_head_bytes,
+        binary_extensions: binary_extensions(params),
+        text_detection: text_detection(params)?,
+    })
+}
+
+// Thresholds of the binary detection, each a share between 0 and 1
+fn text_detection(params: &IndexingParams) -> Result<TextDetection, SearchError> {
+    let ratio = |name: &str, value: Option<f32>, default: f32| match value {
+        Some(value) if !(0.0..=1.0).contains(&value) => Err(SearchError::InvalidParams(format!(
+            "{} must be between 0 and 1.",
+            name
+        ))),
+        Some(value) => Ok(value),
+        None => Ok(default),
+    };
+    Ok(TextDetection {
+        max_control_ratio: ratio(
+            "max_control_ratio",
+            params.max_control_ratio,
+            DEFAULT_MAX_CONTROL_RATIO,
+        )?,
+        min_ascii_ratio: ratio(
+            "min_ascii_ratio",
+            params.min_ascii_ratio,
+            DEFAULT_MIN_ASCII_RATIO,
+        )?,
+    })
+}
+
+// The built-in binary extensions plus the extra ones, minus the ones to treat as text, which
+// win over both
+fn binary_extensions(params: &IndexingParams) -> BTreeSet<String> {
+    let mut extensions = default_binary_extensions();
+    if let Some(extra) = &params.extra_binary_extensions {
+        extensions.extend(build_extension_set(extra));
+    }
+    if let Some(text) = &params.treat_as_text_extensions {
+        for ext in build_extension_set(text) {
+            extensions.remove(&ext);
+        }
+    }
+    // PDF documents are read by the text extractor instead
+    if params.index_pdf.unwrap_or(false) {
+        extensions.remove("pdf");
+    }
+    extensions
+}
+
+// Writer options resolved from the parameters
+fn writer_options(
+    params: &IndexingParams,
+    deadline: Option<Instant>,
+    cancel: Option<&CancellationToken>,
+    progress: Option<ProgressCallback>,
+) -> Result<WriterOptions, SearchError> {
+    if let Some(buffer_bytes) = params.writer_buffer_bytes
+        && buffer_bytes < MIN_WRITER_BUFFER_BYTES
+    {
+        return Err(SearchError::InvalidParams(format!(
+            "writer_buffer_bytes must be at least {} bytes, got {}.",
+            MIN_WRITER_BUFFER_BYTES, buffer_bytes
+        )));
+    }
+    let retries = params.read_retries.unwrap_or(DEFAULT_READ_RETRIES);
+    if retries > MAX_READ_RETRIES {
+        return Err(SearchError::InvalidParams(format!(
+            "read_retries must be at most {}, got {}.",
+            MAX_READ_RETRIES, retries
+        )));
+    }
+    Ok(WriterOptions {
+        threads: params.threads,
+        buffer_bytes: params.writer_buffer_bytes,
+        deadline,
+        cancel: cancel.cloned(),
+        max_total_bytes: params.max_total_bytes,
+        read_retry: RetryPolicy {
+            retries,
+            backoff: Duration::from_millis(
+                params.retry_backoff_ms.unwrap_or(DEFAULT_RETRY_BACKOFF_MS),
+            ),
+        },
+        progress,
+    })
+}
+
+// Open the reader shared by the searches of an index
+fn open_reader(index: &Index, mode: ReloadMode) -> Result<IndexReader, SearchError> {
+    index
+        .reader_builder()
+        .reload_policy(mode.into())
+        .try_into()
+        .map_err(|e| SearchError::IndexRead(format!("Index reader error: {}", e)))
+}
+
+// Fingerprint of the collected files from their paths, sizes and modification times, which
+// changes whenever a file is added, removed, renamed or modified
+fn files_fingerprint(files: &[PathBuf]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for path in files {
+        path.hash(&mut hasher);
+        if let Ok(metadata) = fs::metadata(path) {
+            metadata.len().hash(&mut hasher);
+            metadata.modified().ok().hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+// Walk the directories and build a single index, reusing the on-disk cache when requested
+fn build_index(
+    directories: &[String],
+    params: &IndexingParams,
+    deadline: Option<Instant>,
+    cancel: Option<&CancellationToken>,
+    progress: Option<ProgressCallback>,
+) -> Result<BuiltIndex, SearchError> {
+    // 1-2. Validate the specified directories and collect their files
+    let roots = resolve_roots(directories)?;
+    let collected = collect_files(&roots, params, deadline, cancel)?;
+    let fingerprint = files_fingerprint(&collected.files);
+    index_collected(
+        roots,
+        collected,
+        fingerprint,
+        params,
+        deadline,
+        cancel,
+        progress,
+    )
+}
+
+// Build the index of files already collected from the roots, whose fingerprint is known
+fn index_collected(
+    roots: Vec<PathBuf>,
+    collected: CollectedFiles,
+    fingerprint: u64,
+    params: &IndexingParams,
+    deadline: Option<Instant>,
+    cancel: Option<&CancellationToken>,
+    progress: Option<ProgressCallback>,
+) -> Result<BuiltIndex, SearchError> {
+    let CollectedFiles {
+        files,
+        skipped_symlinks,
+        permission_errors,
+        timed_out: walk_timed_out,
+        ..
+    } = collected;
+
+    // 3. Reuse the cached on-disk index when it is fresh, otherwise build the index
+    let index_options = index_options(params)?;
+    let writer_options = writer_options(params, deadline, cancel, progress)?;
+    let (index, fields, records) = match &params.index_cache_dir {
+        Some(cache_dir) => index_cache::open_or_build(
+            Path::new(cache_dir),
+            &roots,
+            &files,
+            !walk_timed_out,
+            &index_options,
+            &writer_options,
+        )
+        .map_err(SearchError::IndexWrite)?,
+        None => {
+            let (schema, fields) = build_schema(&index_options);
+            let index = Index::create_in_ram(schema);
+            register_tokenizers(&index, &index_options);
+            let records = write_index(&index, &files, fields, &index_options, &writer_options)
+                .map_err(SearchError::IndexWrite)?;
+            (index, fields, records)
+        }
+    };
+
+    let mut stats = IndexStats::from_records(&records);
+    stats.skipped_symlinks = skipped_symlinks;
+    stats.permission_errors = permission_errors;
+    tracing::info!("Processing complete: {}", stats.summary());
+
+    // Files left unread at the deadline are missing from the counters
+    let timed_out = walk_timed_out || stats.found_files < files.len();
+    if timed_out {
+        tracing::warn!(
+            "Indexing timed out after reading {} of {} collected files",
+            stats.found_files,
+            files.len()
+        );
+    }
+
+    Ok(BuiltIndex {
+        fingerprint,
+        roots,
+        reader: open_reader(&index, params.reload_policy.unwrap_or_default())?,
+        index,
+        fields,
+        stats,
+        index_options,
+        params: params.clone(),
+        timed_out,
+        files: records,
+    })
+}
+
+// Path and query of the document indexed by the self-test
+const SELF_TEST_PATH: &str = "self-test/known-document.txt";
+const SELF_TEST_QUERY: &str = "lazy fox";
+
+// Index a known document in an in-memory index and check that a known query finds it
+fn run_self_test() -> Result<(), String> {
+    let options = IndexOptions::default();
+    let (schema, fields) = build_schema(&options);
+    let index = Index::create_in_ram(schema);
+    register_tokenizers(&index, &options);
+
+    let writer_options = WriterOptions {
+        buffer_bytes: Some(MIN_WRITER_BUFFER_BYTES),
+        ..WriterOptions::default()
+    };
+    let mut index_writer = create_writer(&index, &writer_options)?;
+    index_writer
+        .add_document(tantivy::doc!(
+            fields.path => SELF_TEST_PATH,
+            fields.filename => "known-document.txt",
+            fields.content => "The quick brown fox jumps over the lazy dog.",
+            fields.modified => 0u64,
+            fields.size => 44u64,
+            fields.extension => "txt",
+        ))
+        .map_err(|e| format!("Document addition error: {}", e))?;
+    index_writer
+        .commit()
+        .map_err(|e| format!("Commit error: {}", e))?;
+
+    let reader = index.reader().map_err(|e| e.to_string())?;
+    let searcher = reader.searcher();
+    let query = QueryParser::for_index(&index, vec![fields.content, fields.filename])
+        .parse_query(SELF_TEST_QUERY)
+        .map_err(|e| format!("Query parse error: {}", e))?;
+    let top_docs = searcher
+        .search(&query, &TopDocs::with_limit(1))
+        .map_err(|e| format!("Search error: {}", e))?;
+    let Some((_, doc_address)) = top_docs.first() else {
+        return Err(format!(
+            "The query '{}' did not find the known document",
+            SELF_TEST_QUERY
+        ));
+    };
+    let document: TantivyDocument = searcher.doc(*doc_address).map_err(|e| e.to_string())?;
+    match document.get_first(fields.path).and_then(|v| v.as_str()) {
+        Some(SELF_TEST_PATH) => Ok(()),
+        path => Err(format!(
+            "The query '{}' found an unexpected document: {:?}",
+            SELF_TEST_QUERY, path
+        )),
+    }
+}
+
+// Walk and classify the files of the directories without indexing them, listing the files that
+// would be indexed and the reason each other file would be skipped
+fn dry_run(
+    directories: &[String],
+    params: &IndexingParams,
+    format: OutputFormat,
+    cancel: Option<&CancellationToken>,
+) -> Result<String, SearchError> {
+    let roots = resolve_roots(directories)?;
+    let collected = collect_files(&roots, params, None, cancel)?;
+    let kinds = classify_files(
+        &collected.files,
+        &index_options(params)?,
+        &writer_options(params, None, cancel, None)?,
+    )
+    .map_err(SearchError::Internal)?;
+    let mut stats = IndexStats::from_kinds(kinds.iter().map(|(_, kind)| *kind));
+    stats.skipped_symlinks = collected.skipped_symlinks;
+    stats.permission_errors = collected.permission_errors;
+
+    let (indexed, skipped): (Vec<_>, Vec<_>) = kinds
+        .into_iter()
+        .partition(|(_, kind)| *kind == FileKind::Indexed);
+    if format == OutputFormat::Json {
+        let results = DryRunResults {
+            files: indexed
+                .iter()
+                .map(|(path, _)| path.to_string_lossy().to_string())
+                .collect(),
+            skipped: skipped
+                .iter()
+                .map(|(path, kind)| SkippedFile {
+                    path: path.to_string_lossy().to_string(),
+                    reason: *kind,
+                })
+                .collect(),
+            stats,
+        };
+        return to_pretty_json(&results);
+    }
+
+    let mut result_str = format!(
+        "Dry run for '{}': {} files would be indexed\n",
+        roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        indexed.len()
+    );
+    for (path, _) in &indexed {
+        result_str.push_str(&format!("  {}\n", path.display()));
+    }
+    if !skipped.is_empty() {
+        result_str.push_str("Skipped files:\n");
+        for (path, kind) in &skipped {
+            result_str.push_str(&format!("  {} ({})\n", path.display(), kind.reason()));
+        }
+    }
+    result_str.push_str(&stats.summary());
+    Ok(result_str)
+}
+
+// Replace preview parameters: where to look, and what to replace with what
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct ReplacePreviewParams {
+    #[schemars(description = "Path to the directory whose files to preview the replacement in")]
+    pub directory: Option<String>,
+    #[schemars(description = "Additional directories to include")]
+    pub directories: Option<Vec<String>>,
+    #[schemars(
+        description = "Id of an index returned by the index tool, to reuse its content instead of indexing the directories"
+    )]
+    pub index_id: Option<String>,
+    #[schemars(description = "Text to replace; matched literally unless regex is set")]
+    pub search: String,
+    #[schemars(description = "Replacement text; with regex, $1 or ${name} insert capture groups")]
+    pub replacement: String,
+    #[schemars(
+        description = "Treat search as a regular expression, matched line by line (default: false)"
+    )]
+    pub regex: Option<bool>,
+    #[schemars(description = "Match the search text case-insensitively (default: false)")]
+    pub ignore_case: Option<bool>,
+    #[schemars(description = "Maximum number of files whose diff is shown (default: 10)")]
+    pub limit: Option<usize>,
+    #[serde(flatten)]
+    pub indexing: IndexingParams,
+}
+
+// Stats parameters: the directories or index to describe
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct StatsParams {
+    #[schemars(description = "Path to the directory to index and describe")]
+    pub directory: Option<String>,
+    #[schemars(description = "Additional directories to index together with it")]
+    pub directories: Option<Vec<String>>,
+    #[schemars(
+        description = "Id of an index returned by the index tool, to describe it instead of indexing the directories"
+    )]
+    pub index_id: Option<String>,
+    #[serde(flatten)]
+    pub indexing: IndexingParams,
+}
+
+// File content parameters: file path
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct FileContentParams {
+    #[schemars(description = "Path to the file to read")]
+    pub file_path: String,
+}
+
+// Search history parameters: how many of the recent searches to return
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct HistoryParams {
+    #[schemars(
+        description = "Maximum number of searches to return, newest first (default: all kept, at most 100)"
+    )]
+    pub limit: Option<usize>,
+}
+
+// Number of recent searches kept for the search history tool
+const SEARCH_HISTORY_CAPACITY: usize = 100;
+
+// Watch parameters: the index to keep up to date, or to stop watching
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct WatchParams {
+    #[schemars(description = "Id of an index returned by the index tool")]
+    pub index_id: String,
+    #[schemars(
+        description = "Milliseconds without further changes to wait before updating the index, so that a burst of changes causes a single update (default: 500)"
+    )]
+    pub debounce_ms: Option<u64>,
+    #[schemars(description = "Stop watching the index instead (default: false)")]
+    pub stop: Option<bool>,
+}
+
+// Time the files of a watched index have to stay unchanged before it is updated
+const DEFAULT_WATCH_DEBOUNCE_MS: u64 = 500;
+
+// A search recorded in the history
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HistoryEntry {
+    pub directories: Vec<String>,
+    // Id of the prebuilt index that was searched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_id: Option<String>,
+    pub keyword: String,
+    // Number of matching files, 0 or 1 for a yes/no question, or of duplicate files
+    pub total_matches: usize,
+    // When the search finished, as an RFC 3339 timestamp in UTC
+    pub timestamp: String,
+}
+
+// Main tool struct
+#[derive(Clone)]
+pub struct SearchTool {
+    // Indexes built with the index tool, keyed by their id
+    indexes: Arc<Mutex<HashMap<String, Arc<BuiltIndex>>>>,
+    // Counter used to generate index ids
+    next_index_id: Arc<AtomicU64>,
+    // Slots for searches and indexing runs; requests beyond them wait for a free one
+    slots: Arc<Semaphore>,
+    // Number of slots, all of which are taken back at shutdown
+    slot_count: u32,
+    // Indexes recently built for searches, keyed by their roots, so that repeated searches of
+    // an unchanged directory skip indexing; None when the cache is disabled
+    recent_indexes: Option<Arc<Mutex<LruCache<Vec<PathBuf>, Arc<BuiltIndex>>>>>,
+    // Directory searched when a request names neither a directory nor an index
+    default_directory: Option<String>,
+    // The most recent searches, oldest first, for the search history tool
+    history: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    // Watchers of the resources clients subscribed to, keyed by their URI
+    subscriptions: Arc<Mutex<HashMap<String, Watcher>>>,
+    // Watchers of the indexes kept up to date with the watch tool, keyed by their id
+    watches: Arc<Mutex<HashMap<String, Watcher>>>,
+    // Cancelled when the request served by this copy of the tool is dropped, which stops its
+    // walks and indexing runs; None outside of a request
+    cancel: Option<CancellationToken>,
+}
+
+// Time a subscribed file has to stay unchanged before its subscribers are notified
+const RESOURCE_DEBOUNCE: Duration = Duration::from_millis(200);
+
+// URI under which an indexed file is exposed as an MCP resource
+fn file_uri(path: &str) -> String {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+    format!("file://{}", absolute.display())
+}
+
+impl SearchTool {
+    // Snapshot of all indexes built with the index tool
+    fn registered_indexes(&self) -> Result<Vec<Arc<BuiltIndex>>, SearchError> {
+        Ok(self.index_registry()?.values().cloned().collect())
+    }
+
+    // Find an index built with the index tool
+    fn lookup_index(&self, index_id: &str) -> Result<Arc<BuiltIndex>, SearchError> {
+        self.index_registry()?
+            .get(index_id)
+            .cloned()
+            .ok_or_else(|| {
+                SearchError::UnknownIndex(format!(
+                    "Unknown index id '{}'. Build an index with the index tool first.",
+                    index_id
+                ))
+            })
+    }
+
+    // Reuse the index recently built for the same directories and parameters when none of the
+    // files changed since, otherwise build it and remember it. The directories are walked
+    // either way; only reading and indexing the files is saved.
+    fn recent_index(
+        &self,
+        directories: &[String],
+        params: &IndexingParams,
+        deadline: Option<Instant>,
+        progress: Option<ProgressCallback>,
+    ) -> Result<Arc<BuiltIndex>, SearchError> {
+        let cancel = self.cancel.as_ref();
+        let Some(recent_indexes) = &self.recent_indexes else {
+            return Ok(Arc::new(build_index(
+                directories,
+                params,
+                deadline,
+                cancel,
+                progress,
+            )?));
+        };
+        let lock = || {
+            recent_indexes
+                .lock()
+                .map_err(|_| SearchError::Internal("Index cache is unavailable".into()))
+        };
+
+        let roots = resolve_roots(directories)?;
+        let collected = collect_files(&roots, params, deadline, cancel)?;
+        let fingerprint = files_fingerprint(&collected.files);
+        if let Some(built) = lock()?.get(&roots)
+            && built.fingerprint == fingerprint
+            && built.params.affecting_contents() == params.affecting_contents()
+        {
+            tracing::info!("Reusing the index built for {}", built.roots_display());
+            return Ok(built.clone());
+        }
+
+        // The files were just collected, so they are indexed without walking the roots again
+        let built = Arc::new(index_collected(
+            roots.clone(),
+            collected,
+            fingerprint,
+            params,
+            deadline,
+            cancel,
+            progress,
+        )?);
+        // A partial index must not stand in for the complete one
+        if !built.timed_out {
+            lock()?.put(roots, built.clone());
+        }
+        Ok(built)
+    }
+
+    // The recent index of the directories, built off the async runtime. Dropping the request
+    // stops the walk and the reading of files.
+    async fn recent_index_until_dropped(
+        &self,
+        directories: Vec<String>,
+        params: IndexingParams,
+    ) -> Result<Arc<BuiltIndex>, SearchError> {
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let tool = self.clone().with_cancellation(cancel);
+        tokio::task::spawn_blocking(move || tool.recent_index(&directories, &params, None, None))
+            .await
+            .map_err(|e| SearchError::Internal(format!("Indexing task failed: {}", e)))?
+    }
+
+    // Lock the indexes built with the index tool
+    fn index_registry(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<String, Arc<BuiltIndex>>>, SearchError> {
+        self.indexes
+            .lock()
+            .map_err(|_| SearchError::Internal("Index registry is unavailable".into()))
+    }
+
+    // Remember a search, forgetting the oldest one once the history is full
+    fn record_search(&self, roots: &[PathBuf], params: &SearchParams, total_matches: usize) {
+        let entry = HistoryEntry {
+            directories: root_names(roots),
+            index_id: params.index_id.clone(),
+            keyword: params.keyword.clone(),
+            total_matches,
+            timestamp: chrono::DateTime::<chrono::Utc>::from(SystemTime::now())
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        };
+        if let Ok(mut history) = self.history.lock() {
+            if history.len() == SEARCH_HISTORY_CAPACITY {
+                history.pop_front();
+            }
+            history.push_back(entry);
+        }
+    }
+
+    // The file on disk behind a resource of an index built with the index tool. Entries of
+    // archives and records of JSON lines files change with the file that contains them.
+    fn resource_file(&self, uri: &str) -> Result<PathBuf, SearchError> {
+        for built in self.registered_indexes()? {
+            let paths = indexed_paths(&built.reader.searcher()).map_err(SearchError::IndexRead)?;
+            if let Some(path) = paths.iter().find(|path| file_uri(path) == uri) {
+                let file = json_lines::container_path(archives::container_path(path));
+                return Ok(PathBuf::from(file));
+            }
+        }
+        Err(SearchError::NotFound(format!(
+            "No indexed file has the URI '{}'",
+            uri
+        )))
+    }
+
+    fn subscription_registry(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<String, Watcher>>, SearchError> {
+        self.subscriptions
+            .lock()
+            .map_err(|_| SearchError::Internal("Resource subscriptions are unavailable".into()))
+    }
+
+    // Watch the file behind a resource and notify the client whenever it changes. The directory
+    // of the file is watched rather than the file itself, so that editors replacing the file on
+    // save don't end the subscription.
+    fn subscribe_resource(&self, uri: String, peer: Peer<RoleServer>) -> Result<(), SearchError> {
+        if !watcher::SUPPORTED {
+            return Err(SearchError::InvalidParams(
+                "Resource subscriptions are not supported by this build, enable the watch feature"
+                    .into(),
+            ));
+        }
+        let file = self.resource_file(&uri)?;
+        // Changes are reported under canonical paths, whatever links lead to the file
+        let file = fs::canonicalize(&file).map_err(|e| {
+            SearchError::Io(format!("Error resolving file '{}': {}", file.display(), e))
+        })?;
+        let directory = file.parent().unwrap_or(Path::new("/")).to_path_buf();
+        let runtime = tokio::runtime::Handle::current();
+        let notified_uri = uri.clone();
+        let watcher = watcher::watch(&[directory], false, RESOURCE_DEBOUNCE, move |changed| {
+            // A replaced file may be gone by now and resolve to nothing, so the reported path
+            // itself counts too
+            let changed_file = changed
+                .iter()
+                .any(|path| *path == file || fs::canonicalize(path).is_ok_and(|path| path == file));
+            if !changed_file {
+                return;
+            }
+            let peer = peer.clone();
+            let uri = notified_uri.clone();
+            runtime.spawn(async move {
+                let notification = ResourceUpdatedNotificationParam { uri };
+                if let Err(e) = peer.notify_resource_updated(notification).await {
+                    tracing::warn!("Failed to notify a resource update: {}", e);
+                }
+            });
+        })
+        .map_err(SearchError::Io)?;
+        self.subscription_registry()?.insert(uri, watcher);
+        Ok(())
+    }
+
+    fn watch_registry(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<String, Watcher>>, SearchError> {
+        self.watches
+            .lock()
+            .map_err(|_| SearchError::Internal("Index watchers are unavailable".into()))
+    }
+
+    // Start or stop updating an index whenever files in its directories change. Each update is
+    // the one of the update tool, which removes the documents of the changed and deleted files
+    // and indexes the changed and added ones again, and waits for a free slot like any request.
+    fn run_watch(&self, params: &WatchParams) -> Result<String, SearchError> {
+        if params.stop.unwrap_or(false) {
+            let message = match self.watch_registry()?.remove(&params.index_id) {
+                Some(_) => format!("Stopped watching index '{}'.", params.index_id),
+                None => format!("Index '{}' was not being watched.", params.index_id),
+            };
+            return Ok(message);
+        }
+        if !watcher::SUPPORTED {
+            return Err(SearchError::InvalidParams(
+                "Watching is not supported by this build, enable the watch feature".into(),
+            ));
+        }
+        let debounce_ms = params.debounce_ms.unwrap_or(DEFAULT_WATCH_DEBOUNCE_MS);
+        if debounce_ms == 0 {
+            return Err(SearchError::InvalidParams(
+                "debounce_ms must be greater than 0".into(),
+            ));
+        }
+        let built = self.lookup_index(&params.index_id)?;
+
+        // Writing a cache directory inside a watched directory must not trigger another update
+        let cache_dir = built
+            .params
+            .index_cache_dir
+            .as_ref()
+            .map(|cache_dir| fs::canonicalize(cache_dir).unwrap_or_else(|_| cache_dir.into()));
+        // The tool updating the index must not own the watcher, or neither would ever be freed
+        let tool = SearchTool {
+            watches: Arc::default(),
+            ..self.clone()
+        };
+        let index_id = params.index_id.clone();
+        let runtime = tokio::runtime::Handle::current();
+        let watcher = watcher::watch(
+            &built.roots,
+            true,
+            Duration::from_millis(debounce_ms),
+            move |changed| {
+                let in_cache =
+                    |path: &PathBuf| cache_dir.as_ref().is_some_and(|dir| path.starts_with(dir));
+                if changed.iter().all(in_cache) {
+                    return;
+                }
+                let Ok(_slot) = runtime.block_on(tool.acquire_slot()) else {
+                    return;
+                };
+                match tool.run_update(&index_id, None) {
+                    Ok(message) => tracing::info!("{}", message),
+                    Err(e) => {
+                        tracing::warn!("Failed to update watched index '{}': {}", index_id, e)
+                    }
+                }
+            },
+        )
+        .map_err(SearchError::Io)?;
+        self.watch_registry()?
+            .insert(params.index_id.clone(), watcher);
+        Ok(format!(
+            "Watching '{}'. Index '{}' is updated in the background whenever files change.",
+            built.roots_display(),
+            params.index_id
+        ))
+    }
+
+    // The most recent searches, newest first
+    fn recent_searches(&self, limit: usize) -> Result<Vec<HistoryEntry>, SearchError> {
+        let history = self
+            .history
+            .lock()
+            .map_err(|_| SearchError::Internal("Search history is unavailable".into()))?;
+        Ok(history.iter().rev().take(limit).cloned().collect())
+    }
+
+    // Hash the text files of the directories, or of the directories of an index, and report
+    // the groups of files with identical content
+    fn find_duplicates(
+        &self,
+        params: &SearchParams,
+        limit: usize,
+        offset: usize,
+    ) -> Result<String, SearchError> {
+        let (roots, indexing) = match &params.index_id {
+            Some(index_id) => {
+                let built = self.lookup_index(index_id)?;
+                (built.roots.clone(), built.params.clone())
+            }
+            None => {
+                let directories = self
+                    .target_directories(params.directory.as_deref(), params.directories.as_deref());
+                if directories.is_empty() {
+                    return Err(SearchError::InvalidParams(NO_DIRECTORY_MESSAGE.into()));
+                }
+                (resolve_roots(&directories)?, params.indexing.clone())
+            }
+        };
+        let deadline = params
+            .timeout_ms
+            .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+        let mut collected = collect_files(&roots, &indexing, deadline, self.cancel.as_ref())?;
+        // A file reached under several paths would otherwise be its own duplicate
+        if roots.len() > 1 || indexing.follow_symlinks.unwrap_or(false) {
+            let mut seen = HashSet::new();
+            collected
+                .files
+                .retain(|path| seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())));
+        }
+        let hashes = hash_files(
+            &collected.files,
+            &index_options(&indexing)?,
+            &writer_options(&indexing, None, None, None)?,
+        )
+        .map_err(SearchError::Internal)?;
+        let hashed_files = hashes.len();
+
+        let mut by_hash: HashMap<blake3::Hash, (u64, Vec<String>)> = HashMap::new();
+        for (path, bytes, hash) in hashes {
+            by_hash
+                .entry(hash)
+                .or_insert_with(|| (bytes, Vec::new()))
+                .1
+                .push(path.to_string_lossy().to_string());
+        }
+        let mut groups: Vec<DuplicateGroup> = by_hash
+            .into_iter()
+            .filter(|(_, (_, files))| files.len() > 1)
+            .map(|(hash, (bytes, mut files))| {
+                files.sort();
+                DuplicateGroup {
+                    hash: hash.to_hex().to_string(),
+                    bytes,
+                    files,
+                }
+            })
+            .collect();
+        groups.sort_by(|a, b| {
+            b.wasted_bytes()
+                .cmp(&a.wasted_bytes())
+                .then_with(|| a.files.cmp(&b.files))
+        });
+        let total_groups = groups.len();
+        let duplicate_files = groups.iter().map(|group| group.files.len()).sum();
+        self.record_search(&roots, params, duplicate_files);
+        let wasted_bytes = groups.iter().map(DuplicateGroup::wasted_bytes).sum();
+        let groups: Vec<DuplicateGroup> = groups.into_iter().skip(offset).take(limit).collect();
+
+        if params.format.unwrap_or_default() == OutputFormat::Json {
+            return to_pretty_json(&DuplicateResults {
+                roots: root_names(&roots),
+                groups,
+                total_groups,
+                duplicate_files,
+                wasted_bytes,
+                hashed_files,
+                offset,
+                timed_out: collected.timed_out,
+            });
+        }
+
+        let roots_display = root_names(&roots).join(", ");
+        let mut result_str = if total_groups == 0 {
+            format!("No duplicate files found in '{}'\n", roots_display)
+        } else {
+            format!(
+                "Found {} groups of duplicate files in '{}': {} files, {} bytes in redundant copies\n",
+                total_groups, roots_display, duplicate_files, wasted_bytes
+            )
+        };
+        for (i, group) in groups.iter().enumerate() {
+            result_str.push_str(&format!(
+                "Group {}: {} files of {} bytes (blake3 {})\n",
+                offset + i + 1,
+                group.files.len(),
+                group.bytes,
+                group.hash
+            ));
+            for file in &group.files {
+                result_str.push_str(&format!("  {}\n", file));
+            }
+        }
+        result_str.push_str(&format!(
+            "Hashed files: {}, Found files: {}",
+            hashed_files,
+            collected.files.len()
+        ));
+        if collected.timed_out {
+            result_str.push_str(
+                "\nTimed out, partial results: only the files found until then were hashed",
+            );
+        }
+        Ok(result_str)
+    }
+
+    // Match the keyword as a substring of file names, without reading or indexing any content.
+    // A prebuilt index contributes its indexed paths; directories are only walked.
+    fn filename_search(
+        &self,
+        params: &SearchParams,
+        limit: usize,
+        offset: usize,
+    ) -> Result<String, SearchError> {
+        let keyword = params.keyword.trim();
+        if keyword.is_empty() {
+            return Err(SearchError::InvalidParams(
+                "Search keyword is empty. Please enter a valid keyword.".into(),
+            ));
+        }
+        let (roots, paths, stats, timed_out, may_hold_duplicates) = match &params.index_id {
+            Some(index_id) => {
+                let built = self.lookup_index(index_id)?;
+                // Records and archive entries are matched by the name of the file holding them
+                let paths = indexed_paths(&built.reader.searcher())
+                    .map_err(SearchError::IndexRead)?
+                    .iter()
+                    .map(|path| json_lines::container_path(archives::container_path(path)))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect();
+                (
+                    built.roots.clone(),
+                    paths,
+                    built.stats,
+                    built.timed_out,
+                    built.may_hold_duplicates(),
+                )
+            }
+            None => {
+                let directories = self
+                    .target_directories(params.directory.as_deref(), params.directories.as_deref());
+                if directories.is_empty() {
+                    return Err(SearchError::InvalidParams(NO_DIRECTORY_MESSAGE.into()));
+                }
+                let deadline = params
+                    .timeout_ms
+                    .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+                let roots = resolve_roots(&directories)?;
+                let collected =
+                    collect_files(&roots, &params.indexing, deadline, self.cancel.as_ref())?;
+                let stats = IndexStats {
+                    found_files: collected.files.len(),
+                    skipped_symlinks: collected.skipped_symlinks,
+                    permission_errors: collected.permission_errors,
+                    ..IndexStats::default()
+                };
+                let paths = collected
+                    .files
+                    .iter()
+                    .map(|path| path.to_string_lossy().to_string())
+                    .collect();
+                let may_hold_duplicates =
+                    roots.len() > 1 || params.indexing.follow_symlinks.unwrap_or(false);
+                (
+                    roots,
+                    paths,
+                    stats,
+                    collected.timed_out,
+                    may_hold_duplicates,
+                )
+            }
+        };
+
+        let case_sensitive = params.indexing.case_sensitive.unwrap_or(false);
+        let needle = if case_sensitive {
+            keyword.to_string()
+        } else {
+            keyword.to_lowercase()
+        };
+
+        let excluded: Vec<String> = params
+            .exclude_keywords
+            .iter()
+            .flatten()
+            .map(|keyword| keyword.trim())
+            .filter(|keyword| !keyword.is_empty())
+            .map(|keyword| {
+                if case_sensitive {
+                    keyword.to_string()
+                } else {
+                    keyword.to_lowercase()
+                }
+            })
+            .collect();
+
+        let mut hits = Vec::new();
+        for path in paths {
+            let name = Path::new(&path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let haystack = if case_sensitive {
+                name.clone()
+            } else {
+                name.to_lowercase()
+            };
+            if excluded
+                .iter()
+                .any(|keyword| haystack.contains(keyword.as_str()))
+            {
+                continue;
+            }
+            let ranges: Vec<Range<usize>> = haystack
+                .match_indices(&needle)
+                .map(|(start, matched)| start..start + matched.len())
+                .collect();
+            if ranges.is_empty() {
+                continue;
+            }
+            // Nothing is indexed, so the time is taken from the file itself
+            let modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .and_then(|modified| modified_timestamp(modified.as_secs()));
+            hits.push(Hit {
+                path,
+                score: 1.0,
+                match_count: ranges.len(),
+                // Lowercasing may change byte lengths, in which case the ranges don't apply
+                highlighted: if haystack.len() == name.len() {
+                    ranges
+                } else {
+                    Vec::new()
+                },
+                snippet: name,
+                lines: None,
+                context: None,
+                matched_terms: Vec::new(),
+                normalized_score: None,
+                modified,
+                highlights: None,
+                more_snippets: Vec::new(),
+                more_highlighted: Vec::new(),
+            });
+        }
+
+        // Without scores, hits are ordered by path unless sorted by modification time
+        let modified_of = |hit: &Hit| {
+            fs::metadata(&hit.path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        };
+        match params.sort_by.unwrap_or_default() {
+            SortBy::Relevance | SortBy::PathAsc => hits.sort_by(|a, b| a.path.cmp(&b.path)),
+            SortBy::ModifiedDesc => hits.sort_by_cached_key(|hit| Reverse(modified_of(hit))),
+            SortBy::ModifiedAsc => hits.sort_by_cached_key(modified_of),
+        }
+        if may_hold_duplicates {
+            hits = distinct_files(hits, |hit| hit.score, |hit| hit.path.as_str());
+        }
+
+        let total_matches = hits.len();
+        self.record_search(&roots, params, total_matches);
+        let format = params.format.unwrap_or_default();
+        if params.count_only.unwrap_or(false) {
+            return Ok(count_output(format, total_matches));
+        }
+        let mut hits: Vec<Hit> = hits.into_iter().skip(offset).take(limit).collect();
+
+        if format == OutputFormat::Json {
+            let (default_pre, default_post) = default_markers(format);
+            finish_json_snippets(
+                &mut hits,
+                (
+                    params.highlight_pre.as_deref().unwrap_or(default_pre),
+                    params.highlight_post.as_deref().unwrap_or(default_post),
+                ),
+                params.highlight_offsets.unwrap_or(false),
+            );
+            return SearchResults {
+                roots: root_names(&roots),
+                total_hits: hits.len(),
+                hits,
+                stats,
+                total_matches,
+                offset,
+                below_min_score: 0,
+                timed_out,
+            }
+            .to_json();
+        }
+
+        let summary = if timed_out {
+            format!(
+                "{}\nTimed out, partial results: only the files found until then were searched",
+                stats.summary()
+            )
+        } else {
+            stats.summary()
+        };
+        let summary = format!("Searched: {}\n{}", root_names(&roots).join(", "), summary);
+        if hits.is_empty() {
+            return Ok(format!(
+                "No file names contain '{}'. Total matches: {}\n{}",
+                keyword, total_matches, summary
+            ));
+        }
+        let heading = format!(
+            "File name results ({} hits, {}-{} of {} matches):",
+            hits.len(),
+            offset + 1,
+            offset + hits.len(),
+            total_matches
+        );
+        if format == OutputFormat::Html {
+            let (default_pre, default_post) = default_markers(format);
+            let markers = (
+                params.highlight_pre.as_deref().unwrap_or(default_pre),
+                params.highlight_post.as_deref().unwrap_or(default_post),
+            );
+            return Ok(html_results(&heading, &[], &hits, markers, &summary));
+        }
+        let mut result_str = String::new();
+        for hit in &hits {
+            result_str.push_str(&format!("Hit: {}\n", hit.path));
+        }
+        Ok(format!("{}\n{}\n{}", heading, result_str, summary))
+    }
+}
+
+#[tool(tool_box)]
+impl SearchTool {
+    pub fn new(max_concurrent_searches: usize, index_cache_size: usize) -> Self {
+        let slot_count = max_concurrent_searches.clamp(1, u32::MAX as usize) as u32;
+        Self {
+            indexes: Arc::new(Mutex::new(HashMap::new())),
+            next_index_id: Arc::new(AtomicU64::new(1)),
+            slots: Arc::new(Semaphore::new(slot_count as usize)),
+            slot_count,
+            recent_indexes: NonZeroUsize::new(index_cache_size)
+                .map(|size| Arc::new(Mutex::new(LruCache::new(size)))),
+            default_directory: None,
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(SEARCH_HISTORY_CAPACITY))),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            watches: Arc::new(Mutex::new(HashMap::new())),
+            cancel: None,
+        }
+    }
+
+    // Search this directory when a request names neither a directory nor an index
+    pub fn with_default_directory(mut self, default_directory: Option<String>) -> Self {
+        self.default_directory = default_directory;
+        self
+    }
+
+    // A copy of the tool serving a single request, whose walks and indexing runs stop once the
+    // token is cancelled
+    fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    // Directories named by a request, or the default directory when it names none
+    fn target_directories(
+        &self,
+        directory: Option<&str>,
+        directories: Option<&[String]>,
+    ) -> Vec<String> {
+        let named = collect_directories(directory, directories);
+        match &self.default_directory {
+            Some(default_directory) if named.is_empty() => vec![default_directory.clone()],
+            _ => named,
+        }
+    }
+
+    // Stop taking requests and wait up to the grace period for the running searches and
+    // indexing runs. They stop reading files and commit what they have, so that no cached index
+    // is left half-written when the process exits.
+    pub async fn shutdown(&self, grace: Duration) {
+        request_shutdown();
+        // Stop watching, so that no further updates start while the running work finishes
+        if let Ok(mut watches) = self.watches.lock() {
+            watches.clear();
+        }
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            subscriptions.clear();
+        }
+        let finished = tokio::time::timeout(grace, self.slots.acquire_many(self.slot_count)).await;
+        // Requests still waiting for a slot fail from now on
+        self.slots.close();
+        match finished {
+            Ok(_) => tracing::info!("All searches and indexing runs finished"),
+            Err(_) => tracing::warn!(
+                "Shutting down with searches or indexing runs still working after {:?}",
+                grace
+            ),
+        }
+    }
+
+    // Wait until fewer than the allowed number of searches and indexing runs are working, so
+    // that simultaneous requests queue instead of each walking the disk with its own writer
+    async fn acquire_slot(&self) -> Result<OwnedSemaphorePermit, SearchError> {
+        if self.slots.available_permits() == 0 {
+            tracing::info!("All search slots are busy, queuing the request");
+        }
+        self.slots
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| SearchError::Internal("Server is shutting down".into()))
+    }
+
+    /// Build an index for a directory and keep it for later searches
+    #[tool(
+        description = "Index the text files within the specified directory and return an index id to pass to the search tool"
+    )]
+    async fn index(
+        &self,
+        #[tool(aggr)] params: IndexParams,
+        peer: Peer<RoleServer>,
+    ) -> Result<String, McpError> {
+        let directories = collect_directories(
+            Some(params.directory.as_str()),
+            params.directories.as_deref(),
+        );
+        if params.indexing.dry_run.unwrap_or(false) {
+            let _slot = self.acquire_slot().await?;
+            // A dry run walks and reads the files too, so it blocks and stops the same way
+            let cancel = CancellationToken::new();
+            let _cancel_on_drop = cancel.clone().drop_guard();
+            let indexing = params.indexing.clone();
+            return Ok(tokio::task::spawn_blocking(move || {
+                dry_run(&directories, &indexing, OutputFormat::Text, Some(&cancel))
+            })
+            .await
+            .map_err(|e| SearchError::Internal(format!("Dry run task failed: {}", e)))??);
+        }
+        let progress = params
+            .indexing
+            .report_progress
+            .unwrap_or(false)
+            .then(|| progress_notifier(peer));
+        let _slot = self.acquire_slot().await?;
+        // Indexing blocks, so it runs off the async runtime. Dropping the request, as when the
+        // client cancels it or disconnects, stops the walk and the reading of files.
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let indexing = params.indexing.clone();
+        let built = tokio::task::spawn_blocking(move || {
+            build_index(&directories, &indexing, None, Some(&cancel), progress)
+        })
+        .await
+        .map_err(|e| SearchError::Internal(format!("Indexing task failed: {}", e)))??;
+        let index_id = format!(
+            "index-{}",
+            self.next_index_id.fetch_add(1, Ordering::Relaxed)
+        );
+        let message = format!(
+            "Index '{}' built for '{}'.\n{}\nPass index_id '{}' to the search tool to query it.",
+            index_id,
+            built.roots_display(),
+            built.stats.summary(),
+            index_id
+        );
+
+        self.index_registry()?.insert(index_id, Arc::new(built));
+        Ok(message)
+    }
+
+    /// Bring a prebuilt index up to date with the files in its directories
+    #[tool(
+        description = "Update an index built with the index tool: re-read only the files that were added or modified since, and remove the ones that were deleted"
+    )]
+    async fn update(
+        &self,
+        #[tool(aggr)] params: UpdateParams,
+        peer: Peer<RoleServer>,
+    ) -> Result<String, McpError> {
+        let progress = params
+            .report_progress
+            .unwrap_or(false)
+            .then(|| progress_notifier(peer));
+        let _slot = self.acquire_slot().await?;
+        // Updating blocks, so it runs off the async runtime. Dropping the request stops the walk
+        // and the reading of files.
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let tool = self.clone().with_cancellation(cancel);
+        let output =
+            tokio::task::spawn_blocking(move || tool.run_update(&params.index_id, progress))
+                .await
+                .map_err(|e| SearchError::Internal(format!("Update task failed: {}", e)))??;
+        Ok(output)
+    }
+
+    // Re-read the added and modified files of an index and remove the deleted ones
+    fn run_update(
+        &self,
+        index_id: &str,
+        progress: Option<ProgressCallback>,
+    ) -> Result<String, SearchError> {
+        let built = self.lookup_index(index_id)?;
+        let cancel = self.cancel.as_ref();
+        let collected = collect_files(&built.roots, &built.params, None, cancel)?;
+        // Files a cancelled walk did not reach would be taken for deleted
+        if collected.timed_out {
+            return Err(SearchError::Internal(format!(
+                "Update of index '{}' cancelled before its directories were walked",
+                index_id
+            )));
+        }
+        let writer_options = writer_options(&built.params, None, cancel, progress)?;
+
+        // A cached index is updated through its manifest, which must stay in sync with it
+        let reload_mode = built.params.reload_policy.unwrap_or_default();
+        let (index, reader, fields, records, changes) = match &built.params.index_cache_dir {
+            Some(cache_dir) => {
+                let (index, fields, records) = index_cache::open_or_build(
+                    Path::new(cache_dir),
+                    &built.roots,
+                    &collected.files,
+                    !collected.timed_out,
+                    &built.index_options,
+                    &writer_options,
+                )
+                .map_err(SearchError::IndexWrite)?;
+                let reader = open_reader(&index, reload_mode)?;
+                (
+                    index,
+                    reader,
+                    fields,
+                    records,
+                    "cached index synchronized".to_string(),
+                )
+            }
+            None => {
+                let update = update_index(
+                    &built.index,
+                    &built.reader.searcher(),
+                    &collected.files,
+                    &built.files,
+                    built.fields,
+                    &built.index_options,
+                    &writer_options,
+                )
+                .map_err(SearchError::IndexWrite)?;
+                let changes = format!(
+                    "{} files read again, {} documents removed",
+                    update.read_files, update.removed_documents
+                );
+                // Make the update visible right away, whatever the reload policy
+                built
+                    .reader
+                    .reload()
+                    .map_err(|e| SearchError::IndexRead(format!("Index reader error: {}", e)))?;
+                (
+                    built.index.clone(),
+                    built.reader.clone(),
+                    built.fields,
+                    update.records,
+                    changes,
+                )
+            }
+        };
+        let mut stats = IndexStats::from_records(&records);
+        stats.skipped_symlinks = collected.skipped_symlinks;
+        stats.permission_errors = collected.permission_errors;
+        let message = format!(
+            "Index '{}' updated: {}.\n{}",
+            index_id,
+            changes,
+            stats.summary()
+        );
+        let updated = BuiltIndex {
+            index,
+            reader,
+            fields,
+            stats,
+            timed_out: false,
+            fingerprint: files_fingerprint(&collected.files),
+            files: records,
+            ..(*built).clone()
+        };
+        self.index_registry()?
+            .insert(index_id.to_string(), Arc::new(updated));
+        Ok(message)
+    }
+
+    /// Rebuild an index from scratch, for when change detection missed modified files
+    #[tool(
+        description = "Rebuild the index of a directory from scratch, discarding the indexes kept in memory and in index_cache_dir however fresh they look, and return the new stats; the 'turn it off and on again' for stale results, e.g. after restoring a backup or with clock skew"
+    )]
+    async fn reindex(
+        &self,
+        #[tool(aggr)] params: ReindexParams,
+        peer: Peer<RoleServer>,
+    ) -> Result<String, McpError> {
+        let progress = params
+            .indexing
+            .report_progress
+            .unwrap_or(false)
+            .then(|| progress_notifier(peer));
+        let _slot = self.acquire_slot().await?;
+        // Rebuilding blocks, so it runs off the async runtime. Dropping the request stops the
+        // walk and the reading of files.
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let tool = self.clone().with_cancellation(cancel);
+        let output = tokio::task::spawn_blocking(move || tool.run_reindex(&params, progress))
+            .await
+            .map_err(|e| SearchError::Internal(format!("Reindexing task failed: {}", e)))??;
+        Ok(output)
+    }
+
+    // Discard every cached copy of the index of the directories and build it again
+    fn run_reindex(
+        &self,
+        params: &ReindexParams,
+        progress: Option<ProgressCallback>,
+    ) -> Result<String, SearchError> {
+        let (directories, indexing) = match &params.index_id {
+            Some(index_id) => {
+                let built = self.lookup_index(index_id)?;
+                (root_names(&built.roots), built.params.clone())
+            }
+            None => {
+                let directories = self
+                    .target_directories(params.directory.as_deref(), params.directories.as_deref());
+                if directories.is_empty() {
+                    return Err(SearchError::InvalidParams(NO_DIRECTORY_MESSAGE.into()));
+                }
+                (directories, params.indexing.clone())
+            }
+        };
+        let roots = resolve_roots(&directories)?;
+
+        let recent_indexes = || {
+            self.recent_indexes.as_ref().map(|recent_indexes| {
+                recent_indexes
+                    .lock()
+                    .map_err(|_| SearchError::Internal("Index cache is unavailable".into()))
+            })
+        };
+        if let Some(recent_indexes) = recent_indexes() {
+            recent_indexes?.pop(&roots);
+        }
+        let mut discarded = "in-memory index discarded";
+        if let Some(cache_dir) = &indexing.index_cache_dir
+            && index_cache::invalidate(Path::new(cache_dir), &roots)
+                .map_err(SearchError::IndexWrite)?
+        {
+            discarded = "in-memory and cached index discarded";
+        }
+
+        let built = Arc::new(build_index(
+            &directories,
+            &indexing,
+            None,
+            self.cancel.as_ref(),
+            progress,
+        )?);
+        // Reindexing has no timeout, so only a cancelled request leaves the index partial
+        if built.timed_out {
+            return Err(SearchError::Internal(format!(
+                "Reindexing of '{}' cancelled before all files were read",
+                built.roots_display()
+            )));
+        }
+        let message = format!(
+            "Index of '{}' rebuilt from scratch ({}).\n{}",
+            built.roots_display(),
+            discarded,
+            built.stats.summary()
+        );
+        match &params.index_id {
+            Some(index_id) => {
+                self.index_registry()?.insert(index_id.clone(), built);
+            }
+            // A partial index must not stand in for the complete one
+            None if !built.timed_out => {
+                if let Some(recent_indexes) = recent_indexes() {
+                    recent_indexes?.put(roots, built);
+                }
+            }
+            None => {}
+        }
+        Ok(message)
+    }
+
+    /// Keep an index up to date in the background as its files change
+    #[tool(
+        description = "Watch the directories of an index built with the index tool and update the index in the background whenever files are added, modified or deleted, so that searches by its index_id are instant and current; pass stop to end watching"
+    )]
+    async fn watch(&self, #[tool(aggr)] params: WatchParams) -> Result<String, McpError> {
+        Ok(self.run_watch(&params)?)
+    }
+
+    /// Show what a search and replace would change, without writing anything
+    #[tool(
+        description = "Preview a search and replace across the indexed files as a unified diff per file, without modifying any file"
+    )]
+    async fn replace_preview(
+        &self,
+        #[tool(aggr)] params: ReplacePreviewParams,
+    ) -> Result<String, McpError> {
+        if params.search.is_empty() {
+            return Err(SearchError::InvalidParams(
+                "Search text is empty. Please enter the text to replace.".into(),
+            )
+            .into());
+        }
+        let limit = match params.limit {
+            Some(0) => {
+                return Err(SearchError::InvalidParams("Limit must be at least 1.".into()).into());
+            }
+            Some(limit) => limit.min(MAX_RESULT_LIMIT),
+            None => DEFAULT_RESULT_LIMIT,
+        };
+        let pattern = if params.regex.unwrap_or(false) {
+            params.search.clone()
+        } else {
+            regex::escape(&params.search)
+        };
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(params.ignore_case.unwrap_or(false))
+            .build()
+            .map_err(|e| SearchError::QueryParse(format!("Regex error: {}", e)))?;
+        // A literal replacement must not expand $ references
+        let replacement = if params.regex.unwrap_or(false) {
+            params.replacement.clone()
+        } else {
+            params.replacement.replace('$', "$$")
+        };
+
+        let built = match &params.index_id {
+            Some(index_id) => self.lookup_index(index_id)?,
+            None => {
+                let directories = self
+                    .target_directories(params.directory.as_deref(), params.directories.as_deref());
+                if directories.is_empty() {
+                    return Err(SearchError::InvalidParams(NO_DIRECTORY_MESSAGE.into()).into());
+                }
+                let _slot = self.acquire_slot().await?;
+                self.recent_index_until_dropped(directories, params.indexing.clone())
+                    .await?
+            }
+        };
+        if !built.index_options.store_content {
+            return Err(SearchError::InvalidParams(
+                "A replace preview scans the stored content; the index was built with store_content: false.".into(),
+            )
+            .into());
+        }
+        // Scanning every stored document blocks, so it runs off the async runtime
+        let preview = tokio::task::spawn_blocking(move || {
+            replace_preview::preview(
+                &built.reader.searcher(),
+                built.fields,
+                &pattern,
+                &replacement,
+                limit,
+            )
+        })
+        .await
+        .map_err(|e| SearchError::Internal(format!("Replace preview task failed: {}", e)))?;
+        Ok(preview.map_err(SearchError::IndexRead)?)
+    }
+
+    /// Report statistics of an index to understand the corpus
+    #[tool(
+        description = "Report statistics of the index of a directory: number of documents, segments, index size in bytes, and the unique terms, total tokens and average length of the content and filename fields"
+    )]
+    async fn stats(&self, #[tool(aggr)] params: StatsParams) -> Result<String, McpError> {
+        let built = match &params.index_id {
+            Some(index_id) => self.lookup_index(index_id)?,
+            None => {
+                let directories = self
+                    .target_directories(params.directory.as_deref(), params.directories.as_deref());
+                if directories.is_empty() {
+                    return Err(SearchError::InvalidParams(NO_DIRECTORY_MESSAGE.into()).into());
+                }
+                let _slot = self.acquire_slot().await?;
+                self.recent_index_until_dropped(directories, params.indexing.clone())
+                    .await?
+            }
+        };
+        let corpus = index_stats::corpus_stats(&built.reader.searcher(), built.fields)
+            .map_err(SearchError::IndexRead)?;
+        let report = serde_json::json!({
+            "roots": root_names(&built.roots),
+            "index": corpus,
+            "files": built.stats,
+        });
+        Ok(to_pretty_json(&report)?)
+    }
+
+    /// Read and return the content of a specified file
+    #[tool(description = "Read the content of a file from the specified path")]
+    async fn read_file_content(
+        &self,
+        #[tool(aggr)] params: FileContentParams,
+    ) -> Result<String, McpError> {
+        // Validate file path
+        let file_path = Path::new(&params.file_path);
+
+        // Entries of indexed archives are read from inside the archive
+        let archive = archives::container_path(&params.file_path);
+        if archive != params.file_path && !file_path.exists() {
+            let name = &params.file_path[archive.len() + ENTRY_SEPARATOR.len()..];
+            return match archives::read_entry(Path::new(archive), name, DEFAULT_MAX_FILE_SIZE_BYTES)
+                .map_err(SearchError::Io)?
+            {
+                Some(bytes) if is_text_content(&bytes, TextDetection::default()) => {
+                    Ok(encoding::decode(bytes, None))
+                }
+                Some(bytes) if bytes.is_empty() => Ok("File is empty.".to_string()),
+                Some(_) => Err(SearchError::Io(format!(
+                    "The file '{}' appears to be a binary file and cannot be displayed as text",
+                    params.file_path
+                ))
+                .into()),
+                None => Err(SearchError::NotFound(format!(
+                    "The archive '{}' has no entry '{}'",
+                    archive, name
+                ))
+                .into()),
+            };
+        }
+
+        // Records of JSON lines files are read from their line of the file
+        if let Some((file, line_number)) = json_lines::split_record_path(&params.file_path)
+            && !file_path.exists()
+            && Path::new(file).is_file()
+        {
+            let bytes = fs::read(file)
+                .map_err(|e| SearchError::Io(format!("Error reading file '{}': {}", file, e)))?;
+            let content = encoding::decode(bytes, None);
+            return match line_number
+                .checked_sub(1)
+                .and_then(|i| content.lines().nth(i))
+            {
+                Some(record) => Ok(record.to_string()),
+                None => Err(SearchError::NotFound(format!(
+                    "The file '{}' has no line {}",
+                    file, line_number
+                ))
+                .into()),
+            };
+        }
+
+        // Check if the path exists
+        if !file_path.exists() {
+            return Err(SearchError::NotFound(format!(
+                "The specified path '{}' does not exist",
+                params.file_path
+            ))
+            .into());
+        }
+
+        // Check if the path is a file
+        if !file_path.is_file() {
+            return Err(SearchError::NotAFile(format!(
+                "The specified path '{}' is not a file",
+                params.file_path
+            ))
+            .into());
+        }
+
+        // PDF documents are returned as their extracted text when the build supports it
+        if pdf::SUPPORTED && pdf::is_pdf(file_path) {
+            return pdf::extract_text(file_path).map_err(|e| SearchError::Io(e).into());
+        }
+
+        // Try to read the file content
+        match fs::read_to_string(file_path) {
+            Ok(content) => {
+                if content.is_empty() {
+                    Ok("File is empty.".to_string())
+                } else {
+                    Ok(content)
+                }
+            }
+            Err(e) => {
+                // Handle binary files or read errors
+                tracing::error!("Error reading file '{}': {}", file_path.display(), e);
+
+                // Try to read as binary and check if it's a binary file
+                match fs::read(file_path) {
+                    Ok(bytes) => {
+                        if is_text_file(file_path, &IndexOptions::default()) {
+                            // Text in another encoding is transcoded like it is when indexing
+                            Ok(encoding::decode(bytes, None))
+                        } else if bytes.iter().any(|&b| b == 0)
+                            || bytes
+                                .iter()
+                                .filter(|&&b| b < 32 && b != 9 && b != 10 && b != 13)
+                                .count()
+                                > bytes.len() / 10
+                        {
+                            Err(SearchError::Io(format!(
+                                "The file '{}' appears to be a binary file and cannot be displayed as text",
+                                params.file_path
+                            ))
+                            .into())
+                        } else {
+                            Err(SearchError::Io(format!(
+                                "The file '{}' could not be read as text: {}",
+                                params.file_path, e
+                            ))
+                            .into())
+                        }
+                    }
+                    Err(read_err) => Err(SearchError::Io(format!(
+                        "Error reading file '{}': {}",
+                        params.file_path, read_err
+                    ))
+                    .into()),
+                }
+            }
+        }
+    }
+
+    /// Check that indexing and searching work, independently of the filesystem
+    #[tool(
+        description = "Smoke test: index a known document in memory, run a known query and report whether it was found, with the time taken"
+    )]
+    async fn self_test(&self) -> Result<String, McpError> {
+        let started = Instant::now();
+        let outcome = tokio::task::spawn_blocking(run_self_test)
+            .await
+            .map_err(|e| SearchError::Internal(format!("Self-test task failed: {}", e)))?;
+        let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+        let report = match outcome {
+            Ok(()) => serde_json::json!({ "success": true, "elapsed_ms": elapsed_ms }),
+            Err(e) => serde_json::json!({ "success": false, "elapsed_ms": elapsed_ms, "error": e }),
+        };
+        Ok(to_pretty_json(&report)?)
+    }
+
+    /// List the most recent searches, to avoid repeating them
+    #[tool(
+        description = "List the most recent searches, newest first, with the searched directories, keyword, number of matching files and time of each, so that a search need not be repeated"
+    )]
+    async fn search_history(
+        &self,
+        #[tool(aggr)] params: HistoryParams,
+    ) -> Result<String, McpError> {
+        let searches = self.recent_searches(params.limit.unwrap_or(SEARCH_HISTORY_CAPACITY))?;
+        Ok(to_pretty_json(
+            &serde_json::json!({ "searches": searches }),
+        )?)
+    }
+
+    /// Report the build of the server, for bug reports and deployment checks
+    #[tool(
+        description = "Report the server version, the git commit it was built from, the Tantivy version and the supported features"
+    )]
+    async fn server_info(&self) -> Result<String, McpError> {
+        let info = serde_json::json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_commit": env!("GIT_COMMIT"),
+            "tantivy_version": tantivy::version_string(),
+            "features": {
+                "segmentation": ["default", "cjk"],
+                "stemming": ["english"],
+                "code_tokenizer": true,
+                "case_sensitive": true,
+                "persistent_index": true,
+                "fallback_encoding": true,
+                "archives": ["zip", "tar", "tar.gz"],
+                "gzip": true,
+                "json_lines": ["jsonl", "ndjson"],
+                "pdf": pdf::SUPPORTED,
+                "watch": watcher::SUPPORTED,
+            },
+        });
+        Ok(to_pretty_json(&info)?)
+    }
+
+    /// Perform full-text search for keywords on text files (such as .txt, .md, etc.) in the specified directory
+    #[tool(description = "Search for keywords in text files within the specified directory")]
+    async fn search(
+        &self,
+        #[tool(aggr)] params: SearchParams,
+        peer: Peer<RoleServer>,
+    ) -> Result<String, McpError> {
+        let timeout_ms = params.timeout_ms;
+        let progress = params
+            .indexing
+            .report_progress
+            .unwrap_or(false)
+            .then(|| progress_notifier(peer.clone()));
+        let on_hit = params.stream.unwrap_or(false).then(|| hit_notifier(peer));
+        // Indexing and searching block, so they run off the async runtime. The slot is held
+        // until the work is done, even when the caller stops waiting for it at the timeout.
+        let slot = self.acquire_slot().await?;
+        // Dropping the request, as when the client cancels it or disconnects, stops the walk and
+        // the reading of files
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let tool = self.clone().with_cancellation(cancel);
+        let task = tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            tool.run_search(params, progress, on_hit)
+        });
+        let result = match timeout_ms {
+            // Indexing stops by itself at the timeout; the grace period only bounds the search
+            // over the partial index, so that the client is never left hanging
+            Some(timeout_ms) => tokio::time::timeout(
+                Duration::from_millis(timeout_ms).saturating_add(TIMEOUT_GRACE),
+                task,
+            )
+            .await
+            .map_err(|_| {
+                SearchError::Timeout(format!("Search timed out after {} ms", timeout_ms))
+            })?,
+            None => task.await,
+        };
+        let output =
+            result.map_err(|e| SearchError::Internal(format!("Search task failed: {}", e)))??;
+        Ok(output)
+    }
+
+    // Index the requested directories if needed and run the search
+    fn run_search(
+        &self,
+        params: SearchParams,
+        progress: Option<ProgressCallback>,
+        on_hit: Option<HitCallback>,
+    ) -> Result<String, SearchError> {
+        let deadline = params
+            .timeout_ms
+            .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+
+        let settings = QuerySettings::resolve(&params)?;
+
+        // File names can be matched without indexing anything
+        if params.filename_only.unwrap_or(false) {
+            return self.filename_search(&params, settings.limit, settings.offset);
+        }
+
+        // Duplicates are found by hashing the walked files, without indexing them
+        if params.find_duplicates.unwrap_or(false) {
+            return self.find_duplicates(&params, settings.limit, settings.offset);
+        }
+
+        // 1-3. Look up the prebuilt index, or index the directory now
+        let built = match &params.index_id {
+            Some(index_id) => self.lookup_index(index_id)?,
+            None => {
+                let directories = self
+                    .target_directories(params.directory.as_deref(), params.directories.as_deref());
+                if directories.is_empty() {
+                    return Err(SearchError::InvalidParams(NO_DIRECTORY_MESSAGE.into()));
+                }
+                if params.indexing.dry_run.unwrap_or(false) {
+                    return dry_run(
+                        &directories,
+                        &params.indexing,
+                        params.format.unwrap_or_default(),
+                        self.cancel.as_ref(),
+                    );
+                }
+                self.recent_index(&directories, &params.indexing, deadline, progress)?
+            }
+        };
+        let total_matches = Cell::new(0);
+        let output = run_query(
+            &built,
+            &params,
+            &settings,
+            on_hit.as_ref(),
+            Some(&total_matches),
+        )?;
+        self.record_search(&built.roots, &params, total_matches.get());
+        Ok(output)
+    }
+}
+
+// Settings of a search resolved from its parameters, checked before indexing so that invalid
+// parameters fail fast
+#[derive(Debug, Clone, Copy)]
+struct QuerySettings {
+    limit: usize,
+    offset: usize,
+    bm25_params: Option<Bm25Params>,
+    line_options: LineOptions,
+    fuzzy_distance: u8,
+    // Boosts of the unstemmed and stemmed content, used by indexes built in recall mode
+    exact_boost: f32,
+    stemmed_boost: f32,
+    // Excerpts returned per hit
+    max_snippets: usize,
+}
+
+impl QuerySettings {
+    fn resolve(params: &SearchParams) -> Result<Self, SearchError> {
+        // Resolve the maximum number of hits to return
+        let limit = match params.limit {
+            Some(0) => {
+                return Err(SearchError::InvalidParams(
+                    "Limit must be at least 1.".into(),
+                ));
+            }
+            Some(limit) => limit.min(MAX_RESULT_LIMIT),
+            None => DEFAULT_RESULT_LIMIT,
+        };
+
+        if let Some(min_score) = params.min_score
+            && !min_score.is_finite()
+        {
+            return Err(SearchError::InvalidParams(
+                "Minimum score must be a finite number.".into(),
+            ));
+        }
+        if let Some(SearchFilters {
+            min_size: Some(min_size),
+            max_size: Some(max_size),
+            ..
+        }) = params.filters
+            && min_size > max_size
+        {
+            return Err(SearchError::InvalidParams(format!(
+                "filters.min_size ({}) is greater than filters.max_size ({}).",
+                min_size, max_size
+            )));
+        }
+        let bm25_params = Bm25Params::resolve(params.bm25_k1, params.bm25_b)
+            .map_err(SearchError::InvalidParams)?;
+        let line_options = LineOptions {
+            context: params.context_lines,
+            max_chars: match params.max_line_chars {
+                Some(0) => {
+                    return Err(SearchError::InvalidParams(
+                        "max_line_chars must be at least 1.".into(),
+                    ));
+                }
+                Some(max_line_chars) => max_line_chars,
+                None => DEFAULT_MAX_LINE_CHARS,
+            },
+        };
+
+        if params.max_per_dir == Some(0) {
+            return Err(SearchError::InvalidParams(
+                "max_per_dir must be at least 1.".into(),
+            ));
+        }
+        path_boosts(params.boosts.as_deref().unwrap_or_default())?;
+        let max_snippets = match params.max_snippets {
+            Some(0) => {
+                return Err(SearchError::InvalidParams(
+                    "max_snippets must be at least 1.".into(),
+                ));
+            }
+            Some(max_snippets) => max_snippets.min(MAX_SNIPPETS),
+            None => 1,
+        };
+
+        // Resolve the edit distance for fuzzy matching
+        let fuzzy_distance = params.fuzzy_distance.unwrap_or(DEFAULT_FUZZY_DISTANCE);
+        if fuzzy_distance > MAX_FUZZY_DISTANCE {
+            return Err(SearchError::InvalidParams(format!(
+                "Fuzzy distance must be at most {}.",
+                MAX_FUZZY_DISTANCE
+            )));
+        }
+
+        let boost = |name: &str, value: Option<f32>, default: f32| match value {
+            Some(value) if !(value.is_finite() && value > 0.0) => Err(SearchError::InvalidParams(
+                format!("{} must be a positive number.", name),
+            )),
+            Some(value) => Ok(value),
+            None => Ok(default),
+        };
+
+        Ok(QuerySettings {
+            limit,
+            offset: params.offset.unwrap_or(0),
+            bm25_params,
+            line_options,
+            fuzzy_distance,
+            exact_boost: boost("exact_boost", params.exact_boost, DEFAULT_EXACT_BOOST)?,
+            stemmed_boost: boost("stemmed_boost", params.stemmed_boost, DEFAULT_STEMMED_BOOST)?,
+            max_snippets,
+        })
+    }
+}
+
+// Run the query of the parameters against an index and format the results. The number of
+// matching files is also reported through `total_matches_out`, for the search history.
+fn run_query(
+    built: &BuiltIndex,
+    params: &SearchParams,
+    settings: &QuerySettings,
+    on_hit: Option<&HitCallback>,
+    total_matches_out: Option<&Cell<usize>>,
+) -> Result<String, SearchError> {
+    let report_total = |total_matches: usize| {
+        if let Some(out) = total_matches_out {
+            out.set(total_matches);
+        }
+    };
+    let QuerySettings {
+        limit,
+        offset,
+        bm25_params,
+        line_options,
+        fuzzy_distance,
+        exact_boost,
+        stemmed_boost,
+        max_snippets,
+    } = *settings;
+    let index = &built.index;
+    let fields = built.fields;
+    let stats = built.stats;
+    let path_field = fields.path;
+    let content_field = fields.content;
+    let indexed_files_count = stats.indexed_files;
+    // Every text response ends with the searched directories and the indexing summary,
+    // flagged when indexing timed out
+    let summary = match params.timeout_ms {
+        Some(timeout_ms) if built.timed_out => format!(
+            "{}\nTimed out after {} ms, partial results: only the files indexed until then were searched",
+            stats.summary(),
+            timeout_ms
+        ),
+        _ => stats.summary(),
+    };
+    let summary = format!("Searched: {}\n{}", built.roots_display(), summary);
+
+    // Return an error if no files were indexed
+    let format = params.format.unwrap_or_default();
+    if indexed_files_count == 0 {
+        if params.count_only.unwrap_or(false) {
+            return Ok(count_output(format, 0));
+        }
+        if format == OutputFormat::Json {
+            return SearchResults {
+                roots: root_names(&built.roots),
+                hits: Vec::new(),
+                stats,
+                total_hits: 0,
+                total_matches: 0,
+                offset,
+                below_min_score: 0,
+                timed_out: built.timed_out,
+            }
+            .to_json();
+        }
+        return Ok(format!(
+            "No text files suitable for indexing were found in the specified directory '{}'.\n{}\nSupported extensions: {:?}",
+            built.roots_display(),
+            summary,
+            built.index_options.binary_extensions
+        ));
+    }
+
+    // 4. Take a searcher from the shared reader of the index
+    let searcher = built.reader.searcher();
+
+    // 5. Parse query containing the keyword against file contents and file names
+    // In recall mode the content is searched both stemmed and unstemmed, each with its boost
+    let content_boosts: Vec<(Field, f32)> = match fields.content_exact {
+        Some(content_exact) => vec![(content_field, stemmed_boost), (content_exact, exact_boost)],
+        None => vec![(content_field, 1.0)],
+    };
+    let mut weighted_fields = content_boosts.clone();
+    weighted_fields.push((fields.filename, FILENAME_BOOST));
+    weighted_fields.extend(fields.content_phonetic.map(|field| (field, PHONETIC_BOOST)));
+    let mut query_parser = QueryParser::for_index(
+        index,
+        weighted_fields.iter().map(|&(field, _)| field).collect(),
+    );
+    // Rank a file name match above an incidental mention in the body
+    for &(field, boost) in &weighted_fields {
+        query_parser.set_field_boost(field, boost);
+    }
+    if params.fuzzy.unwrap_or(false) {
+        // Fuzzy terms are matched with a Levenshtein automaton where a transposition costs 1
+        // Soundex codes are only matched as whole terms
+        for &(field, _) in &weighted_fields {
+            if Some(field) != fields.content_phonetic {
+                query_parser.set_field_fuzzy(field, false, fuzzy_distance, true);
+            }
+        }
+    }
+
+    let store_content = built.index_options.store_content;
+    if params.regex.is_some() && !store_content {
+        return Err(SearchError::InvalidParams(
+            "A regex search scans the stored content; the index was built with store_content: false."
+                .into(),
+        ));
+    }
+    let regex = params
+        .regex
+        .as_deref()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| SearchError::QueryParse(format!("Regex error: {}", e)))
+        })
+        .transpose()?;
+
+    // Ensure the keyword is not empty; a regex may instead scan all files, up to the limit,
+    // and list_all matches every file
+    let list_all = params.list_all.unwrap_or(false);
+    if list_all && !params.keyword.trim().is_empty() {
+        return Err(SearchError::InvalidParams(
+            "list_all lists every indexed file; leave the keyword empty or turn list_all off."
+                .into(),
+        ));
+    }
+    if params.keyword.trim().is_empty() {
+        if regex.is_none() && !list_all {
+            return Err(SearchError::InvalidParams(
+                "Search keyword is empty. Please enter a valid keyword.".into(),
+            ));
+        }
+        if regex.is_some() && params.limit.is_none() {
+            return Err(SearchError::InvalidParams("A regex search without a keyword scans every file. Give a keyword to narrow the candidates or an explicit limit.".into()));
+        }
+    }
+    // Listed files have no relevance to rank by
+    let sort_by = params.sort_by.unwrap_or(if list_all {
+        SortBy::PathAsc
+    } else {
+        SortBy::Relevance
+    });
+    let boosts = path_boosts(params.boosts.as_deref().unwrap_or_default())?;
+
+    let query: Box<dyn Query> = if params.keyword.trim().is_empty() {
+        Box::new(AllQuery)
+    } else if params.prefix.unwrap_or(false) {
+        if params.phrase.unwrap_or(false) {
+            return Err(SearchError::InvalidParams(
+                "The phrase and prefix options can't be combined.".into(),
+            ));
+        }
+        if params.slop.is_some() {
+            return Err(SearchError::InvalidParams(
+                "The slop and prefix options can't be combined.".into(),
+            ));
+        }
+        // File names always use the lowercasing default tokenizer
+        let mut wildcard_fields: Vec<WildcardField> = content_boosts
+            .iter()
+            .map(|&(field, boost)| WildcardField {
+                field,
+                lowercase: !built.index_options.case_sensitive,
+                boost,
+            })
+            .collect();
+        wildcard_fields.push(WildcardField {
+            field: fields.filename,
+            lowercase: true,
+            boost: FILENAME_BOOST,
+        });
+        wildcard_query(&params.keyword, &wildcard_fields).map_err(SearchError::QueryParse)?
+    } else {
+        // Phrase mode quotes the keyword so its words must appear adjacent and in order,
+        // or within the slop of each other
+        let keyword = if params.phrase.unwrap_or(false) {
+            let phrase = phrase_query(&params.keyword);
+            match params.slop {
+                Some(slop) => format!("{}~{}", phrase, slop),
+                None => phrase,
+            }
+        } else {
+            match params.query_mode.unwrap_or_default() {
+                QueryMode::Boolean => {
+                    check_boolean_syntax(&params.keyword).map_err(|e| {
+                        SearchError::QueryParse(format!("Query syntax error: {}", e))
+                    })?;
+                    match params.slop {
+                        Some(slop) => with_phrase_slop(&params.keyword, slop),
+                        None => params.keyword.clone(),
+                    }
+                }
+                QueryMode::Simple => literal_query(&params.keyword),
+            }
+        };
+        if keyword.trim().is_empty() {
+            return Err(SearchError::InvalidParams(
+                "Search keyword contains no searchable words.".into(),
+            ));
+        }
+        query_parser.parse_query(&keyword).map_err(|e| match e {
+            QueryParserError::FieldDoesNotExist(field) => SearchError::QueryParse(format!(
+                "Unknown field '{}' in query. Valid fields are: {}. To search for text containing a colon, wrap it in double quotes.",
+                field,
+                QUERYABLE_FIELDS.join(", ")
+            )),
+            e => SearchError::QueryParse(format!("Query parse error: {}", e)),
+        })?
+    };
+    let query = with_exclusions(
+        query,
+        built,
+        params.exclude_keywords.as_deref().unwrap_or_default(),
+    )?;
+    let query = with_filters(query, fields, params.filters.as_ref());
+
+    // A yes/no question stops at the first match
+    if params.exists_only.unwrap_or(false) {
+        let path = match &regex {
+            Some(regex) => regex_hits(
+                &searcher,
+                &*query,
+                regex,
+                fields,
+                SortBy::PathAsc,
+                line_options,
+                Some(1),
+            )?
+            .into_iter()
+            .next()
+            .map(|hit| hit.path),
+            None => first_match(&searcher, &*query, path_field)?,
+        };
+        report_total(usize::from(path.is_some()));
+        return Ok(exists_output(format, path, &params.keyword, &summary));
+    }
+
+    // Collapse all the matching files into their directories instead of ranking a page of files
+    if params.group_by_dir.unwrap_or(false) && !params.count_only.unwrap_or(false) {
+        let paths = match &regex {
+            Some(regex) => regex_hits(
+                &searcher,
+                &*query,
+                regex,
+                fields,
+                sort_by,
+                line_options,
+                None,
+            )?
+            .into_iter()
+            .map(|hit| hit.path)
+            .collect(),
+            None => matching_paths(&searcher, &*query, path_field)?,
+        };
+        let paths = if built.may_hold_duplicates() {
+            distinct_files(paths, |_| 0.0, |path| path.as_str())
+        } else {
+            paths
+        };
+        let total_matches = paths.len();
+        report_total(total_matches);
+        let directories = group_by_directory(paths);
+        let total_directories = directories.len();
+        let page: Vec<DirectoryHits> = directories.into_iter().skip(offset).take(limit).collect();
+        if format == OutputFormat::Json {
+            return to_pretty_json(&GroupedResults {
+                roots: root_names(&built.roots),
+                directories: page,
+                stats,
+                total_directories,
+                total_matches,
+                offset,
+                timed_out: built.timed_out,
+            });
+        }
+        if page.is_empty() {
+            return Ok(format!(
+                "No search results for keyword '{}'.\n{}",
+                params.keyword, summary
+            ));
+        }
+        let lines: Vec<String> = page
+            .iter()
+            .map(|group| format!("{} ({} matching files)", group.directory, group.matches))
+            .collect();
+        return Ok(format!(
+            "Directories with matches ({}-{} of {} directories, {} matching files):\n{}\n\n{}",
+            offset + 1,
+            offset + page.len(),
+            total_directories,
+            total_matches,
+            lines.join("\n"),
+            summary
+        ));
+    }
+
+    let (mut hits, total_matches, below_min_score, best_score) = match &regex {
+        // 6-8. Scan the stored content of the candidates with the regex
+        Some(regex) => {
+            // Without a keyword to narrow the candidates, stop once the page is filled
+            let stop_after = params.keyword.trim().is_empty().then_some(offset + limit);
+            let hits = regex_hits(
+                &searcher,
+                &*query,
+                regex,
+                fields,
+                sort_by,
+                line_options,
+                stop_after,
+            )?;
+            let mut hits = hits;
+            if !boosts.is_empty() {
+                for hit in &mut hits {
+                    hit.score *= boost_factor(&boosts, &built.roots, &hit.path);
+                }
+                if sort_by == SortBy::Relevance {
+                    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+                }
+            }
+            if built.may_hold_duplicates() {
+                hits = distinct_files(hits, |hit| hit.score, |hit| hit.path.as_str());
+            }
+            let total_matches = hits.len();
+            report_total(total_matches);
+            if params.count_only.unwrap_or(false) {
+                return Ok(count_output(format, total_matches));
+            }
+            let hits = match params.max_per_dir {
+                Some(max_per_dir) => cap_per_directory(
+                    hits.into_iter().map(|hit| {
+                        let path = hit.path.clone();
+                        (hit, path)
+                    }),
+                    max_per_dir,
+                ),
+                None => hits,
+            };
+            let mut page: Vec<Hit> = hits.into_iter().skip(offset).take(limit).collect();
+            let best_score = page.iter().map(|hit| hit.score).reduce(f32::max);
+            let page_len = page.len();
+            if let Some(min_score) = params.min_score {
+                page.retain(|hit| hit.score >= min_score);
+            }
+            if let Some(on_hit) = on_hit {
+                for hit in &page {
+                    stream_hit(on_hit, hit, params, best_score);
+                }
+            }
+            (page, total_matches, page_len - page.len(), best_score)
+        }
+        None => {
+            // 6. Count all matches, then retrieve the requested page of top search results
+            let mut total_matches = searcher
+                .search(&query, &Count)
+                .map_err(|e| SearchError::IndexRead(format!("Search error: {}", e)))?;
+            let dedupe = built.may_hold_duplicates();
+            if params.count_only.unwrap_or(false) {
+                if dedupe {
+                    let paths = matching_paths(&searcher, &*query, path_field)?;
+                    total_matches = distinct_files(paths, |_| 0.0, |path| path.as_str()).len();
+                }
+                report_total(total_matches);
+                return Ok(count_output(format, total_matches));
+            }
+
+            // Collapsing the paths of the same file keeps the best of them, which takes every
+            // match to find
+            let rank_all = params.max_per_dir.is_some() || !boosts.is_empty() || dedupe;
+            // An offset beyond the matches yields an empty page rather than an oversized collector
+            let mut top_docs = if offset >= total_matches {
+                Vec::new()
+            } else {
+                // Capping hits per directory ranks every match, so that dropped hits can be
+                // replaced by lower-ranked ones before the page is taken, and so does boosting,
+                // which can lift any match onto the page
+                let collector = if rank_all {
+                    TopDocs::with_limit(total_matches)
+                } else {
+                    TopDocs::with_limit(limit).and_offset(offset)
+                };
+                // Fuzzy and prefix variants are not known terms, so they keep Tantivy's scores
+                let bm25_terms = weighted_terms(&*query, &weighted_fields);
+                match bm25_params {
+                    Some(bm25_params)
+                        if sort_by == SortBy::Relevance
+                            && !params.fuzzy.unwrap_or(false)
+                            && !params.prefix.unwrap_or(false)
+                            && !bm25_terms.is_empty() =>
+                    {
+                        bm25::top_hits(&searcher, &*query, &bm25_terms, collector, bm25_params)
+                    }
+                    _ => top_hits(&searcher, &*query, collector, sort_by),
+                }
+                .map_err(|e| SearchError::IndexRead(format!("Search error: {}", e)))?
+            };
+            if rank_all {
+                let mut ranked = top_docs
+                    .into_iter()
+                    .map(|(score, doc_address)| {
+                        let path = indexed_path(&searcher, doc_address)?;
+                        Ok(((score, doc_address), path))
+                    })
+                    .collect::<tantivy::Result<Vec<_>>>()
+                    .map_err(|e| SearchError::IndexRead(format!("Search error: {}", e)))?;
+                if !boosts.is_empty() {
+                    for ((score, _), path) in &mut ranked {
+                        *score *= boost_factor(&boosts, &built.roots, path);
+                    }
+                    if sort_by == SortBy::Relevance {
+                        ranked.sort_by(|((a, _), _), ((b, _), _)| b.total_cmp(a));
+                    }
+                }
+                if dedupe {
+                    ranked =
+                        distinct_files(ranked, |((score, _), _)| *score, |(_, path)| path.as_str());
+                    total_matches = ranked.len();
+                }
+                let ranked = match params.max_per_dir {
+                    Some(max_per_dir) => cap_per_directory(ranked, max_per_dir),
+                    None => ranked.into_iter().map(|(item, _)| item).collect(),
+                };
+                top_docs = ranked.into_iter().skip(offset).take(limit).collect();
+            }
+            report_total(total_matches);
+
+            // Drop weak matches from the page, whatever order it is sorted in
+            let best_score = top_docs.iter().map(|(score, _)| *score).reduce(f32::max);
+            let mut below_min_score = 0;
+            if let Some(min_score) = params.min_score {
+                let page_len = top_docs.len();
+                top_docs.retain(|(score, _)| *score >= min_score);
+                below_min_score = page_len - top_docs.len();
+            }
+
+            // 7. Prepare the snippet generator for excerpts around matched terms. Without stored
+            // content there is nothing to take snippets and lines from, so they are left out.
+            let mut snippet_generator = store_content
+                .then(|| SnippetGenerator::create(&searcher, &*query, content_field))
+                .transpose()
+                .map_err(|e| SearchError::IndexRead(format!("Snippet generator error: {}", e)))?;
+            if let Some(snippet_generator) = &mut snippet_generator {
+                snippet_generator.set_max_num_chars(SNIPPET_MAX_CHARS);
+            }
+
+            // Line numbers are found by analyzing each line of the top hits like the content field
+            let line_numbers = params.line_numbers.unwrap_or(false) && store_content;
+            let context_lines = line_options.context.filter(|_| store_content);
+            let content_terms = query_terms(&*query, content_field);
+            let mut content_analyzer = index
+                .tokenizer_for_field(content_field)
+                .map_err(|e| SearchError::IndexRead(format!("Tokenizer error: {}", e)))?;
+            let filename_terms = query_terms(&*query, fields.filename);
+            let mut filename_analyzer = index
+                .tokenizer_for_field(fields.filename)
+                .map_err(|e| SearchError::IndexRead(format!("Tokenizer error: {}", e)))?;
+
+            // 8. Collect file paths, scores and snippets from search results
+            let mut hits = Vec::with_capacity(top_docs.len());
+            for (score, doc_address) in &top_docs {
+                let retrieved_doc: TantivyDocument = searcher
+                    .doc(*doc_address)
+                    .map_err(|e| SearchError::IndexRead(e.to_string()))?;
+                let path_value = retrieved_doc
+                    .get_first(path_field)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("Unknown path");
+
+                let content = retrieved_doc
+                    .get_first(content_field)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default();
+
+                let mut snippets = match &snippet_generator {
+                    Some(snippet_generator) if max_snippets > 1 => {
+                        content_snippets(snippet_generator, content, max_snippets)
+                    }
+                    Some(snippet_generator) => {
+                        let snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
+                        vec![(
+                            snippet.fragment().to_string(),
+                            snippet.highlighted().to_vec(),
+                        )]
+                    }
+                    None => Vec::new(),
+                };
+                // Fall back to the beginning of the file when no fragment was found
+                if snippets
+                    .first()
+                    .is_none_or(|(snippet, _)| snippet.is_empty())
+                {
+                    snippets = vec![(
+                        content.chars().take(SNIPPET_MAX_CHARS).collect(),
+                        Vec::new(),
+                    )];
+                }
+                let mut snippets = snippets.into_iter();
+                let (snippet, highlighted) = snippets.next().unwrap_or_default();
+                let (more_snippets, more_highlighted) = snippets.unzip();
+
+                let lines = (line_numbers || context_lines.is_some()).then(|| {
+                    matching_lines(
+                        &mut content_analyzer,
+                        content,
+                        &content_terms,
+                        MAX_REPORTED_LINES,
+                    )
+                });
+                let context = context_lines
+                    .zip(lines.as_deref())
+                    .map(|(context_lines, lines)| {
+                        context_blocks(content, lines, context_lines, line_options.max_chars)
+                    });
+                let file_name = retrieved_doc
+                    .get_first(fields.filename)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default();
+                let mut terms = matched_terms(&mut content_analyzer, content, &content_terms);
+                terms.extend(matched_terms(
+                    &mut filename_analyzer,
+                    file_name,
+                    &filename_terms,
+                ));
+
+                let hit = Hit {
+                    path: path_value.to_string(),
+                    score: *score,
+                    snippet,
+                    match_count: count_matches(&mut content_analyzer, content, &content_terms),
+                    matched_terms: terms.into_iter().collect(),
+                    highlighted,
+                    lines: lines.filter(|_| line_numbers),
+                    context,
+                    normalized_score: None,
+                    modified: stored_modified(&retrieved_doc, fields),
+                    highlights: None,
+                    more_snippets,
+                    more_highlighted,
+                };
+                if let Some(on_hit) = on_hit {
+                    stream_hit(on_hit, &hit, params, best_score);
+                }
+                hits.push(hit);
+            }
+            (hits, total_matches, below_min_score, best_score)
+        }
+    };
+
+    // Normalized against the best hit of the page, including those below min_score
+    if params.normalize_scores.unwrap_or(false)
+        && let Some(best_score) = best_score
+    {
+        normalize_scores(&mut hits, best_score);
+    }
+
+    // 9. Format the results in the requested output format
+    let (default_pre, default_post) = default_markers(format);
+    let highlight_pre = params.highlight_pre.as_deref().unwrap_or(default_pre);
+    let highlight_post = params.highlight_post.as_deref().unwrap_or(default_post);
+    if format == OutputFormat::Json {
+        finish_json_snippets(
+            &mut hits,
+            (highlight_pre, highlight_post),
+            params.highlight_offsets.unwrap_or(false),
+        );
+        return SearchResults {
+            roots: root_names(&built.roots),
+            total_hits: hits.len(),
+            hits,
+            stats,
+            total_matches,
+            offset,
+            below_min_score,
+            timed_out: built.timed_out,
+        }
+        .to_json();
+    }
+
+    if total_matches == 0 {
+        return Ok(format!(
+            "No search results for keyword '{}'.\n{}",
+            params.keyword, summary
+        ));
+    }
+    if hits.is_empty()
+        && let (Some(min_score), Some(best_score)) = (params.min_score, best_score)
+    {
+        return Ok(format!(
+            "No results above threshold {} for keyword '{}'. Total matches: {}, best score: {:.2}\n{}",
+            min_score, params.keyword, total_matches, best_score, summary
+        ));
+    }
+    if hits.is_empty() {
+        return Ok(format!(
+            "No search results at offset {} for keyword '{}'. Total matches: {}\n{}",
+            offset, params.keyword, total_matches, summary
+        ));
+    }
+
+    let mut notes = Vec::new();
+    if stats.skipped_byte_limit > 0 {
+        notes.push(format!(
+            "Note: indexing stopped at the limit of {} bytes, {} files were left out and the results are partial",
+            built.params.max_total_bytes.unwrap_or_default(),
+            stats.skipped_byte_limit
+        ));
+    }
+    if stats.skipped_too_large > 0 {
+        notes.push(format!(
+            "Note: {} files larger than {} bytes were skipped",
+            stats.skipped_too_large, built.index_options.max_file_size_bytes
+        ));
+    }
+    let heading = format!(
+        "Search results ({} hits, {}-{} of {} matches):",
+        hits.len(),
+        offset + 1,
+        offset + hits.len(),
+        total_matches
+    );
+    if format == OutputFormat::Html {
+        return Ok(html_results(
+            &heading,
+            &notes,
+            &hits,
+            (highlight_pre, highlight_post),
+            &summary,
+        ));
+    }
+
+    let mut result_str: String = notes.iter().map(|note| format!("{}\n", note)).collect();
+    for hit in &hits {
+        result_str.push_str(&format!(
+            "Hit: {} ({}, Matches: {}{})\n  {}\n",
+            hit.path,
+            score_label(hit),
+            hit.match_count,
+            modified_label(hit),
+            highlight_snippet(
+                &hit.snippet,
+                &hit.highlighted,
+                highlight_pre,
+                highlight_post
+            )
+            .replace('\n', " ")
+        ));
+        for (snippet, highlighted) in hit.more_snippets.iter().zip(&hit.more_highlighted) {
+            result_str.push_str(&format!(
+                "  {}\n",
+                highlight_snippet(snippet, highlighted, highlight_pre, highlight_post)
+                    .replace('\n', " ")
+            ));
+        }
+        if !hit.matched_terms.is_empty() {
+            result_str.push_str(&format!("  Terms: {}\n", hit.matched_terms.join(", ")));
+        }
+        if let Some(lines) = &hit.lines {
+            let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+            result_str.push_str(&format!("  Lines: {}\n", lines.join(", ")));
+        }
+        // Matching lines are marked with ':' and context lines with '-', like grep
+        if let Some(blocks) = &hit.context {
+            for (i, block) in blocks.iter().enumerate() {
+                if i > 0 {
+                    result_str.push_str("  --\n");
+                }
+                for line in block {
+                    let separator = if line.matched { ':' } else { '-' };
+                    result_str.push_str(&format!("  {}{} {}\n", line.line, separator, line.text));
+                }
+            }
+        }
+    }
+    Ok(format!("{}\n{}\n{}", heading, result_str, summary))
+}
+
+#[tool(tool_box)]
+impl ServerHandler for SearchTool {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            protocol_version: ProtocolVersion::V_2024_11_05,
+            capabilities: {
+                let mut capabilities = ServerCapabilities::builder()
+                    .enable_logging()
+                    .enable_prompts()
+                    .enable_resources()
+                    .enable_tools()
+                    .build();
+                // Subscriptions are backed by the file watcher of the watch feature
+                if let Some(resources) = capabilities.resources.as_mut() {
+                    resources.subscribe = Some(watcher::SUPPORTED);
+                }
+                capabilities
+            },
+            server_info: Implementation::from_build_env(),
             instructions: Some(
-                "This server provides two tools: 1) Search for keywords in text files within a directory, 2) Read and display the content of a specific file."
+                "This server provides eleven tools: 1) Search for keywords in text files within a directory, 2) Index a directory once and search it repeatedly by passing the returned index_id to the search tool, 3) Update such an index after files changed, re-reading only the changed files, 4) Watch the directories of such an index and update it in the background as files change, 5) Rebuild an index from scratch when its results look stale, 6) Preview a search and replace across the files as a diff without writing anything, 7) Read and display the content of a specific file, 8) Report the server version and build information, 9) Run a self-test that indexes and searches a known document in memory, 10) Report statistics of an index such as its number of documents, unique terms and average document length, 11) List the most recent searches. Files indexed with the index tool are also exposed as file:// resources, and the find-todos and search-in-dir prompts expand into ready-made search instructions."
                     .into(),
             ),
         }
     }
+
+    /// List the prompt templates for common search workflows
+    async fn list_prompts(
+        &self,
+        _request: PaginatedRequestParam,
+        _: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, McpError> {
+        Ok(ListPromptsResult {
+            prompts: prompts::list(),
+            next_cursor: None,
+        })
+    }
+
+    /// Expand a prompt template with its arguments
+    async fn get_prompt(
+        &self,
+        GetPromptRequestParam { name, arguments }: GetPromptRequestParam,
+        _: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, McpError> {
+        prompts::get(&name, arguments.as_ref())
+    }
+
+    /// List the files of all indexes built with the index tool as resources
+    async fn list_resources(
+        &self,
+        _request: PaginatedRequestParam,
+        _: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        let mut resources = Vec::new();
+        for built in self.registered_indexes()? {
+            let paths = indexed_paths(&built.reader.searcher()).map_err(SearchError::IndexRead)?;
+            for path in paths {
+                resources.push(RawResource::new(file_uri(&path), path).no_annotation());
+            }
+        }
+        Ok(ListResourcesResult {
+            resources,
+            next_cursor: None,
+        })
+    }
+
+    /// Read the indexed content of a file resource
+    async fn read_resource(
+        &self,
+        ReadResourceRequestParam { uri }: ReadResourceRequestParam,
+        _: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        for built in self.registered_indexes()? {
+            let searcher = built.reader.searcher();
+            let paths = indexed_paths(&searcher).map_err(SearchError::IndexRead)?;
+            let Some(path) = paths.iter().find(|path| file_uri(path) == uri) else {
+                continue;
+            };
+            if let Some(content) =
+                stored_content(&searcher, built.fields, path).map_err(SearchError::IndexRead)?
+            {
+                return Ok(ReadResourceResult {
+                    contents: vec![ResourceContents::text(content, uri)],
+                });
+            }
+        }
+        Err(McpError::resource_not_found(
+            "resource_not_found",
+            Some(serde_json::json!({ "uri": uri })),
+        ))
+    }
+
+    /// Notify the client whenever the file behind a resource changes
+    async fn subscribe(
+        &self,
+        SubscribeRequestParam { uri }: SubscribeRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        Ok(self.subscribe_resource(uri, context.peer)?)
+    }
+
+    /// Stop notifying the client of changes to a resource
+    async fn unsubscribe(
+        &self,
+        UnsubscribeRequestParam { uri }: UnsubscribeRequestParam,
+        _: RequestContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        self.subscription_registry()?.remove(&uri);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Search parameters as a client would send them
+    fn search_params(value: serde_json::Value) -> SearchParams {
+        serde_json::from_value(value).expect("valid search parameters")
+    }
+
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        fs::write(dir.path().join("notes.txt"), "The quick brown fox jumps.").unwrap();
+        fs::write(dir.path().join("readme.md"), "Nothing to see here.").unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() { fox(); }").unwrap();
+        fs::write(dir.path().join("image.png"), [0x89, b'P', b'N', b'G', 0, 0]).unwrap();
+        dir
+    }
+
+    fn search(dir: &tempfile::TempDir, query: serde_json::Value) -> serde_json::Value {
+        let mut query = query;
+        query["directory"] = dir.path().display().to_string().into();
+        query["format"] = "json".into();
+        let params = search_params(query);
+        let settings = QuerySettings::resolve(&params).unwrap();
+        let built = build_index(
+            &collect_directories(params.directory.as_deref(), None),
+            &params.indexing,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let output = run_query(&built, &params, &settings, None, None).unwrap();
+        serde_json::from_str(&output).unwrap()
+    }
+
+    fn hit_paths(results: &serde_json::Value) -> Vec<String> {
+        results["hits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hit| hit["path"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn indexes_text_files_and_skips_binary_files() {
+        let dir = fixture();
+        let results = search(&dir, serde_json::json!({ "keyword": "fox" }));
+        assert_eq!(results["indexed_files"], 3);
+        assert_eq!(results["skipped_binary"], 1);
+        assert_eq!(results["total_matches"], 2);
+        let paths = hit_paths(&results);
+        assert!(paths.iter().any(|path| path.ends_with("notes.txt")));
+        assert!(paths.iter().any(|path| path.ends_with("main.rs")));
+    }
+
+    #[test]
+    fn matches_phrases_in_order() {
+        let dir = fixture();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "quick brown", "phrase": true }),
+        );
+        assert!(hit_paths(&results)[0].ends_with("notes.txt"));
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "brown quick", "phrase": true }),
+        );
+        assert_eq!(results["total_matches"], 0);
+    }
+
+    #[test]
+    fn pages_through_hits() {
+        let dir = fixture();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox", "limit": 1, "offset": 1 }),
+        );
+        assert_eq!(results["total_hits"], 1);
+        assert_eq!(results["total_matches"], 2);
+    }
+
+    #[test]
+    fn drops_files_with_excluded_keywords() {
+        let dir = fixture();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox", "exclude_keywords": ["main"] }),
+        );
+        assert_eq!(results["total_matches"], 1);
+        assert!(hit_paths(&results)[0].ends_with("notes.txt"));
+    }
+
+    #[test]
+    fn groups_hits_by_directory() {
+        let dir = fixture();
+        let src = dir.path().join("src");
+        fs::write(src.join("lib.rs"), "pub fn fox() {}").unwrap();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox", "group_by_dir": true }),
+        );
+        assert_eq!(results["total_directories"], 2);
+        assert_eq!(results["total_matches"], 3);
+        let top = &results["directories"][0];
+        assert!(top["directory"].as_str().unwrap().ends_with("src"));
+        assert_eq!(top["matches"], 2);
+    }
+
+    #[test]
+    fn overrides_the_binary_extensions() {
+        let dir = fixture();
+        fs::write(dir.path().join("dump.bin"), "firmware fox banner").unwrap();
+        let results = search(&dir, serde_json::json!({ "keyword": "fox" }));
+        assert_eq!(results["skipped_binary"], 2);
+
+        let results = search(
+            &dir,
+            serde_json::json!({
+                "keyword": "fox",
+                "extra_binary_extensions": [".RS"],
+                "treat_as_text_extensions": ["bin", "rs"],
+            }),
+        );
+        assert_eq!(results["skipped_binary"], 1);
+        assert_eq!(results["total_matches"], 3);
+    }
+
+    #[test]
+    fn indexes_files_with_a_byte_order_mark() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = "Saved by Notepad with a kiwi";
+        let utf16 = |bom: [u8; 2], to_bytes: fn(u16) -> [u8; 2]| {
+            bom.into_iter()
+                .chain(text.encode_utf16().flat_map(to_bytes))
+                .collect::<Vec<u8>>()
+        };
+        fs::write(
+            dir.path().join("utf16le.txt"),
+            utf16([0xff, 0xfe], u16::to_le_bytes),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("utf16be.txt"),
+            utf16([0xfe, 0xff], u16::to_be_bytes),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("utf8.txt"),
+            [&[0xef, 0xbb, 0xbf], text.as_bytes()].concat(),
+        )
+        .unwrap();
+
+        let results = search(&dir, serde_json::json!({ "keyword": "kiwi" }));
+        assert_eq!(results["indexed_files"], 3);
+        assert_eq!(results["skipped_binary"], 0);
+        assert_eq!(results["total_matches"], 3);
+        for hit in results["hits"].as_array().unwrap() {
+            assert!(
+                hit["snippet"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("Saved by Notepad")
+            );
+        }
+    }
+
+    #[test]
+    fn renders_hits_as_html() {
+        let dir = fixture();
+        fs::write(dir.path().join("tags.txt"), "<b>fox</b> & hound").unwrap();
+        let params = search_params(serde_json::json!({
+            "directory": dir.path().display().to_string(),
+            "keyword": "hound",
+            "format": "html",
+        }));
+        let settings = QuerySettings::resolve(&params).unwrap();
+        let built = build_index(
+            &[params.directory.clone().unwrap()],
+            &params.indexing,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let html = run_query(&built, &params, &settings, None, None).unwrap();
+        assert!(html.contains("<a href=\"file://"));
+        assert!(html.contains("&lt;b&gt;fox&lt;/b&gt; &amp; <mark>hound</mark>"));
+    }
+
+    #[test]
+    fn reports_highlight_offsets_within_the_snippet() {
+        let dir = fixture();
+        for markers in [
+            serde_json::json!({}),
+            serde_json::json!({ "highlight_pre": "[", "highlight_post": "]" }),
+        ] {
+            let mut query = serde_json::json!({ "keyword": "quick", "highlight_offsets": true });
+            query
+                .as_object_mut()
+                .unwrap()
+                .extend(markers.as_object().unwrap().clone());
+            let results = search(&dir, query);
+            let hit = &results["hits"][0];
+            let snippet = hit["snippet"].as_str().unwrap();
+            let (start, end) = (
+                hit["highlights"][0][0].as_u64().unwrap(),
+                hit["highlights"][0][1].as_u64().unwrap(),
+            );
+            assert_eq!(&snippet[start as usize..end as usize], "quick");
+        }
+    }
+
+    #[test]
+    fn answers_whether_any_file_matches() {
+        let dir = fixture();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox", "exists_only": true }),
+        );
+        assert_eq!(results["exists"], true);
+        assert!(results["path"].as_str().is_some());
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "wolf", "exists_only": true }),
+        );
+        assert_eq!(results["exists"], false);
+        assert!(results["path"].is_null());
+    }
+
+    #[test]
+    fn groups_files_with_identical_content() {
+        let dir = fixture();
+        fs::write(
+            dir.path().join("src/notes.txt"),
+            "The quick brown fox jumps.",
+        )
+        .unwrap();
+        let params = search_params(serde_json::json!({
+            "directory": dir.path().display().to_string(),
+            "keyword": "",
+            "find_duplicates": true,
+            "format": "json",
+        }));
+        let output = SearchTool::new(1, 0)
+            .run_search(params, None, None)
+            .unwrap();
+        let results: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(results["total_groups"], 1);
+        assert_eq!(results["hashed_files"], 4);
+        let files = results["groups"][0]["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(
+            files
+                .iter()
+                .all(|file| file.as_str().unwrap().ends_with("notes.txt"))
+        );
+    }
+
+    #[test]
+    fn reports_the_query_terms_found_in_each_hit() {
+        let dir = fixture();
+        let results = search(&dir, serde_json::json!({ "keyword": "fox OR jumps" }));
+        let terms_of = |name: &str| {
+            results["hits"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|hit| hit["path"].as_str().unwrap().ends_with(name))
+                .map(|hit| hit["matched_terms"].clone())
+                .unwrap()
+        };
+        assert_eq!(terms_of("notes.txt"), serde_json::json!(["fox", "jumps"]));
+        assert_eq!(terms_of("main.rs"), serde_json::json!(["fox"]));
+    }
+
+    #[test]
+    fn falls_back_to_the_default_directory() {
+        let dir = fixture();
+        let without_directory = || search_params(serde_json::json!({ "keyword": "fox" }));
+        let error = SearchTool::new(1, 0)
+            .run_search(without_directory(), None, None)
+            .unwrap_err();
+        assert_eq!(error.code(), "invalid_params");
+
+        let tool =
+            SearchTool::new(1, 0).with_default_directory(Some(dir.path().display().to_string()));
+        let output = tool.run_search(without_directory(), None, None).unwrap();
+        assert!(output.contains("notes.txt"));
+    }
+
+    #[test]
+    fn filters_hits_by_size_and_extension() {
+        let dir = fixture();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox", "filters": { "extension": ".RS" } }),
+        );
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].ends_with("main.rs"));
+
+        // notes.txt has 26 bytes and main.rs 20
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox", "filters": { "min_size": 21 } }),
+        );
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].ends_with("notes.txt"));
+    }
+
+    #[test]
+    fn reindexing_rebuilds_a_cached_index_from_scratch() {
+        let dir = fixture();
+        let cache = tempfile::tempdir().unwrap();
+        let params: ReindexParams = serde_json::from_value(serde_json::json!({
+            "directory": dir.path().display().to_string(),
+            "index_cache_dir": cache.path().display().to_string(),
+        }))
+        .unwrap();
+        let tool = SearchTool::new(1, 1);
+        let first = tool.run_reindex(&params, None).unwrap();
+        assert!(first.contains("(in-memory index discarded)"));
+        let second = tool.run_reindex(&params, None).unwrap();
+        assert!(second.contains("(in-memory and cached index discarded)"));
+        assert!(second.contains("Indexed: 3"));
+    }
+
+    #[test]
+    fn reuses_recent_indexes_across_request_only_parameters() {
+        let dir = fixture();
+        let directories = [dir.path().display().to_string()];
+        let tool = SearchTool::new(1, 1);
+        let first = tool
+            .recent_index(&directories, &IndexingParams::default(), None, None)
+            .unwrap();
+        let progress = IndexingParams {
+            report_progress: Some(true),
+            threads: Some(1),
+            ..IndexingParams::default()
+        };
+        let second = tool
+            .recent_index(&directories, &progress, None, None)
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let stemmed = IndexingParams {
+            stemming: Some(true),
+            ..IndexingParams::default()
+        };
+        let third = tool
+            .recent_index(&directories, &stemmed, None, None)
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+    }
+
+    #[test]
+    fn normalizes_scores_against_the_best_hit() {
+        let dir = fixture();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox jumps", "normalize_scores": true }),
+        );
+        let normalized: Vec<f64> = results["hits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hit| hit["normalized_score"].as_f64().unwrap())
+            .collect();
+        assert_eq!(normalized[0], 1.0);
+        assert!(normalized.iter().all(|score| (0.0..=1.0).contains(score)));
+        assert!(normalized[1] < 1.0);
+    }
+
+    #[test]
+    fn caps_hits_per_directory_and_backfills_from_others() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("crowded")).unwrap();
+        fs::create_dir(dir.path().join("quiet")).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(dir.path().join("crowded").join(name), "fox fox fox").unwrap();
+        }
+        fs::write(
+            dir.path().join("quiet/d.txt"),
+            "A fox, mentioned once among many other words.",
+        )
+        .unwrap();
+
+        let results = search(&dir, serde_json::json!({ "keyword": "fox", "limit": 2 }));
+        assert!(
+            hit_paths(&results)
+                .iter()
+                .all(|path| path.contains("crowded"))
+        );
+
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "fox", "limit": 2, "max_per_dir": 1 }),
+        );
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].contains("crowded"));
+        assert!(paths[1].ends_with("d.txt"));
+    }
+
+    #[test]
+    fn collapses_the_paths_of_a_file_into_its_best_hit() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(
+            dir.path().join("docs/report.txt"),
+            "Quarterly walrus numbers.",
+        )
+        .unwrap();
+        let query = |params: serde_json::Value| -> serde_json::Value {
+            let params = search_params(params);
+            let settings = QuerySettings::resolve(&params).unwrap();
+            let built = build_index(
+                &collect_directories(params.directory.as_deref(), params.directories.as_deref()),
+                &params.indexing,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            serde_json::from_str(&run_query(&built, &params, &settings, None, None).unwrap())
+                .unwrap()
+        };
+        let root = dir.path().display().to_string();
+
+        // Both roots reach the file
+        let results = query(serde_json::json!({
+            "keyword": "walrus",
+            "directory": root,
+            "directories": [dir.path().join("docs").display().to_string()],
+            "format": "json",
+        }));
+        assert_eq!(results["total_matches"], 1);
+        assert_eq!(hit_paths(&results).len(), 1);
+
+        // A link named after the keyword ranks above the file it points to
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(
+                dir.path().join("docs/report.txt"),
+                dir.path().join("walrus.txt"),
+            )
+            .unwrap();
+            let results = query(serde_json::json!({
+                "keyword": "walrus",
+                "directory": root,
+                "follow_symlinks": true,
+                "format": "json",
+            }));
+            let paths = hit_paths(&results);
+            assert_eq!(paths.len(), 1);
+            assert!(paths[0].ends_with("walrus.txt"));
+        }
+    }
+
+    #[test]
+    fn multiplies_the_scores_of_files_matching_boost_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        for directory in ["docs", "tests"] {
+            fs::create_dir(dir.path().join(directory)).unwrap();
+        }
+        fs::write(dir.path().join("tests/parser.rs"), "parser parser parser").unwrap();
+        fs::write(
+            dir.path().join("docs/parser.md"),
+            "The parser is documented here in some detail.",
+        )
+        .unwrap();
+
+        let results = search(&dir, serde_json::json!({ "keyword": "parser" }));
+        assert!(hit_paths(&results)[0].ends_with("parser.rs"));
+        let unboosted = results["hits"][1]["score"].as_f64().unwrap();
+
+        let boosts = serde_json::json!([
+            { "pattern": "docs/**", "factor": 10 },
+            { "pattern": "*.md", "factor": 2 },
+        ]);
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "parser", "limit": 1, "boosts": boosts }),
+        );
+        assert!(hit_paths(&results)[0].ends_with("parser.md"));
+        let boosted = results["hits"][0]["score"].as_f64().unwrap();
+        assert!((boosted / unboosted - 20.0).abs() < 1e-3);
+
+        let params = search_params(serde_json::json!({
+            "keyword": "parser",
+            "boosts": [{ "pattern": "docs/**", "factor": 0 }],
+        }));
+        let error = QuerySettings::resolve(&params).unwrap_err();
+        assert_eq!(error.code(), "invalid_params");
+    }
+
+    #[test]
+    fn indexes_each_json_lines_record_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("events.jsonl"),
+            "{\"event\":\"login\"}\n\n{\"event\":\"logout\"}\n",
+        )
+        .unwrap();
+
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "logout", "jsonl_mode": true }),
+        );
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].ends_with("events.jsonl#3"));
+        let snippet = results["hits"][0]["snippet"].as_str().unwrap();
+        assert!(snippet.contains("logout") && !snippet.contains("login"));
+    }
+
+    #[test]
+    fn ranks_exact_words_above_stemmed_forms_in_recall_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("exact.txt"), "The build runs nightly.").unwrap();
+        fs::write(dir.path().join("inflected.txt"), "The build is running.").unwrap();
+
+        let results = search(&dir, serde_json::json!({ "keyword": "runs" }));
+        assert_eq!(hit_paths(&results).len(), 1);
+
+        let recall = serde_json::json!({ "keyword": "runs", "recall_mode": true });
+        let results = search(&dir, recall);
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("exact.txt"));
+        assert!(paths[1].ends_with("inflected.txt"));
+    }
+
+    #[test]
+    fn asks_for_confirmation_before_indexing_large_directories() {
+        let dir = fixture();
+        let params = |confirm_large: bool| IndexingParams {
+            large_directory_threshold: Some(2),
+            confirm_large: Some(confirm_large),
+            ..IndexingParams::default()
+        };
+        let directories = [dir.path().display().to_string()];
+        let error = build_index(&directories, &params(false), None, None, None)
+            .err()
+            .unwrap();
+        assert_eq!(error.code(), "confirmation_required");
+        let built = build_index(&directories, &params(true), None, None, None).unwrap();
+        assert_eq!(built.stats.indexed_files, 3);
+    }
+
+    #[test]
+    fn reports_the_modification_time_of_each_hit() {
+        let dir = fixture();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_564_800);
+        fs::File::options()
+            .write(true)
+            .open(dir.path().join("notes.txt"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let results = search(&dir, serde_json::json!({ "keyword": "quick" }));
+        assert_eq!(results["hits"][0]["modified"], "2024-05-01T12:00:00Z");
+    }
+
+    #[test]
+    fn matches_names_that_sound_alike_in_phonetic_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("contacts.txt"), "Call John Smyth tomorrow.").unwrap();
+
+        let results = search(&dir, serde_json::json!({ "keyword": "Smith" }));
+        assert!(hit_paths(&results).is_empty());
+
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "Smith", "phonetic": true }),
+        );
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].ends_with("contacts.txt"));
+    }
+
+    #[test]
+    fn remembers_recent_searches_newest_first() {
+        let dir = fixture();
+        let tool = SearchTool::new(1, 0);
+        for keyword in ["fox", "quick"] {
+            let params = search_params(serde_json::json!({
+                "directory": dir.path().display().to_string(),
+                "keyword": keyword,
+            }));
+            tool.run_search(params, None, None).unwrap();
+        }
+
+        let history = tool.recent_searches(10).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].keyword, "quick");
+        assert_eq!(history[0].total_matches, 1);
+        assert_eq!(history[1].keyword, "fox");
+        assert_eq!(history[1].total_matches, 2);
+        assert_eq!(tool.recent_searches(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn finds_files_without_storing_their_content() {
+        let dir = fixture();
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "quick", "store_content": false, "line_numbers": true }),
+        );
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].ends_with("notes.txt"));
+        assert_eq!(results["hits"][0]["snippet"], "");
+        assert!(results["hits"][0].get("lines").is_none());
+
+        let params = search_params(serde_json::json!({
+            "keyword": "quick",
+            "regex": "qu.ck",
+            "directory": dir.path().display().to_string(),
+            "store_content": false,
+        }));
+        let error = SearchTool::new(1, 0)
+            .run_search(params, None, None)
+            .unwrap_err();
+        assert_eq!(error.code(), "invalid_params");
+    }
+
+    #[test]
+    fn counts_pdf_documents_whose_text_can_not_be_extracted() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("broken.pdf"), "%PDF-1.4 truncated").unwrap();
+        let params = IndexingParams {
+            index_pdf: Some(true),
+            ..IndexingParams::default()
+        };
+        let built = build_index(
+            &[dir.path().display().to_string()],
+            &params,
+            None,
+            None,
+            None,
+        );
+        if pdf::SUPPORTED {
+            assert_eq!(built.unwrap().stats.skipped_extraction_failed, 1);
+        } else {
+            assert_eq!(built.err().unwrap().code(), "invalid_params");
+        }
+    }
+
+    // A tool holding the index of the directory under the id index-1, as the index tool builds it
+    fn tool_with_index(dir: &tempfile::TempDir) -> SearchTool {
+        let tool = SearchTool::new(1, 0);
+        let built = build_index(
+            &[dir.path().display().to_string()],
+            &IndexingParams::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        tool.index_registry()
+            .unwrap()
+            .insert("index-1".into(), Arc::new(built));
+        tool
+    }
+
+    // Search a registered index for a keyword, with results in JSON
+    fn search_index(tool: &SearchTool, index_id: &str, keyword: &str) -> serde_json::Value {
+        let params = search_params(serde_json::json!({
+            "keyword": keyword,
+            "index_id": index_id,
+            "format": "json",
+        }));
+        let settings = QuerySettings::resolve(&params).unwrap();
+        let built = tool.lookup_index(index_id).unwrap();
+        serde_json::from_str(&run_query(&built, &params, &settings, None, None).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn updates_edited_added_and_deleted_files() {
+        let dir = fixture();
+        let tool = tool_with_index(&dir);
+        fs::write(
+            dir.path().join("notes.txt"),
+            "The quick brown walrus jumps.",
+        )
+        .unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "pub fn walrus() {}").unwrap();
+        fs::remove_file(dir.path().join("readme.md")).unwrap();
+
+        // The skipped image is unchanged, so it is not read again
+        let message = tool.run_update("index-1", None).unwrap();
+        assert!(message.contains("2 files read again, 2 documents removed"));
+        assert_eq!(search_index(&tool, "index-1", "walrus")["total_matches"], 2);
+        assert_eq!(search_index(&tool, "index-1", "fox")["total_matches"], 1);
+        assert_eq!(
+            search_index(&tool, "index-1", "nothing")["total_matches"],
+            0
+        );
+        assert!(
+            tool.run_update("index-1", None)
+                .unwrap()
+                .contains("0 files read again")
+        );
+    }
+
+    #[test]
+    fn resolves_subscribed_resources_to_their_files() {
+        let dir = fixture();
+        let built = build_index(
+            &[dir.path().display().to_string()],
+            &IndexingParams::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let tool = SearchTool::new(1, 0);
+        tool.index_registry()
+            .unwrap()
+            .insert("index-1".into(), Arc::new(built));
+        let notes = dir.path().join("notes.txt");
+        let file = tool
+            .resource_file(&file_uri(&notes.display().to_string()))
+            .unwrap();
+        assert_eq!(file, notes);
+        let error = tool.resource_file("file:///missing.txt").unwrap_err();
+        assert_eq!(error.code(), "not_found");
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn updates_watched_indexes_when_files_change() {
+        let dir = fixture();
+        let tool = tool_with_index(&dir);
+        tool.run_watch(&WatchParams {
+            index_id: "index-1".into(),
+            debounce_ms: Some(50),
+            stop: None,
+        })
+        .unwrap();
+        fs::write(
+            dir.path().join("notes.txt"),
+            "The quick brown walrus jumps.",
+        )
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while search_index(&tool, "index-1", "walrus")["total_matches"] != 1 {
+            assert!(
+                Instant::now() < deadline,
+                "the change never reached the index"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        tool.run_watch(&WatchParams {
+            index_id: "index-1".into(),
+            debounce_ms: None,
+            stop: Some(true),
+        })
+        .unwrap();
+        assert!(tool.watch_registry().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queues_requests_beyond_the_allowed_number() {
+        let tool = SearchTool::new(1, 0);
+        let slot = tool.acquire_slot().await.unwrap();
+        let mut waiting = Box::pin(tool.acquire_slot());
+        let queued = tokio::time::timeout(Duration::from_millis(50), &mut waiting).await;
+        assert!(queued.is_err(), "a second request ran alongside the first");
+        drop(slot);
+        let started = tokio::time::timeout(Duration::from_secs(5), waiting).await;
+        assert!(started.unwrap().is_ok());
+
+        // A limit of 0 still lets requests through one at a time
+        let tool = SearchTool::new(0, 0);
+        let started = tokio::time::timeout(Duration::from_secs(5), tool.acquire_slot()).await;
+        assert!(started.unwrap().is_ok());
+    }
+
+    #[test]
+    fn watches_only_indexes_built_with_the_index_tool() {
+        let tool = SearchTool::new(1, 0);
+        let params = WatchParams {
+            index_id: "index-9".into(),
+            debounce_ms: None,
+            stop: None,
+        };
+        let error = tool.run_watch(&params).unwrap_err();
+        let expected = if watcher::SUPPORTED {
+            "unknown_index"
+        } else {
+            "invalid_params"
+        };
+        assert_eq!(error.code(), expected);
+        let stop = WatchParams {
+            stop: Some(true),
+            ..params
+        };
+        assert!(
+            tool.run_watch(&stop)
+                .unwrap()
+                .contains("was not being watched")
+        );
+    }
+
+    #[test]
+    fn indexes_only_the_head_of_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = "filler ".repeat(100);
+        fs::write(
+            dir.path().join("LICENSE"),
+            format!("Licensed under Apache. {}footer", body),
+        )
+        .unwrap();
+
+        let head = |keyword: &str| {
+            serde_json::json!({
+                "keyword": keyword,
+                "content_head_bytes": 64,
+                "max_file_size_bytes": 100,
+            })
+        };
+        // The file is larger than the size limit, but only its head is read
+        assert_eq!(search(&dir, head("apache"))["total_matches"], 1);
+        assert_eq!(search(&dir, head("footer"))["total_matches"], 0);
+        let results = search(&dir, serde_json::json!({ "keyword": "footer" }));
+        assert_eq!(results["total_matches"], 1);
+    }
+
+    #[test]
+    fn reports_no_matches_as_an_empty_hit_list() {
+        let dir = fixture();
+        for query in [
+            serde_json::json!({ "keyword": "zebra" }),
+            serde_json::json!({ "keyword": "fox", "offset": 10 }),
+            serde_json::json!({ "keyword": "zebra", "regex": "z+" }),
+            serde_json::json!({ "keyword": "zebra", "extensions": ["csv"] }),
+        ] {
+            let results = search(&dir, query);
+            assert_eq!(results["total_hits"], 0);
+            assert_eq!(results["hits"], serde_json::json!([]));
+        }
+
+        let params = search_params(serde_json::json!({
+            "directory": dir.path().display().to_string(),
+            "keyword": "zebra",
+            "filename_only": true,
+            "format": "json",
+        }));
+        let output = SearchTool::new(1, 0)
+            .run_search(params, None, None)
+            .unwrap();
+        let results: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(results["total_hits"], 0);
+        assert_eq!(results["hits"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn returns_several_snippets_around_different_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let filler = "Unrelated words fill the space between the sections. ".repeat(10);
+        fs::write(
+            dir.path().join("guide.md"),
+            format!(
+                "Install the parser first. {}Configure the parser next. {}Finally run the parser.",
+                filler, filler
+            ),
+        )
+        .unwrap();
+
+        let results = search(&dir, serde_json::json!({ "keyword": "parser" }));
+        assert!(results["hits"][0].get("more_snippets").is_none());
+
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "parser", "max_snippets": 3, "highlight_pre": "[", "highlight_post": "]" }),
+        );
+        let hit = &results["hits"][0];
+        let mut snippets = vec![hit["snippet"].as_str().unwrap().to_string()];
+        for snippet in hit["more_snippets"].as_array().unwrap() {
+            snippets.push(snippet.as_str().unwrap().to_string());
+        }
+        assert_eq!(snippets.len(), 3);
+        for section in ["Install", "Configure", "Finally"] {
+            let found = snippets.iter().filter(|snippet| snippet.contains(section));
+            assert_eq!(found.count(), 1, "{} in {:?}", section, snippets);
+        }
+        assert!(snippets.iter().all(|snippet| snippet.contains("[parser]")));
+    }
+
+    #[tokio::test]
+    async fn indexes_and_reads_the_entries_of_archives() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut zip =
+            zip::ZipWriter::new(fs::File::create(dir.path().join("archive.zip")).unwrap());
+        zip.start_file("inner.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"zipped walrus").unwrap();
+        zip.finish().unwrap();
+        let gzip = flate2::write::GzEncoder::new(
+            fs::File::create(dir.path().join("archive.tar.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(gzip);
+        let content = b"tarred walrus";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "inner.txt", &content[..])
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let results = search(
+            &dir,
+            serde_json::json!({ "keyword": "walrus", "index_archives": true }),
+        );
+        let paths = hit_paths(&results);
+        assert_eq!(paths.len(), 2);
+        let tool = SearchTool::new(1, 0);
+        for (archive, text) in [("archive.zip", "zipped"), ("archive.tar.gz", "tarred")] {
+            let path = paths
+                .iter()
+                .find(|path| path.ends_with(&format!("{}!/inner.txt", archive)))
+                .unwrap();
+            let content = tool
+                .read_file_content(FileContentParams {
+                    file_path: path.clone(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(content, format!("{} walrus", text));
+        }
+    }
+
+    // Preview a replacement in the files of a directory
+    async fn replace_in(
+        dir: &tempfile::TempDir,
+        mut arguments: serde_json::Value,
+    ) -> Result<String, McpError> {
+        arguments["directory"] = dir.path().display().to_string().into();
+        SearchTool::new(1, 0)
+            .replace_preview(serde_json::from_value(arguments).unwrap())
+            .await
+    }
+
+    #[tokio::test]
+    async fn previews_replacements_as_a_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("prices.txt"),
+            "apples cost 3 dollars\nnothing here\npears cost 4 dollars\n",
+        )
+        .unwrap();
+
+        let preview = replace_in(
+            &dir,
+            serde_json::json!({ "search": "dollars", "replacement": "$1 euros" }),
+        )
+        .await
+        .unwrap();
+        let path = dir.path().join("prices.txt").display().to_string();
+        assert_eq!(
+            preview,
+            format!(
+                "Preview of 2 replacements in 1 files (nothing was written):\n\
+                 --- {path}\n+++ {path}\n\
+                 @@ -1 +1 @@\n-apples cost 3 dollars\n+apples cost 3 $1 euros\n\
+                 @@ -3 +3 @@\n-pears cost 4 dollars\n+pears cost 4 $1 euros\n"
+            )
+        );
+
+        // With regex, $ references insert the captured groups instead
+        let preview = replace_in(
+            &dir,
+            serde_json::json!({
+                "search": "(\\d) dollars",
+                "replacement": "$1 euros",
+                "regex": true,
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(preview.contains("+apples cost 3 euros\n"));
+        assert!(preview.contains("+pears cost 4 euros\n"));
+    }
+
+    #[tokio::test]
+    async fn previews_the_diff_of_at_most_limit_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(dir.path().join(name), "old text").unwrap();
+        }
+
+        let preview = replace_in(
+            &dir,
+            serde_json::json!({ "search": "old", "replacement": "new", "limit": 2 }),
+        )
+        .await
+        .unwrap();
+        assert!(preview.starts_with("Preview of 3 replacements in 3 files"));
+        assert!(preview.contains("a.txt\n"));
+        assert!(preview.contains("b.txt\n"));
+        assert!(!preview.contains("c.txt\n"));
+        assert!(preview.ends_with("... 1 more files not shown\n"));
+    }
+
+    #[test]
+    fn streams_hits_as_they_appear_in_the_json_result() {
+        let dir = fixture();
+        let params = search_params(serde_json::json!({
+            "keyword": "fox",
+            "directory": dir.path().display().to_string(),
+            "format": "json",
+            "normalize_scores": true,
+            "highlight_pre": "[",
+            "highlight_post": "]",
+        }));
+        let settings = QuerySettings::resolve(&params).unwrap();
+        let built = build_index(
+            &collect_directories(params.directory.as_deref(), None),
+            &params.indexing,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let streamed = Arc::new(Mutex::new(Vec::new()));
+        let sink = streamed.clone();
+        let on_hit: HitCallback = Arc::new(move |hit| {
+            sink.lock()
+                .unwrap()
+                .push(serde_json::to_value(hit).unwrap());
+        });
+        let output = run_query(&built, &params, &settings, Some(&on_hit), None).unwrap();
+        let results: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            serde_json::Value::Array(streamed.lock().unwrap().clone()),
+            results["hits"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_parameters_before_indexing() {
+        let params = search_params(serde_json::json!({ "keyword": "fox", "limit": 0 }));
+        let error = QuerySettings::resolve(&params).unwrap_err();
+        assert_eq!(error.code(), "invalid_params");
+    }
+
+    #[test]
+    fn reports_missing_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing").display().to_string();
+        let error = build_index(&[missing], &IndexingParams::default(), None, None, None)
+            .err()
+            .unwrap();
+        assert_eq!(error.code(), "not_found");
+    }
 }
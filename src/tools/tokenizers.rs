@@ -0,0 +1,452 @@
+use crate::tools::indexer::IndexOptions;
+use rmcp::schemars;
+use std::collections::VecDeque;
+use std::ops::Range;
+use tantivy::Index;
+use tantivy::tokenizer::{
+    Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer, Token,
+    TokenFilter, TokenStream, Tokenizer,
+};
+
+// Tantivy's built-in tokenizer, which lowercases all terms
+pub const DEFAULT_TOKENIZER: &str = "default";
+// Tokenizer of the phonetic copy of the content, which indexes the Soundex code of every word
+pub const PHONETIC_TOKENIZER: &str = "phonetic_soundex";
+
+// Tokens longer than this are dropped, like Tantivy's default tokenizer does
+const MAX_TOKEN_LENGTH: usize = 40;
+
+// How file contents are split into words
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Segmentation {
+    // Split on whitespace and punctuation
+    #[default]
+    Default,
+    // Additionally split runs of Chinese, Japanese and Korean characters into bigrams
+    Cjk,
+}
+
+// Whether a character belongs to a script written without spaces between words
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1100..=0x11FF // Hangul Jamo
+            | 0x3040..=0x30FF // Hiragana and Katakana
+            | 0x3130..=0x318F // Hangul compatibility Jamo
+            | 0x3400..=0x4DBF // CJK unified ideographs extension A
+            | 0x4E00..=0x9FFF // CJK unified ideographs
+            | 0xAC00..=0xD7AF // Hangul syllables
+            | 0xF900..=0xFAFF // CJK compatibility ideographs
+            | 0xFF66..=0xFF9F // Halfwidth Katakana
+            | 0x20000..=0x2FFFF // CJK unified ideographs extensions B and later
+    )
+}
+
+// Splits text like SimpleTokenizer, except that runs of CJK characters are emitted as
+// overlapping bigrams. Consecutive bigrams get consecutive positions, so a multi-character
+// query becomes a phrase query over its bigrams and matches the run it was taken from.
+#[derive(Clone, Default)]
+pub struct CjkBigramTokenizer;
+
+pub struct CjkBigramTokenStream {
+    tokens: std::vec::IntoIter<Token>,
+    token: Token,
+}
+
+impl Tokenizer for CjkBigramTokenizer {
+    type TokenStream<'a> = CjkBigramTokenStream;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut push = |offset_from: usize, offset_to: usize| {
+            tokens.push(Token {
+                offset_from,
+                offset_to,
+                position: tokens.len(),
+                text: text[offset_from..offset_to].to_string(),
+                position_length: 1,
+            });
+        };
+
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if is_cjk(c) {
+                // Byte offsets of every character boundary in the run
+                let mut boundaries = vec![start];
+                let mut end = start + c.len_utf8();
+                while let Some(&(offset, next)) = chars.peek()
+                    && is_cjk(next)
+                {
+                    boundaries.push(offset);
+                    end = offset + next.len_utf8();
+                    chars.next();
+                }
+                boundaries.push(end);
+
+                if boundaries.len() == 2 {
+                    // A lone character is kept as a unigram
+                    push(boundaries[0], boundaries[1]);
+                } else {
+                    for window in boundaries.windows(3) {
+                        push(window[0], window[2]);
+                    }
+                }
+            } else if c.is_alphanumeric() {
+                let mut end = start + c.len_utf8();
+                while let Some(&(offset, next)) = chars.peek()
+                    && next.is_alphanumeric()
+                    && !is_cjk(next)
+                {
+                    end = offset + next.len_utf8();
+                    chars.next();
+                }
+                push(start, end);
+            }
+        }
+
+        CjkBigramTokenStream {
+            tokens: tokens.into_iter(),
+            token: Token::default(),
+        }
+    }
+}
+
+impl TokenStream for CjkBigramTokenStream {
+    fn advance(&mut self) -> bool {
+        match self.tokens.next() {
+            Some(token) => {
+                self.token = token;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+// Byte ranges of the words of a code identifier: snake_case is split at the underscores and
+// camelCase before each capital that starts a word, so "parseHTTPRequest_v2" yields "parse",
+// "HTTP", "Request" and "v2"
+fn identifier_parts(text: &str) -> Vec<Range<usize>> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut parts = Vec::new();
+    let mut start = None;
+    for (i, &(offset, c)) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                parts.push(start..offset);
+            }
+            continue;
+        }
+        match start {
+            None => start = Some(offset),
+            Some(word_start) => {
+                let prev = chars[i - 1].1;
+                let next = chars.get(i + 1).map(|&(_, next)| next);
+                let starts_word = c.is_uppercase()
+                    && (prev.is_lowercase()
+                        || prev.is_numeric()
+                        || (prev.is_uppercase() && next.is_some_and(char::is_lowercase)));
+                if starts_word {
+                    parts.push(word_start..offset);
+                    start = Some(offset);
+                }
+            }
+        }
+    }
+    if let Some(start) = start {
+        parts.push(start..text.len());
+    }
+    parts
+}
+
+// Splits camelCase and snake_case identifiers into their words, so that "user" finds
+// "getUserById". The words get consecutive positions, so the whole identifier is still found
+// as a phrase of its words. Must come before any filter that changes the token text.
+#[derive(Clone, Default)]
+pub struct IdentifierSplitter;
+
+impl TokenFilter for IdentifierSplitter {
+    type Tokenizer<T: Tokenizer> = IdentifierSplitterFilter<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> IdentifierSplitterFilter<T> {
+        IdentifierSplitterFilter { inner: tokenizer }
+    }
+}
+
+#[derive(Clone)]
+pub struct IdentifierSplitterFilter<T> {
+    inner: T,
+}
+
+impl<T: Tokenizer> Tokenizer for IdentifierSplitterFilter<T> {
+    type TokenStream<'a> = IdentifierSplitterStream<T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        IdentifierSplitterStream {
+            tail: self.inner.token_stream(text),
+            parts: VecDeque::new(),
+            token: Token::default(),
+            shift: 0,
+        }
+    }
+}
+
+pub struct IdentifierSplitterStream<T> {
+    tail: T,
+    // Words of the current identifier that are still to be emitted
+    parts: VecDeque<Token>,
+    token: Token,
+    // Positions added by the identifiers split so far
+    shift: usize,
+}
+
+impl<T: TokenStream> TokenStream for IdentifierSplitterStream<T> {
+    fn advance(&mut self) -> bool {
+        while self.parts.is_empty() {
+            if !self.tail.advance() {
+                return false;
+            }
+            let token = self.tail.token();
+            let position = token.position + self.shift;
+            for (i, range) in identifier_parts(&token.text).into_iter().enumerate() {
+                self.parts.push_back(Token {
+                    offset_from: token.offset_from + range.start,
+                    offset_to: token.offset_from + range.end,
+                    position: position + i,
+                    text: token.text[range].to_string(),
+                    position_length: 1,
+                });
+            }
+            self.shift += self.parts.len().saturating_sub(1);
+        }
+        match self.parts.pop_front() {
+            Some(token) => {
+                self.token = token;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+// American Soundex code of a word: its first letter followed by three digits for the consonant
+// sounds that follow, so that "Smith" and "Smyth" both become "S530". Words that don't start
+// with an ASCII letter have no code.
+fn soundex(word: &str) -> Option<String> {
+    let digit = |c: char| match c.to_ascii_lowercase() {
+        'b' | 'f' | 'p' | 'v' => Some('1'),
+        'c' | 'g' | 'j' | 'k' | 'q' | 's' | 'x' | 'z' => Some('2'),
+        'd' | 't' => Some('3'),
+        'l' => Some('4'),
+        'm' | 'n' => Some('5'),
+        'r' => Some('6'),
+        _ => None,
+    };
+    let mut letters = word.chars().filter(char::is_ascii_alphabetic);
+    let first = word.chars().next().filter(char::is_ascii_alphabetic)?;
+    letters.next();
+    let mut code = String::with_capacity(4);
+    code.push(first.to_ascii_uppercase());
+    let mut previous = digit(first);
+    for c in letters {
+        if code.len() == 4 {
+            break;
+        }
+        match digit(c) {
+            // Letters with the same digit are coded once, also when separated by h or w
+            Some(d) if previous != Some(d) => {
+                code.push(d);
+                previous = Some(d);
+            }
+            Some(_) => {}
+            None if matches!(c.to_ascii_lowercase(), 'h' | 'w') => {}
+            // A vowel separates letters with the same digit
+            None => previous = None,
+        }
+    }
+    while code.len() < 4 {
+        code.push('0');
+    }
+    Some(code)
+}
+
+// Replaces every word with its Soundex code, so that names are matched by how they sound.
+// Words without a code are kept as they are.
+#[derive(Clone, Default)]
+pub struct SoundexFilter;
+
+impl TokenFilter for SoundexFilter {
+    type Tokenizer<T: Tokenizer> = SoundexFilterWrapper<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> SoundexFilterWrapper<T> {
+        SoundexFilterWrapper { inner: tokenizer }
+    }
+}
+
+#[derive(Clone)]
+pub struct SoundexFilterWrapper<T> {
+    inner: T,
+}
+
+impl<T: Tokenizer> Tokenizer for SoundexFilterWrapper<T> {
+    type TokenStream<'a> = SoundexTokenStream<T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        SoundexTokenStream {
+            tail: self.inner.token_stream(text),
+        }
+    }
+}
+
+pub struct SoundexTokenStream<T> {
+    tail: T,
+}
+
+impl<T: TokenStream> TokenStream for SoundexTokenStream<T> {
+    fn advance(&mut self) -> bool {
+        if !self.tail.advance() {
+            return false;
+        }
+        if let Some(code) = soundex(&self.tail.token().text) {
+            self.tail.token_mut().text = code;
+        }
+        true
+    }
+
+    fn token(&self) -> &Token {
+        self.tail.token()
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.tail.token_mut()
+    }
+}
+
+// Name of the tokenizer used for file contents, both at index and query time
+pub fn content_tokenizer(options: &IndexOptions) -> String {
+    let mut name = String::from("content");
+    if options.segmentation == Segmentation::Cjk {
+        name.push_str("_cjk");
+    }
+    if options.code_tokenizer {
+        name.push_str("_code");
+    }
+    if options.case_sensitive {
+        name.push_str("_cased");
+    }
+    if options.stemming {
+        name.push_str("_en_stem");
+    }
+    // The default options match Tantivy's built-in tokenizer
+    if name == "content" {
+        return DEFAULT_TOKENIZER.to_string();
+    }
+    name
+}
+
+// Name of the tokenizer of the unstemmed copy of the content indexed in recall mode
+pub fn exact_tokenizer(options: &IndexOptions) -> String {
+    content_tokenizer(&exact_options(options))
+}
+
+// Options of the unstemmed copy of the content: the same as the content field, except for
+// stemming
+fn exact_options(options: &IndexOptions) -> IndexOptions {
+    IndexOptions {
+        stemming: false,
+        ..options.clone()
+    }
+}
+
+// Build the analyzer for file contents described by the options
+fn content_analyzer(options: &IndexOptions) -> TextAnalyzer {
+    let builder = match options.segmentation {
+        Segmentation::Default => TextAnalyzer::builder(SimpleTokenizer::default()).dynamic(),
+        Segmentation::Cjk => TextAnalyzer::builder(CjkBigramTokenizer).dynamic(),
+    };
+    // Identifiers are split first, while the token text still matches the original text
+    let mut builder = if options.code_tokenizer {
+        builder.filter_dynamic(IdentifierSplitter)
+    } else {
+        builder
+    };
+    builder = builder.filter_dynamic(RemoveLongFilter::limit(MAX_TOKEN_LENGTH));
+    if !options.case_sensitive {
+        builder = builder.filter_dynamic(LowerCaser);
+    }
+    // Stemming runs last so that it sees the same letter case as the indexed terms
+    if options.stemming {
+        builder = builder.filter_dynamic(Stemmer::new(Language::English));
+    }
+    builder.build()
+}
+
+// Register the content tokenizers described by the options on an index. Tokenizers are not
+// persisted with the index, so this must be called for every index that is created or opened.
+pub fn register_tokenizers(index: &Index, options: &IndexOptions) {
+    register_content_tokenizer(index, options);
+    if options.recall_mode {
+        register_content_tokenizer(index, &exact_options(options));
+    }
+    if options.phonetic {
+        index.tokenizers().register(
+            PHONETIC_TOKENIZER,
+            TextAnalyzer::builder(SimpleTokenizer::default())
+                .filter(RemoveLongFilter::limit(MAX_TOKEN_LENGTH))
+                .filter(SoundexFilter)
+                .build(),
+        );
+    }
+}
+
+fn register_content_tokenizer(index: &Index, options: &IndexOptions) {
+    let name = content_tokenizer(options);
+    if name != DEFAULT_TOKENIZER {
+        index
+            .tokenizers()
+            .register(&name, content_analyzer(options));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_words_that_sound_alike_the_same() {
+        assert_eq!(soundex("Smith").as_deref(), Some("S530"));
+        assert_eq!(soundex("smyth").as_deref(), Some("S530"));
+        assert_eq!(soundex("Robert").as_deref(), Some("R163"));
+        assert_eq!(soundex("Rupert").as_deref(), Some("R163"));
+        assert_eq!(soundex("Ashcraft").as_deref(), Some("A261"));
+        assert_eq!(soundex("Lee").as_deref(), Some("L000"));
+        assert_eq!(soundex("42nd"), None);
+    }
+}
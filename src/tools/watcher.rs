@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Whether this build can watch files for changes, as enabled by the watch feature
+pub const SUPPORTED: bool = cfg!(feature = "watch");
+
+// Watches files and directories until it is dropped
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+pub struct Watcher {
+    #[cfg(feature = "watch")]
+    _inner: notify::RecommendedWatcher,
+}
+
+// Watch the paths, the contents of directories recursively when asked to. Changes are collected
+// until none arrived for the debounce period and then passed to on_change at once, on a thread
+// of the watcher, so that saving a file doesn't report each of its writes.
+#[cfg(feature = "watch")]
+pub fn watch(
+    paths: &[PathBuf],
+    recursive: bool,
+    debounce: Duration,
+    on_change: impl Fn(Vec<PathBuf>) + Send + 'static,
+) -> Result<Watcher, String> {
+    use notify::Watcher as _;
+    use std::collections::BTreeSet;
+
+    let (sender, receiver) = std::sync::mpsc::channel::<Vec<PathBuf>>();
+    let mut inner = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reads don't change anything
+        if let Some(event) = event.ok().filter(|event| !event.kind.is_access()) {
+            let _ = sender.send(event.paths);
+        }
+    })
+    .map_err(|e| format!("Watch error: {}", e))?;
+    let mode = if recursive {
+        notify::RecursiveMode::Recursive
+    } else {
+        notify::RecursiveMode::NonRecursive
+    };
+    for path in paths {
+        inner
+            .watch(path, mode)
+            .map_err(|e| format!("Watch error for '{}': {}", path.display(), e))?;
+    }
+
+    // The channel closes when the watcher is dropped, which ends the thread
+    std::thread::spawn(move || {
+        while let Ok(paths) = receiver.recv() {
+            let mut changed: BTreeSet<PathBuf> = paths.into_iter().collect();
+            while let Ok(paths) = receiver.recv_timeout(debounce) {
+                changed.extend(paths);
+            }
+            on_change(changed.into_iter().collect());
+        }
+    });
+    Ok(Watcher { _inner: inner })
+}
+
+#[cfg(not(feature = "watch"))]
+pub fn watch(
+    _paths: &[PathBuf],
+    _recursive: bool,
+    _debounce: Duration,
+    _on_change: impl Fn(Vec<PathBuf>) + Send + 'static,
+) -> Result<Watcher, String> {
+    Err("Watch error: this build can't watch files, enable the watch feature".to_string())
+}
+
+#[cfg(all(test, feature = "watch"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc;
+
+    #[test]
+    fn reports_changed_files_once_they_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let _watcher = watch(
+            &[dir.path().to_path_buf()],
+            true,
+            Duration::from_millis(100),
+            move |changed| {
+                let _ = sender.send(changed);
+            },
+        )
+        .unwrap();
+        let file = dir.path().join("notes.txt");
+        fs::write(&file, "first").unwrap();
+        fs::write(&file, "second").unwrap();
+
+        let changed = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let file = file.canonicalize().unwrap();
+        assert!(
+            changed
+                .iter()
+                .any(|path| path.canonicalize().ok().as_ref() == Some(&file))
+        );
+    }
+}